@@ -0,0 +1,249 @@
+use crate::dql::statement::parse_stored_query;
+use crate::models::{Catalog, Constraint, ConstraintKind, DatabaseEntry, Sequence, SequenceOwner, Table, TableEntry, TableStats, View};
+use crate::storage::StorageBackend;
+
+// Tables in the default database keep their bare names, so existing table files stay where they are
+pub const DEFAULT_DATABASE: &str = "main";
+
+// Virtual database holding the read-only system tables
+pub const INFORMATION_SCHEMA: &str = "information_schema";
+
+// Splits a storage name such as `shop.orders` into its database and table parts
+pub fn split_table_name(name: &str) -> (String, String) {
+    match name.split_once('.') {
+        Some((database, table)) => (database.to_string(), table.to_string()),
+        None => (DEFAULT_DATABASE.to_string(), name.to_string()),
+    }
+}
+
+// Turns a name as written in a command into the name the table is stored under
+pub fn resolve_table_name(name: &str, database: &str) -> String {
+    let (database, table) = match name.split_once('.') {
+        Some((database, table)) => (database, table),
+        None => (database, name),
+    };
+    if database == DEFAULT_DATABASE {
+        table.to_string()
+    } else {
+        format!("{}.{}", database, table)
+    }
+}
+
+// Loads the catalog, building it from the stored tables the first time round
+pub fn load_catalog(storage: &mut dyn StorageBackend) -> Result<Catalog, String> {
+    if let Some(mut catalog) = storage.load_catalog()? {
+        // Older catalogs get their foreign keys from the table schemas, once
+        let mut unrecorded = vec![];
+        for (database, entry) in &catalog.databases {
+            unrecorded.extend(entry.tables.iter().filter(|(_, t)| t.foreign_keys.is_none()).map(|(name, _)| resolve_table_name(name, database)));
+        }
+        if !unrecorded.is_empty() {
+            for name in unrecorded {
+                let table = storage.load_schema(&name)?;
+                let (database, name) = split_table_name(&name);
+                if let Some(entry) = catalog.databases.get_mut(&database).and_then(|d| d.tables.get_mut(&name)) {
+                    entry.foreign_keys = Some(foreign_keys(&table));
+                }
+            }
+            storage.save_catalog(&catalog)?;
+        }
+        return Ok(catalog);
+    }
+
+    let mut catalog = Catalog::default();
+    catalog.databases.insert(DEFAULT_DATABASE.to_string(), DatabaseEntry::default());
+    for name in storage.list_tables()? {
+        let table = storage.load_schema(&name)?;
+        catalog.register_table(&table);
+    }
+    storage.save_catalog(&catalog)?;
+    Ok(catalog)
+}
+
+impl Catalog {
+    pub fn has_database(&self, database: &str) -> bool {
+        self.databases.contains_key(database)
+    }
+
+    pub fn table(&self, name: &str) -> Option<&TableEntry> {
+        let (database, table) = split_table_name(name);
+        self.databases.get(&database).and_then(|d| d.tables.get(&table))
+    }
+
+    pub fn register_table(&mut self, table: &Table) {
+        let (database, name) = split_table_name(&table.name);
+        let mut indexes: Vec<String> = table.indexes.keys().cloned().collect();
+        indexes.sort();
+        let entry = TableEntry { indexes, schema_version: 1, stats: None, foreign_keys: Some(foreign_keys(table)) };
+        self.databases.entry(database).or_default().tables.insert(name, entry);
+    }
+
+    // Sequences owned by the table's columns go with it
+    pub fn unregister_table(&mut self, name: &str) {
+        let (database, table) = split_table_name(name);
+        if let Some(entry) = self.databases.get_mut(&database) {
+            entry.tables.remove(&table);
+        }
+        for entry in self.databases.values_mut() {
+            entry.sequences.retain(|_, s| s.owner.as_ref().is_none_or(|o| o.table != name));
+        }
+    }
+
+    // Moves the entry across, counting the rename as a schema change
+    pub fn rename_table(&mut self, from: &str, to: &str) {
+        let (database, table) = split_table_name(from);
+        let entry = self.databases.get_mut(&database).and_then(|d| d.tables.remove(&table));
+        let (new_database, new_table) = split_table_name(to);
+        let mut entry = entry.unwrap_or(TableEntry { indexes: vec![], schema_version: 0, stats: None, foreign_keys: Some(vec![]) });
+        entry.schema_version += 1;
+        self.databases.entry(new_database).or_default().tables.insert(new_table, entry);
+        // As the referencing tables' own schemas are rewritten to
+        for constraint in self.databases.values_mut().flat_map(|d| d.tables.values_mut()).flat_map(|t| t.foreign_keys.iter_mut().flatten()) {
+            if let ConstraintKind::ForeignKey { ref_table, .. } = &mut constraint.kind {
+                if ref_table == from {
+                    *ref_table = to.to_string();
+                }
+            }
+        }
+        // Owned sequences keep their names and stay where they are
+        for sequence in self.databases.values_mut().flat_map(|d| d.sequences.values_mut()) {
+            if let Some(owner) = sequence.owner.as_mut().filter(|o| o.table == from) {
+                owner.table = to.to_string();
+            }
+        }
+    }
+
+    // Keeps the index list in step with the table, and bumps the version when its schema changed
+    pub fn update_table(&mut self, table: &Table, schema_changed: bool) {
+        let (database, name) = split_table_name(&table.name);
+        let entry = self.databases.entry(database).or_default().tables.entry(name).or_insert(TableEntry {
+            indexes: vec![],
+            schema_version: 0,
+            stats: None,
+            foreign_keys: None,
+        });
+        let mut indexes: Vec<String> = table.indexes.keys().cloned().collect();
+        indexes.sort();
+        entry.indexes = indexes;
+        entry.foreign_keys = Some(foreign_keys(table));
+        if schema_changed || entry.schema_version == 0 {
+            entry.schema_version += 1;
+            entry.stats = None;
+        }
+    }
+
+    // Returns false if the table isn't registered
+    pub fn set_stats(&mut self, name: &str, stats: TableStats) -> bool {
+        let (database, table) = split_table_name(name);
+        match self.databases.get_mut(&database).and_then(|d| d.tables.get_mut(&table)) {
+            Some(entry) => {
+                entry.stats = Some(stats);
+                true
+            },
+            None => false,
+        }
+    }
+
+    pub fn stats(&self, name: &str) -> Option<&TableStats> {
+        self.table(name).and_then(|t| t.stats.as_ref())
+    }
+
+    // Every (table, constraint) whose foreign key points at `name`, including the table itself
+    pub fn referencing(&self, name: &str) -> Vec<(String, Constraint)> {
+        let mut found = vec![];
+        for (database, entry) in &self.databases {
+            for (table, table_entry) in &entry.tables {
+                for constraint in table_entry.foreign_keys.iter().flatten() {
+                    if matches!(&constraint.kind, ConstraintKind::ForeignKey { ref_table, .. } if ref_table == name) {
+                        found.push((resolve_table_name(table, database), constraint.clone()));
+                    }
+                }
+            }
+        }
+        found
+    }
+
+    // Views whose query reads the table or view `name`
+    pub fn dependent_views(&self, name: &str) -> Vec<String> {
+        let mut found = vec![];
+        for (database, entry) in &self.databases {
+            for (view_name, view) in &entry.views {
+                let Ok(query) = parse_stored_query(&view.query) else { continue };
+                if query.table_names().iter().any(|t| resolve_table_name(t, database) == name) {
+                    found.push(resolve_table_name(view_name, database));
+                }
+            }
+        }
+        found
+    }
+
+    pub fn view(&self, name: &str) -> Option<&View> {
+        let (database, view) = split_table_name(name);
+        self.databases.get(&database).and_then(|d| d.views.get(&view))
+    }
+
+    // Adds or replaces the view, returning false if its database doesn't exist
+    pub fn set_view(&mut self, name: &str, view: View) -> bool {
+        let (database, name) = split_table_name(name);
+        match self.databases.get_mut(&database) {
+            Some(entry) => {
+                entry.views.insert(name, view);
+                true
+            },
+            None => false,
+        }
+    }
+
+    pub fn remove_view(&mut self, name: &str) -> Option<View> {
+        let (database, view) = split_table_name(name);
+        self.databases.get_mut(&database).and_then(|d| d.views.remove(&view))
+    }
+
+    pub fn sequence(&self, name: &str) -> Option<&Sequence> {
+        let (database, sequence) = split_table_name(name);
+        self.databases.get(&database).and_then(|d| d.sequences.get(&sequence))
+    }
+
+    pub fn sequence_mut(&mut self, name: &str) -> Option<&mut Sequence> {
+        let (database, sequence) = split_table_name(name);
+        self.databases.get_mut(&database).and_then(|d| d.sequences.get_mut(&sequence))
+    }
+
+    // Adds or replaces the sequence, returning false if its database doesn't exist
+    pub fn set_sequence(&mut self, name: &str, sequence: Sequence) -> bool {
+        let (database, name) = split_table_name(name);
+        match self.databases.get_mut(&database) {
+            Some(entry) => {
+                entry.sequences.insert(name, sequence);
+                true
+            },
+            None => false,
+        }
+    }
+
+    pub fn remove_sequence(&mut self, name: &str) -> Option<Sequence> {
+        let (database, sequence) = split_table_name(name);
+        self.databases.get_mut(&database).and_then(|d| d.sequences.remove(&sequence))
+    }
+
+    // Creates the sequence behind a SERIAL column in the table's database, named
+    // <table>_<column>_seq with a number added if that is taken
+    pub fn add_owned_sequence(&mut self, table: &str, column: &str, sequence: Sequence) -> String {
+        let (database, table_name) = split_table_name(table);
+        let base = format!("{}_{}_seq", table_name, column);
+        let entry = self.databases.entry(database.clone()).or_default();
+        let mut name = base.clone();
+        let mut n = 1;
+        while entry.sequences.contains_key(&name) {
+            name = format!("{}{}", base, n);
+            n += 1;
+        }
+        let owner = SequenceOwner { table: table.to_string(), column: column.to_string() };
+        entry.sequences.insert(name.clone(), Sequence { owner: Some(owner), ..sequence });
+        resolve_table_name(&name, &database)
+    }
+}
+
+fn foreign_keys(table: &Table) -> Vec<Constraint> {
+    table.constraints.iter().filter(|c| matches!(c.kind, ConstraintKind::ForeignKey { .. })).cloned().collect()
+}
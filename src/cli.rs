@@ -3,20 +3,36 @@ use rustyline::DefaultEditor;
 use serde_json::{self, Value};
 use std::collections::HashMap;
 use crate::ddl::alter::alter;
+use crate::ddl::analyze::analyze;
 use crate::ddl::create::{create, CreateData};
-use crate::ddl::drop::drop;
-use crate::dml::delete::delete;
+use crate::catalog::{load_catalog, resolve_table_name, DEFAULT_DATABASE};
+use crate::ddl::drop::{drop, drop_database};
+use crate::ddl::sequence::{create_sequence, drop_sequence};
+use crate::ddl::view::{create_view, drop_view, refresh_view};
+use crate::dml::delete::{delete, truncate};
 use crate::dml::insert::insert;
+use crate::dml::returning::DmlReturn;
 use crate::dml::update::update;
+use crate::dql::explain::explain;
+use crate::dql::information_schema::{describe, show_databases, show_sequences, show_tables, show_views};
 use crate::dql::select::select;
 use crate::models::{FieldDef, Table};
-use crate::storage::load_from_disk;
+use crate::storage::{btree::compare_keys, data_dir, file::FileBackend, memory::MemoryBackend, StorageBackend, StorageKind};
 
 // Simple key-value store CLI
 #[derive(Parser, Debug)]
 #[command(name = "Ezpz Database")]
 #[command(about = "Simple SQL-like database CLI", long_about = None)]
 struct Cli {
+    // Storage format for newly created tables
+    #[arg(long, value_enum, default_value = "json")]
+    storage: StorageKind,
+    // Keep tables in memory for the session instead of on disk
+    #[arg(long)]
+    in_memory: bool,
+    // Database that unqualified table names belong to
+    #[arg(long, default_value = DEFAULT_DATABASE)]
+    database: String,
     // Key to get or set
     #[command(subcommand)]
     command: Option<Command>,
@@ -28,37 +44,98 @@ enum Command {
         #[arg(trailing_var_arg = true, num_args(1..))]
         query: Vec<String>,
     },
+    // WITH ... SELECT ...
+    With {
+        #[arg(trailing_var_arg = true, allow_hyphen_values = true, num_args(1..))]
+        query: Vec<String>,
+    },
     Create {
         create_type: String,
-        #[arg(trailing_var_arg = true, num_args(1..))]
+        #[arg(trailing_var_arg = true, allow_hyphen_values = true, num_args(1..))]
         tokens: Vec<String>,
     },
     Drop {
         name: String,
+        target: Option<String>,
+    },
+    Use {
+        database: String,
+    },
+    Show {
+        target: String,
+    },
+    Describe {
+        table: String,
     },
     Alter {
         table: String,
         action: String,
+        #[arg(trailing_var_arg = true, allow_hyphen_values = true)]
         tokens: Vec<String>,
     },
     Insert {
         table: String,
+        #[arg(trailing_var_arg = true, allow_hyphen_values = true)]
         tokens: Vec<String>,
     },
     Delete {
         table: String,
+        #[arg(trailing_var_arg = true, allow_hyphen_values = true)]
         tokens: Vec<String>,
     },
     Update {
         table: String,
+        #[arg(trailing_var_arg = true, allow_hyphen_values = true)]
         tokens: Vec<String>,
-    }
+    },
+    Truncate {
+        table: String,
+        #[arg(trailing_var_arg = true)]
+        options: Vec<String>,
+    },
+    // ANALYZE [<table>]
+    Analyze {
+        table: Option<String>,
+    },
+    // EXPLAIN [ANALYZE] {SELECT | WITH} ...
+    Explain {
+        #[arg(trailing_var_arg = true, allow_hyphen_values = true, num_args(1..))]
+        tokens: Vec<String>,
+    },
+    // REFRESH MATERIALIZED VIEW <name>
+    Refresh {
+        #[arg(num_args(3))]
+        tokens: Vec<String>,
+    },
 }
 
 
 pub fn ezpzdb_cli() {
-    let init: Vec<_> = std::env::args().collect();
-    if init.len() <= 1 {
+    let init = Cli::parse();
+    let mut storage: Box<dyn StorageBackend> = if init.in_memory {
+        Box::new(MemoryBackend::new())
+    } else {
+        match data_dir() {
+            Some(dir) => Box::new(FileBackend::new(dir, init.storage)),
+            None => {
+                println!("Error: No home directory found");
+                return;
+            }
+        }
+    };
+    let mut database = init.database.clone();
+    match load_catalog(storage.as_mut()) {
+        Ok(catalog) if catalog.has_database(&database) => {},
+        Ok(_) => {
+            println!("Database {} not found", database);
+            return;
+        },
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            return;
+        }
+    }
+    if init.command.is_none() {
         let mut rl = DefaultEditor::new().unwrap();
         let exit_command = "quit".to_string();
         println!();
@@ -76,65 +153,219 @@ pub fn ezpzdb_cli() {
                 tokens.extend(splits);
                 let cli = Cli::try_parse_from(tokens);
                 if let Ok(valid) = cli {
-                    run_command(valid);
+                    run_command(storage.as_mut(), &mut database, valid, true);
                     println!();
                 }
             }
         }
     } else {
-        run_command(Cli::parse());
+        run_command(storage.as_mut(), &mut database, init, false);
     }
 }
 
-fn run_command(tokens: Cli) {
+// `interactive` is set for the REPL, where destructive commands ask before running
+fn run_command(storage: &mut dyn StorageBackend, database: &mut String, tokens: Cli, interactive: bool) {
     match tokens {
-        Cli { command: Some(Command::Select { query }) } => {
-            let select_results = select(query);
-            if select_results.filtered.is_empty() {
-                println!("No records found");
-            } else {
-                print_to_cli(select_results.filtered, select_results.schema);
-
-                for field in select_results.missing {
-                    println!("Field not found: {}", field);
-                }
+        Cli { command: Some(Command::Select { query }), .. } => {
+            print_select(storage, database, query);
+        }
+        Cli { command: Some(Command::With { query }), .. } => {
+            let mut tokens = vec!["WITH".to_string()];
+            tokens.extend(query);
+            print_select(storage, database, tokens);
+        }
+        Cli { command: Some(Command::Analyze { table }), .. } => {
+            analyze(storage, database, table.map(|t| resolve_table_name(&t, database)));
+        }
+        Cli { command: Some(Command::Explain { tokens }), .. } => {
+            match explain(storage, database, tokens) {
+                Ok(lines) => lines.iter().for_each(|line| println!("{}", line)),
+                Err(e) => println!("Error: {}", e),
             }
         }
-        Cli { command: Some(Command::Create { create_type, mut tokens }) } => {
-            let create_data: CreateData;
+        Cli { command: Some(Command::Create { create_type, mut tokens }), .. } => {
             let other_tokens = &tokens.split_off(1);
-            match create_type.as_str() {
+            let create_data = match create_type.as_str() {
                 "TABLE" | "table" => {
-                    create_data = CreateData::Table { name: tokens[0].clone(), schema: other_tokens.clone() };
+                    let name = resolve_table_name(&tokens[0], database);
+                    match other_tokens.first().map(|t| t.to_uppercase()).as_deref() {
+                        Some("AS") => {
+                            let Some(query) = query_after_as(other_tokens) else { return };
+                            CreateData::TableAs { name, database: database.clone(), query }
+                        },
+                        Some("LIKE") => {
+                            match other_tokens.get(1) {
+                                Some(source) => CreateData::TableLike { name, source: resolve_table_name(source, database) },
+                                None => {
+                                    println!("Missing source table");
+                                    return;
+                                }
+                            }
+                        },
+                        _ => CreateData::Table { name, schema: other_tokens.clone() },
+                    }
                 },
                 "INDEX" | "index" => {
-                    create_data = CreateData::Index { table: tokens[0].clone(), column: other_tokens[0].clone() };
+                    CreateData::Index { table: resolve_table_name(&tokens[0], database), column: other_tokens[0].clone() }
+                },
+                "DATABASE" | "database" => {
+                    CreateData::Database { name: tokens[0].clone() }
+                },
+                "SEQUENCE" | "sequence" => {
+                    create_sequence(storage, resolve_table_name(&tokens[0], database), other_tokens.clone());
+                    return;
+                },
+                "VIEW" | "view" => {
+                    let Some(query) = query_after_as(other_tokens) else { return };
+                    create_view(storage, resolve_table_name(&tokens[0], database), query, false);
+                    return;
+                },
+                "MATERIALIZED" | "materialized" => {
+                    if !tokens[0].eq_ignore_ascii_case("VIEW") || other_tokens.is_empty() {
+                        println!("Expected VIEW <name> after MATERIALIZED");
+                        return;
+                    }
+                    let Some(query) = query_after_as(&other_tokens[1..]) else { return };
+                    create_view(storage, resolve_table_name(&other_tokens[0], database), query, true);
+                    return;
                 },
                 _ => {
                     println!("Invalid create type entered");
                     return;
                 }
+            };
+            create(storage, create_data);
+        }
+        Cli { command: Some(Command::Drop { name, target }), .. } => {
+            match (name.as_str(), target) {
+                ("DATABASE" | "database", Some(db)) => drop_database(storage, db),
+                ("TABLE" | "table", Some(table)) => drop(storage, resolve_table_name(&table, database)),
+                ("SEQUENCE" | "sequence", Some(sequence)) => drop_sequence(storage, resolve_table_name(&sequence, database)),
+                ("VIEW" | "view", Some(view)) => drop_view(storage, resolve_table_name(&view, database)),
+                (_, None) => drop(storage, resolve_table_name(&name, database)),
+                _ => println!("Invalid drop type entered"),
+            }
+        }
+        Cli { command: Some(Command::Use { database: name }), .. } => {
+            match load_catalog(storage) {
+                Ok(catalog) if catalog.has_database(&name) => {
+                    println!("Using database {}", name);
+                    *database = name;
+                },
+                Ok(_) => println!("Database {} not found", name),
+                Err(e) => eprintln!("Error: {}", e),
+            }
+        }
+        Cli { command: Some(Command::Show { target }), .. } => {
+            let shown = match target.as_str() {
+                "TABLES" | "tables" => show_tables(storage, database),
+                "DATABASES" | "databases" => show_databases(storage),
+                "SEQUENCES" | "sequences" => show_sequences(storage, database),
+                "VIEWS" | "views" => show_views(storage, database),
+                _ => {
+                    println!("Invalid show type entered");
+                    return;
+                }
+            };
+            match shown {
+                Ok(table) => print_table(table),
+                Err(e) => eprintln!("Error: {}", e),
+            }
+        }
+        Cli { command: Some(Command::Describe { table }), .. } => {
+            let name = resolve_table_name(&table, database);
+            let version = load_catalog(storage).ok().and_then(|c| c.table(&name).map(|t| t.schema_version));
+            match describe(storage, &name) {
+                Ok(described) => {
+                    print_table(described);
+                    if let Some(v) = version {
+                        println!("Schema version: {}", v);
+                    }
+                },
+                Err(e) => eprintln!("Error: {}", e),
             }
-            create(create_data);
         }
-        Cli { command: Some(Command::Drop { name }) } => {
-            drop(name);
+        Cli { command: Some(Command::Alter { table, action, tokens }), .. } => {
+            let table_data = match storage.load_table(&resolve_table_name(&table, database)) {
+                Ok(t) => t,
+                Err(e) => {
+                    eprintln!("Error: {}", e);
+                    return;
+                }
+            };
+            alter(storage, table_data, action, tokens);
         }
-        Cli { command: Some(Command::Alter { table, action, tokens }) } => {
-            let table_data = load_from_disk(&table);
-            alter(table_data, action, tokens);
+        Cli { command: Some(Command::Insert { mut table, mut tokens }), .. } => {
+            if table.eq_ignore_ascii_case("INTO") && !tokens.is_empty() {
+                table = tokens.remove(0);
+            }
+            let table_data = match storage.load_schema(&resolve_table_name(&table, database)) {
+                Ok(t) => t,
+                Err(e) => {
+                    eprintln!("Error: {}", e);
+                    return;
+                }
+            };
+            match insert(storage, database, table_data, tokens) {
+                Ok(result) => print_dml_result(result, "inserted"),
+                Err(e) => { println!("Error: {}", e); }
+            }
+        }
+        Cli { command: Some(Command::Delete { mut table, mut tokens }), .. } => {
+            if table.eq_ignore_ascii_case("FROM") && !tokens.is_empty() {
+                table = tokens.remove(0);
+            }
+            let every_row = !tokens.iter().any(|t| t.eq_ignore_ascii_case("WHERE"));
+            if every_row && interactive && !confirm(&format!("Delete every row in {}?", table)) {
+                println!("Cancelled");
+                return;
+            }
+            let table_data = match storage.load_table(&resolve_table_name(&table, database)) {
+                Ok(t) => t,
+                Err(e) => {
+                    eprintln!("Error: {}", e);
+                    return;
+                }
+            };
+            match delete(storage, table_data, tokens) {
+                Ok(result) => print_dml_result(result, "deleted"),
+                Err(e) => { println!("Error: {}", e); }
+            }
         }
-        Cli { command: Some(Command::Insert { table, tokens }) } => {
-            let table_data: Table = load_from_disk(&table);
-            insert(table_data, tokens);
+        Cli { command: Some(Command::Update { table, tokens }), .. } => {
+            let table_data = match storage.load_table(&resolve_table_name(&table, database)) {
+                Ok(t) => t,
+                Err(e) => {
+                    eprintln!("Error: {}", e);
+                    return;
+                }
+            };
+            match update(storage, database, table_data, tokens) {
+                Ok(result) => print_dml_result(result, "updated"),
+                Err(e) => { println!("Error: {}", e); }
+            }
         }
-        Cli { command: Some(Command::Delete { table, tokens }) } => {
-            let table_data: Table = load_from_disk(&table);
-            delete(table_data, tokens);
+        Cli { command: Some(Command::Truncate { table, options }), .. } => {
+            let name = resolve_table_name(&table, database);
+            let table_data = match storage.load_schema(&name) {
+                Ok(t) => t,
+                Err(e) => {
+                    eprintln!("Error: {}", e);
+                    return;
+                }
+            };
+            match truncate(storage, table_data, options) {
+                Ok(()) => println!("Table {} truncated", table),
+                Err(e) => { println!("Error: {}", e); }
+            }
         }
-        Cli { command: Some(Command::Update { table, tokens }) } => {
-            let table_data: Table = load_from_disk(&table);
-            update(table_data, tokens);
+        Cli { command: Some(Command::Refresh { tokens }), .. } => {
+            match tokens.iter().map(|t| t.to_uppercase()).collect::<Vec<String>>().as_slice() {
+                [materialized, view, _] if materialized == "MATERIALIZED" && view == "VIEW" => {
+                    refresh_view(storage, resolve_table_name(&tokens[2], database));
+                },
+                _ => println!("Expected MATERIALIZED VIEW <name>"),
+            }
         }
         _ => {
             println!("No command provided");
@@ -142,28 +373,67 @@ fn run_command(tokens: Cli) {
     }
 }
 
+// AS SELECT ... gives what follows SELECT, and AS WITH ... the whole query from WITH
+fn query_after_as(tokens: &[String]) -> Option<Vec<String>> {
+    let keywords: Vec<String> = tokens.iter().take(2).map(|t| t.to_uppercase()).collect();
+    match keywords.iter().map(|k| k.as_str()).collect::<Vec<&str>>().as_slice() {
+        ["AS", "SELECT"] => Some(tokens[2..].to_vec()),
+        ["AS", "WITH"] => Some(tokens[1..].to_vec()),
+        _ => {
+            println!("Expected AS SELECT or AS WITH");
+            None
+        }
+    }
+}
+
+fn print_select(storage: &mut dyn StorageBackend, database: &str, query: Vec<String>) {
+    let select_results = select(storage, database, query);
+    if select_results.filtered.is_empty() {
+        println!("No records found");
+    } else {
+        print_to_cli(select_results.filtered, select_results.schema);
+
+        for field in select_results.missing {
+            println!("Field not found: {}", field);
+        }
+    }
+}
+
+fn confirm(question: &str) -> bool {
+    println!("{} [y/N]", question);
+    let mut answer = String::new();
+    if std::io::stdin().read_line(&mut answer).is_err() {
+        return false;
+    }
+    matches!(answer.trim().to_lowercase().as_str(), "y" | "yes")
+}
+
+fn print_dml_result(result: DmlReturn, action: &str) {
+    println!("{} rows {}", result.count, action);
+    if let Some(returning) = result.returning {
+        if !returning.filtered.is_empty() {
+            print_to_cli(returning.filtered, returning.schema);
+        }
+    }
+}
+
+fn print_table(table: Table) {
+    if table.data.is_empty() {
+        println!("No records found");
+    } else {
+        let schema = table.schema.into_iter().map(|f| (true, f)).collect();
+        print_to_cli(table.data, schema);
+    }
+}
+
 fn print_to_cli(data: HashMap<Value, Value>, schema: Vec<(bool, FieldDef)>) {
     // Sets column width, and label
     let mut cols: Vec<(String, usize)> = vec![];
 
-    let primary_key = &schema.iter().find(|(_, field)| field.primary_key).unwrap().1;
-    let mut sorted: Vec<&Value> = data.values().collect();
-    sorted.sort_by(|curr, next| {
-        let curr_state = match curr {
-            Value::Object(c) => c.get(&primary_key.name).cloned().unwrap_or(Value::Null),
-            _ => Value::Null,
-        };
-        let next_state = match next {
-            Value::Object(c) => c.get(&primary_key.name).cloned().unwrap_or(Value::Null),
-            _ => Value::Null,
-        };
-        let out = match (curr_state, next_state) {
-            (Value::String(c), Value::String(n)) => c.cmp(&n),
-            (Value::Number(c), Value::Number(n)) => c.as_i64().cmp(&n.as_i64()),
-            _ => std::cmp::Ordering::Equal,
-        };
-        out
-    });
+    // Rows print in key order, which is the row number for tables without a primary key
+    let mut sorted: Vec<(&Value, &Value)> = data.iter().collect();
+    sorted.sort_by(|curr, next| compare_keys(curr.0, next.0));
+    let sorted: Vec<&Value> = sorted.into_iter().map(|(_, row)| row).collect();
 
     for (should_print, header) in schema.iter() {
         if !*should_print {
@@ -178,7 +448,7 @@ fn print_to_cli(data: HashMap<Value, Value>, schema: Vec<(bool, FieldDef)>) {
             }
         )
         .max()
-        .unwrap_or(3), 3);
+        .unwrap_or(3), std::cmp::max(header.name.len(), 3));
         cols.push((header.name.clone(), max_length));
     }
 
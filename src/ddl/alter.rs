@@ -1,14 +1,19 @@
-use std::cmp::Ordering;
+use std::{cmp::Ordering, collections::HashMap};
 
 use serde_json::Value;
 
-use crate::{models::{FieldDataType, FieldDef, SerialState, Table}, storage::save_to_disk};
+use crate::{catalog::{load_catalog, resolve_table_name, split_table_name}, ddl::{constraint::{check_references, constraint_columns, parse_constraint, parse_single_column, referencing_constraints, rename_constraint_column, validate_rows}, convert::{check_type, convert_column, ConversionMode}, create::{build_index, rebuild_indexes}, sequence::Sequences}, dql::expr::{tokenize, Expr, Parser}, models::{ConstraintKind, FieldDataType, FieldDef, SerialState, Table}, storage::{btree::compare_keys, StorageBackend}};
 
 
-pub fn alter(mut table: Table, action: String, tokens: Vec<String>) {
+pub fn alter(storage: &mut dyn StorageBackend, mut table: Table, action: String, tokens: Vec<String>) {
 
     match action.as_str() {
         "add" | "ADD" => {
+            match tokens.first().map(|t| t.to_uppercase()).as_deref() {
+                Some("PRIMARY") => return add_primary_key(storage, table, &tokens[1..]),
+                Some("CONSTRAINT") => return add_constraint(storage, table, &tokens[1..]),
+                _ => {},
+            }
             if tokens.len() <= 1 {
                 println!("Missing parameters");
                 return;
@@ -19,7 +24,7 @@ pub fn alter(mut table: Table, action: String, tokens: Vec<String>) {
             let col_type = &tokens[1].to_uppercase();
             // 1 - Check to see if new column already in table
             // 2 - If exists, early return
-            if field_names.contains(&&col_name) {
+            if field_names.contains(&col_name ) {
                 println!("Column already exists in table");
                 return
             }
@@ -36,12 +41,18 @@ pub fn alter(mut table: Table, action: String, tokens: Vec<String>) {
                 },
                 primary_key: false,
                 serial: if col_type.as_str() == "SERIAL" {
-                    Some(SerialState { next_val: 1 })
+                    Some(SerialState::default())
                 } else {
                     None
                 }
             };
             table.schema.push(new_field.clone());
+            // A new SERIAL column gets its own sequence, which numbers the existing rows
+            let Some(mut sequences) = load_sequences(storage, &table) else { return };
+            if new_field.serial.is_some() {
+                sequences.own_serial_sequences(&mut table);
+                new_field = table.schema[table.schema.len() - 1].clone();
+            }
 
             // 4 - Sort rows, ordered by primary key
             let mut rows: Vec<(&Value, &mut Value)> = table.data.iter_mut().map(|f| (f.0, f.1)).collect();
@@ -86,16 +97,12 @@ pub fn alter(mut table: Table, action: String, tokens: Vec<String>) {
                             map.insert(new_field.name.clone(), Value::Bool(false));
                         },
                         Some(FieldDataType::SERIAL) => {
-                            match new_field.serial.as_mut() {
-                                Some(next) => {
-                                    let curr = next.next_val;
-                                    next.next_val += 1;
-                                    if new_field.primary_key {
-                                        map.insert(new_field.name.clone(), Value::from(curr));
-                                    }
-                                    row.1[&new_field.name] = Value::from(curr);
-                                },
-                                None => {eprintln!("Error: SerialState not found");}
+                            match sequences.serial_value(&new_field) {
+                                Ok(value) => { map.insert(new_field.name.clone(), value); },
+                                Err(e) => {
+                                    println!("Error: {}", e);
+                                    return;
+                                }
                             }
                         },
                         None => {}
@@ -105,150 +112,21 @@ pub fn alter(mut table: Table, action: String, tokens: Vec<String>) {
 
             // Write new table to disk
             println!("Adding {} column", col_name);
-            save_to_disk(&table.name, &table);
+            save_with_sequences(storage, &table, sequences);
         },
         "modify" | "MODIFY" => {
-            if tokens.len() <= 1 {
-                println!("Missing parameters");
-                return;
-            }
-
-            let col_name = &tokens[0];
-            let new_type = &tokens[1];
-
-            let col_index = table.schema.iter().position(|f| &f.name == col_name);
-            if let Some(i) = col_index {
-                match new_type.as_str() {
-                    "TEXT" => {
-                        if table.schema[i].data_type == Some(FieldDataType::TEXT) {
-                            println!("Schema field {} already set to TEXT", col_name);
-                            return;
-                        } else {
-                            table.schema[i].data_type = Some(FieldDataType::TEXT);
-                        }
-                    },
-                    "NUMBER" => {
-                        if table.schema[i].data_type == Some(FieldDataType::NUMBER) {
-                            println!("Schema field {} already set to NUMBER", col_name);
-                            return;
-                        } else {
-                            table.schema[i].data_type = Some(FieldDataType::NUMBER);
-                        }
-                    },
-                    "BOOLEAN" => {
-                        if table.schema[i].data_type == Some(FieldDataType::BOOLEAN) {
-                            println!("Schema field {} already set to BOOLEAN", col_name);
-                            return;
-                        } else {
-                            table.schema[i].data_type = Some(FieldDataType::BOOLEAN);
-                        }
-                    },
-                    "SERIAL" => {
-                        if table.schema[i].data_type == Some(FieldDataType::SERIAL) {
-                            println!("Schema field {} already set to SERIAL", col_name);
-                            return;
-                        } else {
-                            table.schema[i].data_type = Some(FieldDataType::SERIAL);
-                        }
-                    },
-                    _ => {
-                        println!("{} is an invalid data type", new_type);
-                        return;
-                    }
-                }
-                if new_type.as_str() == "SERIAL" {
-                    table.schema[i].serial = Some(SerialState { next_val: 1 });
-                }
-            } else {
-                println!("Column not found");
-            }
-
-            let mut rows: Vec<(&Value, &mut Value)> = table.data.iter_mut().map(|f| (f.0, f.1)).collect();
-            rows.sort_by(|c, n| {
-                match (c.0, n.0) {
-                    (Value::Number(curr), Value::Number(next)) => {
-                        let curr_i = curr.as_f64().unwrap();
-                        let next_i = next.as_f64().unwrap();
-                        if curr_i < next_i {
-                            Ordering::Less
-                        } else {
-                            Ordering::Greater
-                        }
-                    },
-                    (Value::String(curr), Value::String(next)) => {
-                        curr.cmp(next)
-                    },
-                    (Value::Bool(curr), Value::Bool(next)) => {
-                        if *curr && !next {
-                            Ordering::Greater
-                        } else if !curr && *next {
-                            Ordering::Less
-                        } else {
-                            Ordering::Equal
-                        }
-                    },
-                    _ => { Ordering::Equal }
-                }
-            });
-
-            // Create updated set of data
-            for row in &mut rows {
-                if let (Some(map), Some(i)) = (row.1.as_object_mut(), col_index) {
-                    if table.schema[i].data_type.is_none() {
-                        println!("Error in columns existing data type");
-                        return;
-                    }
-                    let field = &mut table.schema[i];
-                    let val = map.get_mut(&field.name);
-                    match field.data_type {
-                        Some(FieldDataType::TEXT) => {
-                            if let Some(v) = &val {
-                                map[&field.name] = Value::String(v.to_string().trim().to_string());
-                            }
-                        },
-                        Some(FieldDataType::NUMBER) => {
-                            if let Some(v) = &val {
-                                if v.to_string().parse::<i64>().is_ok() {
-                                    map[&field.name] = Value::Number(v.to_string().parse::<i64>().unwrap().into());
-                                } else {
-                                    map[&field.name] = Value::Number(0.into());
-                                }
-                            }
-                        },
-                        Some(FieldDataType::BOOLEAN) => {
-                            if let Value::String(v) = &val.unwrap() {
-                                if v.to_string().parse::<bool>().is_ok() {
-                                    map[&field.name] = Value::Bool(v.to_string().parse::<bool>().unwrap());
-                                } else {
-                                    map[&field.name] = Value::Bool(false);
-                                }
-                            }
-                        },
-                        Some(FieldDataType::SERIAL) => {
-                            match field.serial.as_mut() {
-                                Some(next) => {
-                                    let curr = next.next_val;
-                                    next.next_val += 1;
-                                    if field.primary_key {
-                                        map.insert(field.name.clone(), Value::from(curr));
-                                    }
-                                    row.1[&field.name] = Value::from(curr);
-                                },
-                                None => {eprintln!("Error: SerialState not found");}
-                            }
-                        },
-                        None => {}
-                    }
-                }
-            }
-            println!("Modified column {} to use {} data type", col_name, new_type);
-            save_to_disk(&table.name, &table);
+            modify_column(storage, table, &tokens);
         },
         "drop" | "DROP" => {
-            if tokens.len() <= 0 {
+            if tokens.is_empty() {
                 println!("Missing parameters");
                 return;
             }
+            match tokens[0].to_uppercase().as_str() {
+                "PRIMARY" => return drop_primary_key(storage, table, &tokens[1..]),
+                "CONSTRAINT" => return drop_constraint(storage, table, &tokens[1..]),
+                _ => {},
+            }
 
             let col_name = &tokens[0];
 
@@ -260,9 +138,16 @@ pub fn alter(mut table: Table, action: String, tokens: Vec<String>) {
                 }
             }
 
+            if let Some(constraint) = table.constraints.iter().find(|c| constraint_columns(c).contains(col_name)) {
+                println!("Column {} is used by constraint {}", col_name, constraint.name);
+                return;
+            }
+
+            let Some(mut sequences) = load_sequences(storage, &table) else { return };
             let col_index = table.schema.iter().position(|f| &f.name == col_name);
             if let Some(i) = col_index {
-                table.schema.remove(i);
+                let field = table.schema.remove(i);
+                sequences.drop_owned(&field);
             } else {
                 println!("Column not found");
                 return;
@@ -275,7 +160,7 @@ pub fn alter(mut table: Table, action: String, tokens: Vec<String>) {
             }
 
             println!("Dropping {} column", col_name);
-            save_to_disk(&table.name, &table);
+            save_with_sequences(storage, &table, sequences);
         },
         "rename" | "RENAME" => {
             if tokens.len() <= 1 {
@@ -283,6 +168,11 @@ pub fn alter(mut table: Table, action: String, tokens: Vec<String>) {
                 return;
             }
 
+            if tokens[0] == "TO" || tokens[0] == "to" {
+                rename_table(storage, &table, &tokens[1]);
+                return;
+            }
+
             let col_name = &tokens[0];
             let new_name = &tokens[1];
 
@@ -301,8 +191,13 @@ pub fn alter(mut table: Table, action: String, tokens: Vec<String>) {
                     map.remove(col_name);
                 }
             }
+            for constraint in table.constraints.iter_mut() {
+                rename_constraint_column(constraint, col_name, new_name);
+            }
+            let Some(mut sequences) = load_sequences(storage, &table) else { return };
+            sequences.rename_owner_column(&table.name, col_name, new_name);
             println!("Renamed column {} to {}", col_name, new_name);
-            save_to_disk(&table.name, &table);
+            save_with_sequences(storage, &table, sequences);
         },
         _ => {
             println!("not found");
@@ -310,3 +205,361 @@ pub fn alter(mut table: Table, action: String, tokens: Vec<String>) {
     }
 
 }
+
+// Syntax: <column> <type> [USING <expr>] [STRICT | LENIENT]
+// Every value is converted before anything is written, so a strict failure leaves the table untouched.
+fn modify_column(storage: &mut dyn StorageBackend, mut table: Table, tokens: &[String]) {
+    if tokens.len() <= 1 {
+        println!("Missing parameters");
+        return;
+    }
+
+    let col_name = &tokens[0];
+    let new_type = match tokens[1].to_uppercase().as_str() {
+        "TEXT" => FieldDataType::TEXT,
+        "NUMBER" => FieldDataType::NUMBER,
+        "BOOLEAN" => FieldDataType::BOOLEAN,
+        "SERIAL" => FieldDataType::SERIAL,
+        _ => {
+            println!("{} is an invalid data type", tokens[1]);
+            return;
+        }
+    };
+    let (using, mode) = match parse_modify_options(&tokens[2..]) {
+        Ok(options) => options,
+        Err(e) => {
+            println!("Error: {}", e);
+            return;
+        }
+    };
+
+    let Some(i) = table.schema.iter().position(|f| &f.name == col_name) else {
+        println!("Column not found");
+        return;
+    };
+    if table.schema[i].data_type.as_ref() == Some(&new_type) && using.is_none() {
+        println!("Schema field {} already set to {:?}", col_name, new_type);
+        return;
+    }
+    let primary_key = table.schema[i].primary_key;
+
+    let (mut converted, failures) = convert_column(&table, col_name, &new_type, using.as_ref());
+    if !failures.is_empty() {
+        for failure in &failures {
+            println!("Row {}: cannot convert {} to {:?}: {}", failure.key, failure.value, new_type, failure.reason);
+        }
+        if mode == ConversionMode::Strict || primary_key {
+            if primary_key && mode == ConversionMode::Lenient {
+                println!("Primary key values cannot be set to NULL");
+            }
+            println!("No changes made: {} of {} rows could not be converted", failures.len(), table.data.len());
+            return;
+        }
+        for failure in &failures {
+            converted.insert(failure.key.clone(), Value::Null);
+        }
+        println!("{} rows set to NULL", failures.len());
+    }
+
+    // Existing serial values are kept and empty ones are numbered after the highest. A column
+    // that already has a sequence keeps it, only moving it forward.
+    let Some(mut sequences) = load_sequences(storage, &table) else { return };
+    if new_type == FieldDataType::SERIAL {
+        let highest = converted.values().filter_map(|v| v.as_i64()).max();
+        if table.schema[i].serial.as_ref().is_none_or(|s| s.sequence.is_none()) {
+            let counter = table.schema[i].serial.as_ref().and_then(|s| s.next_val).unwrap_or(1);
+            let start = highest.map_or(1, |h| h.saturating_add(1)).max(counter);
+            table.schema[i].serial = Some(SerialState { sequence: None, next_val: Some(start) });
+            sequences.own_serial_sequences(&mut table);
+        } else if let Some(h) = highest {
+            sequences.advance_past(&table.schema[i], h);
+        }
+        let mut empty: Vec<Value> = converted.iter().filter(|(_, v)| v.is_null()).map(|(k, _)| k.clone()).collect();
+        empty.sort_by(compare_keys);
+        for key in empty {
+            match sequences.serial_value(&table.schema[i]) {
+                Ok(value) => { converted.insert(key, value); },
+                Err(e) => {
+                    println!("No changes made: {}", e);
+                    return;
+                }
+            }
+        }
+    } else {
+        sequences.drop_owned(&table.schema[i]);
+        table.schema[i].serial = None;
+    }
+
+    if primary_key {
+        let mut data = HashMap::new();
+        for (key, mut row) in table.data.drain() {
+            let new_key = converted.remove(&key).unwrap_or(Value::Null);
+            row[col_name] = new_key.clone();
+            if data.insert(new_key.clone(), row).is_some() {
+                println!("No changes made: more than one row converts to primary key {}", new_key);
+                return;
+            }
+        }
+        table.data = data;
+    } else {
+        for (key, row) in table.data.iter_mut() {
+            row[col_name] = converted.remove(key).unwrap_or(Value::Null);
+        }
+    }
+    table.schema[i].data_type = Some(new_type.clone());
+
+    if primary_key {
+        rebuild_indexes(&mut table);
+    } else if let Some(index) = build_index(&table, col_name).filter(|_| table.indexes.contains_key(col_name)) {
+        table.indexes.insert(col_name.clone(), index);
+    }
+
+    let keys: Vec<Value> = table.data.keys().cloned().collect();
+    let checked = validate_rows(storage, &table, &keys).and_then(|_| {
+        if primary_key { check_references(storage, &table) } else { Ok(()) }
+    });
+    if let Err(e) = checked {
+        println!("No changes made: {}", e);
+        return;
+    }
+
+    println!("Modified column {} to use {:?} data type", col_name, new_type);
+    save_with_sequences(storage, &table, sequences);
+}
+
+fn parse_modify_options(tokens: &[String]) -> Result<(Option<Expr>, ConversionMode), String> {
+    let mut parser = Parser::new(tokenize(&tokens.join(" "))?);
+    let using = if parser.eat_keyword("USING") {
+        Some(parser.parse_expr()?)
+    } else {
+        None
+    };
+    let mode = if parser.eat_keyword("LENIENT") {
+        ConversionMode::Lenient
+    } else {
+        parser.eat_keyword("STRICT");
+        ConversionMode::Strict
+    };
+    parser.expect_end()?;
+    Ok((using, mode))
+}
+
+// Syntax after PRIMARY: KEY (<column>). Rows move to the values of the new key column.
+fn add_primary_key(storage: &mut dyn StorageBackend, mut table: Table, tokens: &[String]) {
+    let column = match parse_primary_key(tokens) {
+        Ok(c) => c,
+        Err(e) => {
+            println!("Error: {}", e);
+            return;
+        }
+    };
+    if let Some(key) = table.schema.iter().find(|f| f.primary_key) {
+        println!("Table already has primary key {}", key.name);
+        return;
+    }
+    let Some(i) = table.schema.iter().position(|f| f.name == column) else {
+        println!("Column not found");
+        return;
+    };
+
+    let mut data = HashMap::new();
+    for (_, row) in table.data.drain() {
+        let key = row.get(&column).cloned().unwrap_or(Value::Null);
+        if key.is_null() {
+            println!("Cannot add primary key: {} has NULL values", column);
+            return;
+        }
+        if let Err(e) = check_type(&key, &table.schema[i].data_type) {
+            println!("Cannot add primary key: {}", e);
+            return;
+        }
+        if data.contains_key(&key) {
+            println!("Cannot add primary key: {} has duplicate value {}", column, key);
+            return;
+        }
+        data.insert(key, row);
+    }
+    table.data = data;
+    table.schema[i].primary_key = true;
+    rebuild_indexes(&mut table);
+
+    println!("Primary key set to {}", column);
+    save_altered_table(storage, &table);
+}
+
+// Without a key column, rows are numbered in their old key order
+fn drop_primary_key(storage: &mut dyn StorageBackend, mut table: Table, tokens: &[String]) {
+    if !tokens.first().is_some_and(|t| t.eq_ignore_ascii_case("KEY")) || tokens.len() > 1 {
+        println!("Expected DROP PRIMARY KEY");
+        return;
+    }
+    let Some(i) = table.schema.iter().position(|f| f.primary_key) else {
+        println!("Table has no primary key");
+        return;
+    };
+    match referencing_constraints(storage, &table.name) {
+        Ok(referencing) => {
+            if let Some((name, constraint)) = referencing.first() {
+                println!("Primary key is referenced by constraint {} on {}", constraint.name, name);
+                return;
+            }
+        },
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            return;
+        }
+    }
+
+    let mut rows: Vec<(Value, Value)> = table.data.drain().collect();
+    rows.sort_by(|a, b| compare_keys(&a.0, &b.0));
+    table.data = rows.into_iter().enumerate().map(|(n, (_, row))| (Value::from(n as u64 + 1), row)).collect();
+    table.schema[i].primary_key = false;
+    rebuild_indexes(&mut table);
+
+    println!("Dropped primary key {}", table.schema[i].name);
+    save_altered_table(storage, &table);
+}
+
+fn parse_primary_key(tokens: &[String]) -> Result<String, String> {
+    let mut parser = Parser::new(tokenize(&tokens.join(" "))?);
+    parser.expect_keyword("KEY")?;
+    let column = parse_single_column(&mut parser)?;
+    parser.expect_end()?;
+    Ok(column)
+}
+
+fn add_constraint(storage: &mut dyn StorageBackend, mut table: Table, tokens: &[String]) {
+    let constraint = match parse_constraint(storage, tokens, &table) {
+        Ok(c) => c,
+        Err(e) => {
+            println!("Error: {}", e);
+            return;
+        }
+    };
+    let name = constraint.name.clone();
+    table.constraints.push(constraint);
+
+    // Existing rows have to satisfy the constraint before it is kept
+    let mut keys: Vec<Value> = table.data.keys().cloned().collect();
+    keys.sort_by(compare_keys);
+    if let Err(e) = validate_rows(storage, &table, &keys) {
+        println!("Cannot add constraint: {}", e);
+        return;
+    }
+
+    println!("Added constraint {}", name);
+    save_altered_table(storage, &table);
+}
+
+fn drop_constraint(storage: &mut dyn StorageBackend, mut table: Table, tokens: &[String]) {
+    let Some(name) = tokens.first() else {
+        println!("Missing parameters");
+        return;
+    };
+    let Some(i) = table.constraints.iter().position(|c| &c.name == name) else {
+        println!("Constraint {} not found", name);
+        return;
+    };
+    table.constraints.remove(i);
+    println!("Dropped constraint {}", name);
+    save_altered_table(storage, &table);
+}
+
+// Renames within the table's own database, unless the new name says otherwise
+fn rename_table(storage: &mut dyn StorageBackend, table: &Table, new_name: &str) {
+    let (database, _) = split_table_name(&table.name);
+    let new_name = resolve_table_name(new_name, &database);
+
+    let mut catalog = match load_catalog(storage) {
+        Ok(c) => c,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            return;
+        }
+    };
+    let (new_database, _) = split_table_name(&new_name);
+    if !catalog.has_database(&new_database) {
+        println!("Database {} not found", new_database);
+        return;
+    }
+    if catalog.table(&new_name).is_some() {
+        println!("Table {} already exists", new_name);
+        return;
+    }
+    if let Some(view) = catalog.dependent_views(&table.name).first() {
+        println!("Table {} is read by view {}; drop the view first", table.name, view);
+        return;
+    }
+
+    let referencing = match referencing_constraints(storage, &table.name) {
+        Ok(r) => r,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            return;
+        }
+    };
+    if let Err(e) = storage.rename_table(&table.name, &new_name) {
+        eprintln!("Error: {}", e);
+        return;
+    }
+
+    // Foreign keys elsewhere, and on the table itself, follow the new name
+    let mut referencing_tables: Vec<String> = referencing.into_iter()
+        .map(|(t, _)| if t == table.name { new_name.clone() } else { t })
+        .collect();
+    referencing_tables.dedup();
+    for name in referencing_tables {
+        let updated = storage.load_table(&name).and_then(|mut t| {
+            for constraint in t.constraints.iter_mut() {
+                if let ConstraintKind::ForeignKey { ref_table, .. } = &mut constraint.kind {
+                    if *ref_table == table.name {
+                        *ref_table = new_name.clone();
+                    }
+                }
+            }
+            storage.save_table(&t)
+        });
+        if let Err(e) = updated {
+            eprintln!("Error: {}", e);
+        }
+    }
+    catalog.rename_table(&table.name, &new_name);
+    if let Err(e) = storage.save_catalog(&catalog) {
+        eprintln!("Error: {}", e);
+    }
+    println!("Renamed table {} to {}", table.name, new_name);
+}
+
+fn load_sequences(storage: &mut dyn StorageBackend, table: &Table) -> Option<Sequences> {
+    match Sequences::load(storage, &split_table_name(&table.name).0) {
+        Ok(sequences) => Some(sequences),
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            None
+        }
+    }
+}
+
+// Sequences go first, so a saved table never points at one that isn't there yet
+fn save_with_sequences(storage: &mut dyn StorageBackend, table: &Table, sequences: Sequences) {
+    if let Err(e) = sequences.save(storage) {
+        eprintln!("Error: {}", e);
+        return;
+    }
+    save_altered_table(storage, table);
+}
+
+// Saves the table and records the new schema version in the catalog
+fn save_altered_table(storage: &mut dyn StorageBackend, table: &Table) {
+    if let Err(e) = storage.save_table(table) {
+        eprintln!("Error: {}", e);
+        return;
+    }
+    let updated = load_catalog(storage).and_then(|mut catalog| {
+        catalog.update_table(table, true);
+        storage.save_catalog(&catalog)
+    });
+    if let Err(e) = updated {
+        eprintln!("Error: {}", e);
+    }
+}
@@ -0,0 +1,83 @@
+use std::{cmp::{Ordering, Reverse}, collections::BTreeMap};
+
+use serde_json::Value;
+
+use crate::{catalog::{load_catalog, resolve_table_name}, models::{ColumnStats, Table, TableStats}, storage::{btree::compare_keys, StorageBackend}};
+
+const MOST_COMMON_VALUES: usize = 10;
+const HISTOGRAM_BUCKETS: usize = 10;
+
+// Syntax: ANALYZE [<table>]
+// Without a table, every table in the current database is analyzed.
+pub fn analyze(storage: &mut dyn StorageBackend, database: &str, table: Option<String>) {
+    let mut catalog = match load_catalog(storage) {
+        Ok(c) => c,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            return;
+        }
+    };
+    let names: Vec<String> = match table {
+        Some(name) if catalog.table(&name).is_some() => vec![name],
+        Some(name) => {
+            println!("Table {} not found", name);
+            return;
+        },
+        None => match catalog.databases.get(database) {
+            Some(entry) => entry.tables.keys().map(|t| resolve_table_name(t, database)).collect(),
+            None => {
+                println!("Database {} not found", database);
+                return;
+            }
+        },
+    };
+
+    for name in &names {
+        let table = match storage.load_table(name) {
+            Ok(t) => t,
+            Err(e) => {
+                eprintln!("Error: {}", e);
+                return;
+            }
+        };
+        catalog.set_stats(name, collect_stats(&table));
+    }
+    match storage.save_catalog(&catalog) {
+        Ok(_) => println!("{} table(s) analyzed", names.len()),
+        Err(e) => eprintln!("Error: {}", e),
+    }
+}
+
+pub fn collect_stats(table: &Table) -> TableStats {
+    let mut columns = BTreeMap::new();
+    for field in &table.schema {
+        let mut values: Vec<&Value> = table.data.values().map(|row| row.get(&field.name).unwrap_or(&Value::Null)).collect();
+        let nulls = values.iter().filter(|v| v.is_null()).count();
+        values.retain(|v| !v.is_null());
+        values.sort_by(|a, b| compare_keys(a, b));
+
+        let mut counts: Vec<(Value, usize)> = vec![];
+        for value in &values {
+            match counts.last_mut() {
+                Some((last, count)) if compare_keys(last, value) == Ordering::Equal => *count += 1,
+                _ => counts.push(((*value).clone(), 1)),
+            }
+        }
+        let distinct = counts.len();
+        let mut most_common: Vec<(Value, usize)> = counts.into_iter().filter(|(_, count)| *count > 1).collect();
+        most_common.sort_by_key(|(_, count)| Reverse(*count));
+        most_common.truncate(MOST_COMMON_VALUES);
+
+        // The lowest value, the highest, and the ones splitting the rest into even buckets
+        let histogram = match values.len() {
+            0 => vec![],
+            1 => vec![values[0].clone()],
+            n => {
+                let buckets = HISTOGRAM_BUCKETS.min(n - 1);
+                (0..=buckets).map(|i| values[i * (n - 1) / buckets].clone()).collect()
+            },
+        };
+        columns.insert(field.name.clone(), ColumnStats { nulls, distinct, most_common, histogram });
+    }
+    TableStats { rows: table.data.len(), columns }
+}
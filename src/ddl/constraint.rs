@@ -0,0 +1,354 @@
+use std::collections::{HashMap, HashSet};
+
+use serde_json::Value;
+
+use crate::{catalog::{load_catalog, resolve_table_name, split_table_name}, dql::expr::{evaluate, parse_expression, tokenize, truth, Parser}, models::{Constraint, ConstraintKind, Table}, storage::StorageBackend};
+
+// Syntax after CONSTRAINT:
+//   <name> UNIQUE (<column>, ...)
+//   <name> CHECK (<expr>)
+//   <name> FOREIGN KEY (<column>) REFERENCES <table> [(<column>)]
+pub fn parse_constraint(storage: &mut dyn StorageBackend, tokens: &[String], table: &Table) -> Result<Constraint, String> {
+    let source = tokens.join(" ");
+    let mut parser = Parser::new(tokenize(&source)?);
+    let name = parser.parse_identifier()?;
+    if table.constraints.iter().any(|c| c.name == name) {
+        return Err(format!("Constraint {} already exists", name));
+    }
+
+    let kind = if parser.eat_keyword("UNIQUE") {
+        ConstraintKind::Unique { columns: parse_column_list(&mut parser)? }
+    } else if parser.eat_keyword("CHECK") {
+        // Stored as it reads back from the parsed tree, so renames can rewrite it
+        parser.expect_symbol("(")?;
+        let expr = parser.parse_expr()?;
+        parser.expect_symbol(")")?;
+        if expr.has_subquery() {
+            return Err("CHECK cannot contain a subquery".to_string());
+        }
+        ConstraintKind::Check { expression: expr.to_string() }
+    } else if parser.eat_keyword("FOREIGN") {
+        parser.expect_keyword("KEY")?;
+        let column = parse_single_column(&mut parser)?;
+        parser.expect_keyword("REFERENCES")?;
+        let mut ref_table = parser.parse_identifier()?;
+        if parser.eat_symbol(".") {
+            ref_table = format!("{}.{}", ref_table, parser.parse_identifier()?);
+        }
+        let ref_table = resolve_table_name(&ref_table, &split_table_name(&table.name).0);
+        let ref_column = if parser.peek_symbol("(") {
+            Some(parse_single_column(&mut parser)?)
+        } else {
+            None
+        };
+
+        // Only primary keys can be referenced, since rows are looked up by key
+        let target = if ref_table == table.name { table.clone() } else { storage.load_schema(&ref_table)? };
+        let ref_key = target.schema.iter().find(|f| f.primary_key).map(|f| f.name.clone());
+        match (ref_key, ref_column) {
+            (None, _) => return Err(format!("Table {} has no primary key to reference", ref_table)),
+            (Some(key), Some(col)) if key != col => return Err(format!("{} is not the primary key of {}", col, ref_table)),
+            _ => {},
+        }
+        ConstraintKind::ForeignKey { column, ref_table }
+    } else {
+        return Err("Expected UNIQUE, CHECK or FOREIGN KEY".to_string());
+    };
+    parser.expect_end()?;
+
+    let constraint = Constraint { name, kind };
+    for column in constraint_columns(&constraint) {
+        if !table.schema.iter().any(|f| f.name == column) {
+            return Err(format!("Column {} not found", column));
+        }
+    }
+    Ok(constraint)
+}
+
+pub fn parse_column_list(parser: &mut Parser) -> Result<Vec<String>, String> {
+    parser.expect_symbol("(")?;
+    let mut columns = vec![parser.parse_identifier()?];
+    while parser.eat_symbol(",") {
+        columns.push(parser.parse_identifier()?);
+    }
+    parser.expect_symbol(")")?;
+    Ok(columns)
+}
+
+pub fn parse_single_column(parser: &mut Parser) -> Result<String, String> {
+    let mut columns = parse_column_list(parser)?;
+    if columns.len() != 1 {
+        return Err("Expected a single column".to_string());
+    }
+    Ok(columns.remove(0))
+}
+
+// Columns of the constrained table that the constraint reads
+pub fn constraint_columns(constraint: &Constraint) -> Vec<String> {
+    match &constraint.kind {
+        ConstraintKind::Unique { columns } => columns.clone(),
+        ConstraintKind::Check { expression } => parse_expression(expression).map(|e| e.columns()).unwrap_or_default(),
+        ConstraintKind::ForeignKey { column, .. } => vec![column.clone()],
+    }
+}
+
+pub fn rename_constraint_column(constraint: &mut Constraint, from: &str, to: &str) {
+    let rename = |name: &mut String| {
+        if name == from {
+            *name = to.to_string();
+        }
+    };
+    match &mut constraint.kind {
+        ConstraintKind::Unique { columns } => columns.iter_mut().for_each(rename),
+        ConstraintKind::Check { expression } => {
+            if let Ok(mut expr) = parse_expression(expression) {
+                expr.visit_columns_mut(&mut { rename });
+                *expression = expr.to_string();
+            }
+        },
+        ConstraintKind::ForeignKey { column, .. } => rename(column),
+    }
+}
+
+// Checks the table's own constraints. Uniqueness is checked across every row, while
+// checks and foreign keys only look at the rows in `keys`, the ones that changed.
+pub fn validate_rows(storage: &mut dyn StorageBackend, table: &Table, keys: &[Value]) -> Result<(), String> {
+    check_rows(storage, table, keys, None)
+}
+
+// Like validate_rows for a table a key change cascaded into, before `referenced`, the table
+// whose keys moved, has been saved
+pub fn validate_cascaded_rows(storage: &mut dyn StorageBackend, table: &Table, keys: &[Value], referenced: &Table) -> Result<(), String> {
+    check_rows(storage, table, keys, Some(referenced))
+}
+
+fn check_rows(storage: &mut dyn StorageBackend, table: &Table, keys: &[Value], referenced: Option<&Table>) -> Result<(), String> {
+    for constraint in &table.constraints {
+        match &constraint.kind {
+            ConstraintKind::Unique { columns } => {
+                let mut seen = HashSet::new();
+                for row in table.data.values() {
+                    let values: Vec<Value> = columns.iter().map(|c| row.get(c).cloned().unwrap_or(Value::Null)).collect();
+                    // NULL never equals anything, so it can repeat
+                    if values.iter().any(|v| v.is_null()) {
+                        continue;
+                    }
+                    if !seen.insert(values.clone()) {
+                        let shown: Vec<String> = values.iter().map(|v| v.to_string()).collect();
+                        return Err(format!("Constraint {} violated: duplicate value ({})", constraint.name, shown.join(", ")));
+                    }
+                }
+            },
+            ConstraintKind::Check { expression } => {
+                let expr = parse_expression(expression)?;
+                for key in keys {
+                    let Some(row) = table.data.get(key) else { continue };
+                    // Unknown (NULL) passes, only false fails
+                    if truth(&evaluate(&expr, row)?)? == Some(false) {
+                        return Err(format!("Row {} violates constraint {}: CHECK ({})", key, constraint.name, expression));
+                    }
+                }
+            },
+            ConstraintKind::ForeignKey { column, ref_table } => {
+                for key in keys {
+                    let value = match table.data.get(key).and_then(|row| row.get(column)) {
+                        Some(v) if !v.is_null() => v,
+                        _ => continue,
+                    };
+                    let exists = if ref_table == &table.name {
+                        table.data.contains_key(value)
+                    } else if let Some(referenced) = referenced.filter(|r| &r.name == ref_table) {
+                        referenced.data.contains_key(value)
+                    } else {
+                        storage.get_row(ref_table, value)?.is_some()
+                    };
+                    if !exists {
+                        return Err(format!("Row {} violates constraint {}: {} not found in {}", key, constraint.name, value, ref_table));
+                    }
+                }
+            },
+        }
+    }
+    Ok(())
+}
+
+// Every (table, constraint) whose foreign key points at `name`, including the table itself
+pub fn referencing_constraints(storage: &mut dyn StorageBackend, name: &str) -> Result<Vec<(String, Constraint)>, String> {
+    Ok(load_catalog(storage)?.referencing(name))
+}
+
+// Makes sure every row that references this table still finds its key after a change
+pub fn check_references(storage: &mut dyn StorageBackend, table: &Table) -> Result<(), String> {
+    for (referencing, constraint) in referencing_constraints(storage, &table.name)? {
+        let ConstraintKind::ForeignKey { column, .. } = &constraint.kind else { continue };
+        let loaded;
+        let rows: &HashMap<Value, Value> = if referencing == table.name {
+            &table.data
+        } else {
+            loaded = storage.load_table(&referencing)?;
+            &loaded.data
+        };
+        for row in rows.values() {
+            match row.get(column) {
+                Some(value) if !value.is_null() && !table.data.contains_key(value) => {
+                    return Err(format!("{} is still referenced by {} through constraint {}", value, referencing, constraint.name));
+                },
+                _ => {},
+            }
+        }
+    }
+    Ok(())
+}
+
+// Rows keyed by primary key, as a Table holds them
+pub type Rows = HashMap<Value, Value>;
+
+// Points foreign keys at the new key when a referenced row moves. Rows of the table itself are
+// updated in place, adding how they were to `old`; other referencing tables come back changed but
+// unsaved, with how their changed rows were.
+pub fn cascade_key_changes(storage: &mut dyn StorageBackend, table: &mut Table, moved: &HashMap<Value, Value>, old: &mut Rows) -> Result<Vec<(Table, Rows)>, String> {
+    let mut changed: Vec<(Table, Rows)> = vec![];
+    for (referencing, constraint) in referencing_constraints(storage, &table.name)? {
+        let ConstraintKind::ForeignKey { column, .. } = &constraint.kind else { continue };
+        let repoint = |rows: &mut Rows, old: &mut Rows| {
+            for (key, row) in rows.iter_mut() {
+                if let Some(new_key) = row.get(column).and_then(|value| moved.get(value)).cloned() {
+                    old.entry(key.clone()).or_insert_with(|| row.clone());
+                    row[column] = new_key;
+                }
+            }
+        };
+
+        if referencing == table.name {
+            repoint(&mut table.data, old);
+            continue;
+        }
+        // A table can reference this one through several constraints
+        let position = changed.iter().position(|(t, _)| t.name == referencing);
+        let (mut other, mut other_old) = match position {
+            Some(i) => changed.remove(i),
+            None => (storage.load_table(&referencing)?, HashMap::new()),
+        };
+        repoint(&mut other.data, &mut other_old);
+        if !other_old.is_empty() {
+            changed.push((other, other_old));
+        }
+    }
+    Ok(changed)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::{ddl::{alter::alter, create::{create, CreateData}}, dml::{delete::delete, insert::insert, update::update}, models::{IndexNumber, IndexStore}, storage::{btree::compare_keys, memory::MemoryBackend}};
+
+    fn tokens(text: &str) -> Vec<String> {
+        text.split_whitespace().map(String::from).collect()
+    }
+
+    fn run_insert(storage: &mut MemoryBackend, table: &str, values: &str) -> Result<usize, String> {
+        let table = storage.load_schema(table)?;
+        insert(storage, "main", table, tokens(values)).map(|r| r.count)
+    }
+
+    fn add_constraint(storage: &mut MemoryBackend, table: &str, constraint: &str) {
+        let table = storage.load_table(table).unwrap();
+        alter(storage, table, "add".to_string(), tokens(&format!("constraint {}", constraint)));
+    }
+
+    // p(id, name) with rows 1 and 2, and c(id, pid) whose pid references p
+    fn parent_and_child() -> MemoryBackend {
+        let mut storage = MemoryBackend::new();
+        create(&mut storage, CreateData::Table { name: "p".to_string(), schema: tokens("id NUMBER KEY name TEXT") });
+        create(&mut storage, CreateData::Table { name: "c".to_string(), schema: tokens("id NUMBER KEY pid NUMBER") });
+        run_insert(&mut storage, "p", "VALUES (1, 'a'), (2, 'b')").unwrap();
+        add_constraint(&mut storage, "c", "fk FOREIGN KEY (pid) REFERENCES p");
+        storage
+    }
+
+    #[test]
+    fn foreign_keys_need_the_referenced_row() {
+        let mut storage = parent_and_child();
+        assert_eq!(run_insert(&mut storage, "c", "VALUES (10, 1), (11, NULL)"), Ok(2));
+        assert_eq!(run_insert(&mut storage, "c", "VALUES (12, 9)"), Err("Row 12 violates constraint fk: 9 not found in p".to_string()));
+        assert!(storage.get_row("c", &json!(12)).unwrap().is_none());
+    }
+
+    #[test]
+    fn unique_rejects_repeats_but_not_nulls() {
+        let mut storage = parent_and_child();
+        add_constraint(&mut storage, "p", "u UNIQUE (name)");
+        assert_eq!(run_insert(&mut storage, "p", "VALUES (3, 'a')"), Err("Constraint u violated: duplicate value (\"a\")".to_string()));
+        assert_eq!(run_insert(&mut storage, "p", "VALUES (3, NULL), (4, NULL)"), Ok(2));
+    }
+
+    #[test]
+    fn checks_only_fail_when_false() {
+        let mut storage = parent_and_child();
+        add_constraint(&mut storage, "p", "small CHECK (id < 100)");
+        assert_eq!(run_insert(&mut storage, "p", "VALUES (100, 'x')"), Err("Row 100 violates constraint small: CHECK (id < 100)".to_string()));
+        add_constraint(&mut storage, "p", "named CHECK (name != 'z')");
+        assert_eq!(run_insert(&mut storage, "p", "VALUES (5, NULL)"), Ok(1));
+    }
+
+    #[test]
+    fn referenced_rows_cant_be_deleted() {
+        let mut storage = parent_and_child();
+        run_insert(&mut storage, "c", "VALUES (10, 1)").unwrap();
+        let p = storage.load_table("p").unwrap();
+        assert_eq!(delete(&mut storage, p, tokens("WHERE id = 1")).map(|r| r.count), Err("1 is still referenced by c through constraint fk".to_string()));
+        let p = storage.load_table("p").unwrap();
+        assert_eq!(delete(&mut storage, p, tokens("WHERE id = 2")).map(|r| r.count), Ok(1));
+        assert_eq!(storage.load_table("p").unwrap().data.len(), 1);
+    }
+
+    #[test]
+    fn key_changes_cascade_to_referencing_rows() {
+        let mut storage = parent_and_child();
+        create(&mut storage, CreateData::Index { table: "c".to_string(), column: "pid".to_string() });
+        run_insert(&mut storage, "c", "VALUES (10, 1), (11, 1), (12, 2)").unwrap();
+
+        let p = storage.load_table("p").unwrap();
+        update(&mut storage, "main", p, tokens("SET id = 5 WHERE id = 1")).unwrap();
+        let c = storage.load_table("c").unwrap();
+        assert_eq!(c.data[&json!(10)]["pid"], json!(5));
+        assert_eq!(c.data[&json!(11)]["pid"], json!(5));
+        assert_eq!(c.data[&json!(12)]["pid"], json!(2));
+
+        let IndexStore::Number(index) = &c.indexes["pid"].index_data else { panic!("pid should have a number index") };
+        let mut moved = index[&IndexNumber::Int(5)].clone();
+        moved.sort_by(compare_keys);
+        assert_eq!(moved, vec![json!(10), json!(11)]);
+        assert!(!index.contains_key(&IndexNumber::Int(1)));
+    }
+
+    #[test]
+    fn cascades_that_break_a_child_constraint_change_nothing() {
+        let mut storage = parent_and_child();
+        add_constraint(&mut storage, "c", "small CHECK (pid < 10)");
+        run_insert(&mut storage, "c", "VALUES (10, 1)").unwrap();
+
+        let p = storage.load_table("p").unwrap();
+        let result = update(&mut storage, "main", p, tokens("SET id = 20 WHERE id = 1")).map(|r| r.count);
+        assert_eq!(result, Err("Row 10 violates constraint small: CHECK (pid < 10)".to_string()));
+        assert!(storage.get_row("p", &json!(1)).unwrap().is_some());
+        assert_eq!(storage.get_row("c", &json!(10)).unwrap().unwrap()["pid"], json!(1));
+    }
+
+    #[test]
+    fn key_changes_cascade_within_the_table() {
+        let mut storage = MemoryBackend::new();
+        create(&mut storage, CreateData::Table { name: "e".to_string(), schema: tokens("id NUMBER KEY boss NUMBER") });
+        run_insert(&mut storage, "e", "VALUES (1, 1), (2, 1), (3, 2)").unwrap();
+        add_constraint(&mut storage, "e", "fk FOREIGN KEY (boss) REFERENCES e");
+
+        let e = storage.load_table("e").unwrap();
+        update(&mut storage, "main", e, tokens("SET id = 10 WHERE id = 1")).unwrap();
+        let e = storage.load_table("e").unwrap();
+        let bosses: Vec<(i64, i64)> = [2, 3, 10].iter().map(|id| (*id, e.data[&json!(id)]["boss"].as_i64().unwrap())).collect();
+        assert_eq!(bosses, vec![(2, 10), (3, 2), (10, 10)]);
+        assert!(!e.data.contains_key(&json!(1)));
+    }
+}
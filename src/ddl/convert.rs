@@ -0,0 +1,162 @@
+use std::collections::HashMap;
+
+use serde_json::Value;
+
+use crate::{dql::expr::{evaluate, float_value, value_to_text, Expr}, models::{FieldDataType, Table}, storage::btree::compare_keys};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ConversionMode {
+    // Abort the whole change if any row fails
+    Strict,
+    // Set failed values to NULL and carry on
+    Lenient,
+}
+
+pub struct ConversionFailure {
+    pub key: Value,
+    pub value: Value,
+    pub reason: String,
+}
+
+// Converts a single value to the target type. NULL stays NULL.
+pub fn convert_value(value: &Value, target: &FieldDataType) -> Result<Value, String> {
+    match (target, value) {
+        (_, Value::Null) => Ok(Value::Null),
+        (FieldDataType::TEXT, v) => Ok(Value::String(value_to_text(v))),
+        (FieldDataType::NUMBER, Value::Number(_)) => Ok(value.clone()),
+        (FieldDataType::NUMBER, Value::Bool(b)) => Ok(Value::from(*b as i64)),
+        (FieldDataType::NUMBER, Value::String(s)) => {
+            let trimmed = s.trim();
+            if let Ok(i) = trimmed.parse::<i64>() {
+                return Ok(Value::from(i));
+            }
+            match trimmed.parse::<f64>() {
+                Ok(f) if f.is_finite() => float_value(f),
+                _ => Err(format!("'{}' is not a number", s)),
+            }
+        },
+        (FieldDataType::BOOLEAN, Value::Bool(_)) => Ok(value.clone()),
+        (FieldDataType::BOOLEAN, Value::Number(n)) => match n.as_f64().unwrap_or(f64::NAN) {
+            0.0 => Ok(Value::Bool(false)),
+            1.0 => Ok(Value::Bool(true)),
+            _ => Err(format!("{} is not 0 or 1", n)),
+        },
+        (FieldDataType::BOOLEAN, Value::String(s)) => match s.trim().to_lowercase().as_str() {
+            "true" | "t" | "yes" | "y" | "1" => Ok(Value::Bool(true)),
+            "false" | "f" | "no" | "n" | "0" => Ok(Value::Bool(false)),
+            _ => Err(format!("'{}' is not a boolean", s)),
+        },
+        (FieldDataType::SERIAL, v) => {
+            // Serial values are whole numbers a sequence could hand out
+            let number = convert_value(v, &FieldDataType::NUMBER)?;
+            let whole = number.as_i64().or_else(|| number.as_f64().filter(|f| f.fract() == 0.0 && f.abs() < i64::MAX as f64).map(|f| f as i64));
+            match whole {
+                Some(i) => Ok(Value::from(i)),
+                None => Err(format!("{} is not a valid serial value", number)),
+            }
+        },
+        (_, other) => Err(format!("Cannot convert {} to {:?}", other, target)),
+    }
+}
+
+// Converts the column for every row, collecting each row that fails rather than
+// stopping at the first. With `using`, the expression result is converted instead.
+pub fn convert_column(table: &Table, column: &str, target: &FieldDataType, using: Option<&Expr>) -> (HashMap<Value, Value>, Vec<ConversionFailure>) {
+    let mut converted = HashMap::new();
+    let mut failures = vec![];
+
+    for (key, row) in table.data.iter() {
+        let current = row.get(column).cloned().unwrap_or(Value::Null);
+        let source = match using {
+            Some(expr) => evaluate(expr, row),
+            None => Ok(current.clone()),
+        };
+        let result = match source {
+            Ok(value) => convert_value(&value, target).map_err(|e| (value, e)),
+            Err(e) => Err((current, e)),
+        };
+        match result {
+            Ok(value) => { converted.insert(key.clone(), value); },
+            Err((value, reason)) => {
+                failures.push(ConversionFailure { key: key.clone(), value, reason });
+            }
+        }
+    }
+
+    failures.sort_by(|a, b| compare_keys(&a.key, &b.key));
+    (converted, failures)
+}
+
+// Unlike convert_value this never coerces: the value must already be of the column's type.
+// NULL fits any column.
+pub fn check_type(value: &Value, data_type: &Option<FieldDataType>) -> Result<(), String> {
+    let Some(data_type) = data_type else {
+        return Err("Schema column data type not found".to_string());
+    };
+    let fits = match (data_type, value) {
+        (_, Value::Null) => true,
+        (FieldDataType::TEXT, Value::String(_)) => true,
+        (FieldDataType::NUMBER, Value::Number(_)) => true,
+        (FieldDataType::SERIAL, Value::Number(n)) => n.as_i64().is_some(),
+        (FieldDataType::BOOLEAN, Value::Bool(_)) => true,
+        _ => false,
+    };
+    if fits {
+        Ok(())
+    } else {
+        Err(format!("{} is not a valid {:?} value", value, data_type))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{json, Value};
+
+    use super::*;
+
+    #[test]
+    fn null_stays_null_for_every_type() {
+        for target in [FieldDataType::TEXT, FieldDataType::NUMBER, FieldDataType::BOOLEAN, FieldDataType::SERIAL] {
+            assert_eq!(convert_value(&Value::Null, &target), Ok(Value::Null));
+        }
+    }
+
+    #[test]
+    fn converts_to_text() {
+        assert_eq!(convert_value(&json!(12), &FieldDataType::TEXT), Ok(json!("12")));
+        assert_eq!(convert_value(&json!(1.5), &FieldDataType::TEXT), Ok(json!("1.5")));
+        assert_eq!(convert_value(&json!(true), &FieldDataType::TEXT), Ok(json!("true")));
+    }
+
+    #[test]
+    fn converts_to_numbers() {
+        assert_eq!(convert_value(&json!(" 42 "), &FieldDataType::NUMBER), Ok(json!(42)));
+        assert_eq!(convert_value(&json!("2.5"), &FieldDataType::NUMBER), Ok(json!(2.5)));
+        assert_eq!(convert_value(&json!(true), &FieldDataType::NUMBER), Ok(json!(1)));
+        assert_eq!(convert_value(&json!("abc"), &FieldDataType::NUMBER), Err("'abc' is not a number".to_string()));
+        assert!(convert_value(&json!("inf"), &FieldDataType::NUMBER).is_err());
+    }
+
+    #[test]
+    fn converts_to_booleans() {
+        assert_eq!(convert_value(&json!("Yes"), &FieldDataType::BOOLEAN), Ok(json!(true)));
+        assert_eq!(convert_value(&json!("f"), &FieldDataType::BOOLEAN), Ok(json!(false)));
+        assert_eq!(convert_value(&json!(0), &FieldDataType::BOOLEAN), Ok(json!(false)));
+        assert_eq!(convert_value(&json!(2), &FieldDataType::BOOLEAN), Err("2 is not 0 or 1".to_string()));
+        assert!(convert_value(&json!("maybe"), &FieldDataType::BOOLEAN).is_err());
+    }
+
+    #[test]
+    fn serial_values_must_be_whole() {
+        assert_eq!(convert_value(&json!("7"), &FieldDataType::SERIAL), Ok(json!(7)));
+        assert_eq!(convert_value(&json!(3.0), &FieldDataType::SERIAL), Ok(json!(3)));
+        assert!(convert_value(&json!(3.5), &FieldDataType::SERIAL).is_err());
+        assert!(convert_value(&json!(true), &FieldDataType::SERIAL).is_ok());
+    }
+
+    #[test]
+    fn lists_and_objects_dont_convert() {
+        assert!(convert_value(&json!([1]), &FieldDataType::NUMBER).is_err());
+        assert!(convert_value(&json!({ "a": 1 }), &FieldDataType::BOOLEAN).is_err());
+    }
+}
@@ -1,73 +1,229 @@
-use std::{collections::{BTreeMap, HashMap, VecDeque}, fs::write};
-use directories::UserDirs;
+use std::collections::{BTreeMap, HashMap, VecDeque};
 use serde_json::Value;
 
-use crate::{models::{FieldDataType, FieldDef, Index, IndexNumber, IndexStore, OrderedFloat, SerialState, Table}, storage::{load_from_disk, save_to_disk}};
+use crate::{catalog::{load_catalog, split_table_name, INFORMATION_SCHEMA}, ddl::sequence::own_serial_sequences, dql::select::{select, SelectReturn}, models::{DatabaseEntry, FieldDataType, FieldDef, Index, IndexNumber, IndexStore, OrderedFloat, SerialState, Table}, storage::{btree::compare_keys, StorageBackend}};
 
 pub enum CreateData {
     Table { name: String, schema: Vec<String> },
+    TableAs { name: String, database: String, query: Vec<String> },
+    TableLike { name: String, source: String },
     Index { table: String, column: String },
-    // Not yet implemented the following
-    //Database
+    Database { name: String },
 }
 
-pub fn create(create_data: CreateData) {
+pub fn create(storage: &mut dyn StorageBackend, create_data: CreateData) {
     match create_data {
         CreateData::Table {name, schema }=> {
-            if let Some(dirs) = UserDirs::new() {
-                let mut file_name = String::from(name.clone());
-                file_name.push_str(".db");
-                let path = dirs.home_dir().join("Documents/ezpzdb/").join(file_name);
-
-                let fields = generate_schema(schema.clone().into());
-                let new_table = Table { name, schema: fields, data: HashMap::new(), indexes: HashMap::new()};
-                let schema_json = serde_json::to_string_pretty(&new_table);
-                match schema_json {
-                    Ok(sj) => {
-                        let new_file = write(path, sj);
-                        match new_file {
-                            Ok(_) => { println!("New table created"); },
-                            Err(_) => { panic!("Error creating table"); }
-                        }
-                    },
-                    Err(_) => { panic!("Error: Schema could not convert to JSON"); }
+            let fields = generate_schema(schema.clone().into());
+            let new_table = Table { name, schema: fields, data: HashMap::new(), indexes: HashMap::new(), constraints: vec![] };
+            create_table(storage, new_table);
+        },
+        CreateData::TableAs { name, database, query } => {
+            let results = select(storage, &database, query);
+            if !results.missing.is_empty() {
+                for field in results.missing {
+                    println!("Field not found: {}", field);
+                }
+                return;
+            }
+            if results.schema.is_empty() {
+                return;
+            }
+            match table_from_results(name, results) {
+                Ok(new_table) => create_table(storage, new_table),
+                Err(e) => println!("Error: {}", e),
+            }
+        },
+        CreateData::TableLike { name, source } => {
+            let source_table = match storage.load_schema(&source) {
+                Ok(t) => t,
+                Err(e) => {
+                    eprintln!("Error: {}", e);
+                    return;
+                }
+            };
+            // Same columns, indexes and constraints, but no rows and fresh serial sequences
+            let mut schema = source_table.schema;
+            for field in schema.iter_mut() {
+                if field.serial.is_some() {
+                    field.serial = Some(SerialState::default());
                 }
-
-            } else {
-                panic!("No home directory found");
             }
+            let indexes = source_table.indexes.into_iter().map(|(column, index)| {
+                let (index_type, index_data) = set_index_type(index.index_type);
+                (column, Index { indexed_column: index.indexed_column, index_type, index_data })
+            }).collect();
+            create_table(storage, Table { name, schema, data: HashMap::new(), indexes, constraints: source_table.constraints });
         },
         CreateData::Index { table, column } => {
-            let mut table_from_disk = load_from_disk(&table);
-            let column_position = table_from_disk.schema.iter().position(|p| p.name == column);
-            if let Some(pos) = column_position {
-                let index_type;
-                let mut index_data;
-                let column_type = &table_from_disk.schema[pos].data_type;
-                // assign FieldDataType to index_type, according to column type
-                if column_type.is_none() {
-                    println!("Error in schema column data");
+            let mut table_from_disk = match storage.load_table(&table) {
+                Ok(t) => t,
+                Err(e) => {
+                    eprintln!("Error: {}", e);
                     return;
-                } else {
-                    (index_type, index_data) = set_index_type(column_type.clone().unwrap());
                 }
-
-                for (key, row) in table_from_disk.data.iter() {
-                    match &mut index_data {
-                        IndexStore::Text(btree) => {
-                            set_text_index(key, row, &column, btree);
-                        },
-                        IndexStore::Number(btree) => {
-                            set_number_index(key, row, &column, &index_type, btree);
-                        },
-                        IndexStore::Boolean(btree) => {
-                            set_bool_index(key, row, &column, btree);
-                        },
+            };
+            if table_from_disk.schema.iter().any(|p| p.name == column) {
+                let index = match build_index(&table_from_disk, &column) {
+                    Some(index) => index,
+                    None => {
+                        println!("Error in schema column data");
+                        return;
                     }
+                };
+
+                table_from_disk.indexes.insert(column, index);
+                if let Err(e) = storage.save_table(&table_from_disk) {
+                    eprintln!("Error: {}", e);
+                    return;
+                }
+                let updated = load_catalog(storage).and_then(|mut catalog| {
+                    catalog.update_table(&table_from_disk, false);
+                    storage.save_catalog(&catalog)
+                });
+                if let Err(e) = updated {
+                    eprintln!("Error: {}", e);
+                }
+            }
+        },
+        CreateData::Database { name } => {
+            if name.is_empty() || name.contains('.') || name == INFORMATION_SCHEMA {
+                println!("Invalid database name: {}", name);
+                return;
+            }
+            let mut catalog = match load_catalog(storage) {
+                Ok(c) => c,
+                Err(e) => {
+                    eprintln!("Error: {}", e);
+                    return;
                 }
+            };
+            if catalog.has_database(&name) {
+                println!("Database {} already exists", name);
+                return;
+            }
+            catalog.databases.insert(name.clone(), DatabaseEntry::default());
+            match storage.save_catalog(&catalog) {
+                Ok(_) => { println!("Database {} created", name); },
+                Err(e) => { eprintln!("Error: {}", e); }
+            }
+        }
+    }
+}
+
+fn create_table(storage: &mut dyn StorageBackend, mut new_table: Table) {
+    let mut catalog = match load_catalog(storage) {
+        Ok(c) => c,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            return;
+        }
+    };
+    let (database, _) = split_table_name(&new_table.name);
+    if !catalog.has_database(&database) {
+        println!("Database {} not found", database);
+        return;
+    }
+    if catalog.table(&new_table.name).is_some() {
+        println!("Table {} already exists", new_table.name);
+        return;
+    }
+
+    own_serial_sequences(&mut catalog, &mut new_table);
+    if let Err(e) = storage.save_table(&new_table) {
+        eprintln!("Error: {}", e);
+        return;
+    }
+    println!("New table created");
+    catalog.register_table(&new_table);
+    if let Err(e) = storage.save_catalog(&catalog) {
+        eprintln!("Error: {}", e);
+    }
+}
+
+// Builds a table from the selected columns. Without the source primary key among them,
+// rows get a new SERIAL `id` key in the source key order.
+fn table_from_results(name: String, results: SelectReturn) -> Result<Table, String> {
+    let mut fields: Vec<FieldDef> = results.schema.into_iter().filter(|(keep, _)| *keep).map(|(_, f)| f).collect();
+    let mut rows: Vec<(Value, Value)> = results.filtered.into_iter().collect();
+    rows.sort_by(|a, b| compare_keys(&a.0, &b.0));
+
+    let project = |row: &Value, fields: &Vec<FieldDef>| -> Value {
+        let map: serde_json::Map<String, Value> = fields.iter()
+            .map(|f| (f.name.clone(), row.get(&f.name).cloned().unwrap_or(Value::Null)))
+            .collect();
+        Value::Object(map)
+    };
+
+    let mut data: HashMap<Value, Value> = HashMap::new();
+    if let Some(key) = fields.iter().find(|f| f.primary_key).map(|f| f.name.clone()) {
+        for (_, row) in rows {
+            data.insert(row[&key].clone(), project(&row, &fields));
+        }
+    } else {
+        if fields.iter().any(|f| f.name == "id") {
+            return Err("Select the primary key column, or rename the id column".to_string());
+        }
+        fields.insert(0, FieldDef {
+            name: "id".to_string(),
+            data_type: Some(FieldDataType::SERIAL),
+            primary_key: true,
+            serial: Some(SerialState { sequence: None, next_val: Some(rows.len() as i64 + 1) }),
+        });
+        for (i, (_, row)) in rows.iter().enumerate() {
+            let id = Value::from(i as i64 + 1);
+            let mut new_row = project(row, &fields);
+            new_row["id"] = id.clone();
+            data.insert(id, new_row);
+        }
+    }
+    Ok(Table { name, schema: fields, data, indexes: HashMap::new(), constraints: vec![] })
+}
+
+// Indexes every row's value for the column, or None if the column has no type
+pub fn build_index(table: &Table, column: &String) -> Option<Index> {
+    let column_type = table.schema.iter().find(|f| &f.name == column)?.data_type.clone()?;
+    let (index_type, mut index_data) = set_index_type(column_type);
+
+    for (key, row) in table.data.iter() {
+        match &mut index_data {
+            IndexStore::Text(btree) => {
+                set_text_index(key, row, column, btree);
+            },
+            IndexStore::Number(btree) => {
+                set_number_index(key, row, column, &index_type, btree);
+            },
+            IndexStore::Boolean(btree) => {
+                set_bool_index(key, row, column, btree);
+            },
+        }
+    }
+    Some(Index { indexed_column: column.clone(), index_type, index_data })
+}
 
-                table_from_disk.indexes.insert(column.clone(), Index { indexed_column: column, index_type: index_type.clone(), index_data });
-                save_to_disk(&table_from_disk.name, &table_from_disk);
+// Every index stores primary keys, so any re-keying leaves all of them stale
+pub fn rebuild_indexes(table: &mut Table) {
+    let columns: Vec<String> = table.indexes.keys().cloned().collect();
+    for column in columns {
+        if let Some(index) = build_index(table, &column) {
+            table.indexes.insert(column, index);
+        }
+    }
+}
+
+// Moves the rows under `keys` in every index from how `old` has them to how the table has them
+// now. A key missing from `old` is a new row, and one missing from the table a deleted row.
+pub fn reindex_rows(table: &mut Table, old: &HashMap<Value, Value>, keys: &[Value]) {
+    for (column, index) in table.indexes.iter_mut() {
+        for key in keys {
+            if let Some(row) = old.get(key) {
+                unset_index(key, row, column, &mut index.index_data);
+            }
+            let Some(row) = table.data.get(key) else { continue };
+            match &mut index.index_data {
+                IndexStore::Text(btree) => set_text_index(key, row, column, btree),
+                IndexStore::Number(btree) => set_number_index(key, row, column, &index.index_type, btree),
+                IndexStore::Boolean(btree) => set_bool_index(key, row, column, btree),
             }
         }
     }
@@ -110,7 +266,7 @@ fn generate_schema(mut schema_tokens: VecDeque<String>) -> Vec<FieldDef> {
                 },
                 "SERIAL" => {
                     data_type = Some(FieldDataType::SERIAL);
-                    serial = Some(SerialState { next_val: 1 });
+                    serial = Some(SerialState::default());
                 },
                 "KEY" => {
                     if schema_result.iter().any(|f| f.primary_key) {
@@ -132,11 +288,11 @@ fn set_text_index(key: &Value, row: &Value, column: &String, btree: &mut BTreeMa
         Value::String(data) => {
             btree.entry(data.clone()).or_default().push(key.clone());
         }
+        Value::Null => {}
         _ => {
             println!("Error: schema and column types dont match");
-            return;
         }
-    };
+    }
 }
 
 fn set_number_index(key: &Value, row: &Value, column: &String, index_type: &FieldDataType, btree: &mut BTreeMap<IndexNumber, Vec<Value>>) {
@@ -158,15 +314,14 @@ fn set_number_index(key: &Value, row: &Value, column: &String, index_type: &Fiel
                 },
                 _ => {
                     println!("Error: Schema and data types don't match");
-                    return;
                 }
             }
         }
+        Value::Null => {}
         other => {
             println!("Error: schema and column types dont match. Got: {:?}", other);
-            return;
         }
-    };
+    }
 }
 
 fn set_bool_index(key: &Value, row: &Value, column: &String, btree: &mut BTreeMap<bool, Vec<Value>>) {
@@ -174,9 +329,34 @@ fn set_bool_index(key: &Value, row: &Value, column: &String, btree: &mut BTreeMa
         Value::Bool(data) => {
             btree.entry(*data).or_default().push(key.clone());
         }
+        Value::Null => {}
         _ => {
             println!("Error: schema and column types dont match");
-            return;
         }
-    };
+    }
+}
+
+fn unset_index(key: &Value, row: &Value, column: &String, index_data: &mut IndexStore) {
+    match (index_data, &row[column]) {
+        (IndexStore::Text(btree), Value::String(data)) => remove_index_key(btree, data, key),
+        (IndexStore::Number(btree), Value::Number(data)) => {
+            let index_number = match data.as_i64() {
+                Some(int) => IndexNumber::Int(int),
+                None => IndexNumber::Float(OrderedFloat(data.as_f64().unwrap_or(0.0))),
+            };
+            remove_index_key(btree, &index_number, key);
+        },
+        (IndexStore::Boolean(btree), Value::Bool(data)) => remove_index_key(btree, data, key),
+        _ => {},
+    }
+}
+
+// Values left without any rows are dropped from the index
+fn remove_index_key<K: Ord>(btree: &mut BTreeMap<K, Vec<Value>>, value: &K, key: &Value) {
+    if let Some(keys) = btree.get_mut(value) {
+        keys.retain(|k| k != key);
+        if keys.is_empty() {
+            btree.remove(value);
+        }
+    }
 }
@@ -1,18 +1,90 @@
-use std::fs;
-use directories::UserDirs;
+use crate::{catalog::{load_catalog, split_table_name, DEFAULT_DATABASE}, ddl::constraint::referencing_constraints, storage::StorageBackend};
 
-pub fn drop(name: String) {
-    if let Some(dirs) = UserDirs::new() {
-        let mut file_name = String::from(&name);
-        file_name.push_str(".db");
-        let path = dirs.home_dir().join("Documents/ezpzdb/").join(file_name);
+pub fn drop(storage: &mut dyn StorageBackend, name: String) {
+    let catalog = match load_catalog(storage) {
+        Ok(c) => c,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            return;
+        }
+    };
+    if catalog.view(&name).is_some() {
+        println!("{} is a view; use DROP VIEW", name);
+        return;
+    }
+    if let Some(view) = catalog.dependent_views(&name).first() {
+        println!("Table {} is read by view {}; drop the view first", name, view);
+        return;
+    }
+    match referencing_constraints(storage, &name) {
+        Ok(referencing) => {
+            if let Some((table, constraint)) = referencing.iter().find(|(t, _)| *t != name) {
+                println!("Table {} is referenced by constraint {} on {}", name, constraint.name, table);
+                return;
+            }
+        },
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            return;
+        }
+    }
+
+    let success = storage.drop_table(&name);
+    match success {
+        Ok(_) => { println!("Table {} removed successfully", name) },
+        Err(_) => { panic!("Error: Could not remove table") }
+    }
+
+    let updated = load_catalog(storage).and_then(|mut catalog| {
+        catalog.unregister_table(&name);
+        storage.save_catalog(&catalog)
+    });
+    if let Err(e) = updated {
+        eprintln!("Error: {}", e);
+    }
+}
 
-        let success = fs::remove_file(path);
-        match success {
-            Ok(_) => { println!("Table {} removed successfully", name) },
-            Err(_) => { panic!("Error: Could not remove table") }
+pub fn drop_database(storage: &mut dyn StorageBackend, name: String) {
+    if name == DEFAULT_DATABASE {
+        println!("Cannot drop the {} database", DEFAULT_DATABASE);
+        return;
+    }
+    let mut catalog = match load_catalog(storage) {
+        Ok(c) => c,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            return;
+        }
+    };
+    let entry = match catalog.databases.remove(&name) {
+        Some(entry) => entry,
+        None => {
+            println!("Database {} not found", name);
+            return;
+        }
+    };
+
+    // Views and foreign keys within the database go with it, but nothing outside may read it
+    let outside = |other: &String| split_table_name(other).0 != name;
+    for object in entry.tables.keys().chain(entry.views.keys()) {
+        let qualified = format!("{}.{}", name, object);
+        if let Some(view) = catalog.dependent_views(&qualified).into_iter().find(outside) {
+            println!("{} is read by view {}; drop the view first", qualified, view);
+            return;
+        }
+        if let Some((table, constraint)) = catalog.referencing(&qualified).into_iter().find(|(t, _)| outside(t)) {
+            println!("Table {} is referenced by constraint {} on {}", qualified, constraint.name, table);
+            return;
         }
-    } else {
-        panic!("No home directory found");
+    }
+
+    for table in entry.tables.keys() {
+        if let Err(e) = storage.drop_table(&format!("{}.{}", name, table)) {
+            eprintln!("Error: {}", e);
+        }
+    }
+    match storage.save_catalog(&catalog) {
+        Ok(_) => { println!("Database {} removed successfully", name) },
+        Err(e) => { eprintln!("Error: {}", e) }
     }
 }
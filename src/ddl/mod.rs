@@ -3,3 +3,13 @@ pub mod create;
 pub mod drop;
 
 pub mod alter;
+
+pub mod convert;
+
+pub mod constraint;
+
+pub mod sequence;
+
+pub mod view;
+
+pub mod analyze;
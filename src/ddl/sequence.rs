@@ -0,0 +1,312 @@
+use std::collections::BTreeSet;
+
+use serde_json::Value;
+
+use crate::{catalog::{load_catalog, resolve_table_name, split_table_name}, dql::expr::{evaluate, tokenize, EvalContext, Parser}, models::{Catalog, FieldDef, SerialState, Sequence, Table}, storage::StorageBackend};
+
+impl Sequence {
+    // Counts up by one from `start`, as SERIAL columns do
+    pub fn serial(start: i64) -> Sequence {
+        Sequence { start, increment: 1, min: 1.min(start), max: i64::MAX, cycle: false, last_value: start, is_called: false, owner: None }
+    }
+
+    pub fn next_value(&mut self, name: &str) -> Result<i64, String> {
+        if !self.is_called {
+            self.is_called = true;
+            return Ok(self.last_value);
+        }
+        let next = self.last_value.checked_add(self.increment).filter(|n| *n >= self.min && *n <= self.max);
+        let next = match next {
+            Some(n) => n,
+            None if self.cycle => if self.increment > 0 { self.min } else { self.max },
+            None if self.increment > 0 => return Err(format!("Sequence {} reached its maximum value {}", name, self.max)),
+            None => return Err(format!("Sequence {} reached its minimum value {}", name, self.min)),
+        };
+        self.last_value = next;
+        Ok(next)
+    }
+
+    pub fn set_value(&mut self, name: &str, value: i64, is_called: bool) -> Result<(), String> {
+        if value < self.min || value > self.max {
+            return Err(format!("{} is out of range for sequence {} ({}..{})", value, name, self.min, self.max));
+        }
+        self.last_value = value;
+        self.is_called = is_called;
+        Ok(())
+    }
+
+    pub fn restart(&mut self) {
+        self.last_value = self.start;
+        self.is_called = false;
+    }
+
+    // The value nextval would hand out, ignoring the limits
+    fn upcoming(&self) -> i64 {
+        if self.is_called { self.last_value.saturating_add(self.increment) } else { self.last_value }
+    }
+}
+
+// Syntax: <name> [INCREMENT [BY] <n>] [MINVALUE <n> | NO MINVALUE] [MAXVALUE <n> | NO MAXVALUE]
+//         [START [WITH] <n>] [CYCLE | NO CYCLE]
+pub fn create_sequence(storage: &mut dyn StorageBackend, name: String, tokens: Vec<String>) {
+    let sequence = match parse_sequence(&tokens) {
+        Ok(s) => s,
+        Err(e) => {
+            println!("Error: {}", e);
+            return;
+        }
+    };
+    let mut catalog = match load_catalog(storage) {
+        Ok(c) => c,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            return;
+        }
+    };
+    if catalog.sequence(&name).is_some() {
+        println!("Sequence {} already exists", name);
+        return;
+    }
+    if !catalog.set_sequence(&name, sequence) {
+        println!("Database {} not found", split_table_name(&name).0);
+        return;
+    }
+    match storage.save_catalog(&catalog) {
+        Ok(_) => println!("Sequence {} created", name),
+        Err(e) => eprintln!("Error: {}", e),
+    }
+}
+
+fn parse_sequence(tokens: &[String]) -> Result<Sequence, String> {
+    let mut parser = Parser::new(tokenize(&tokens.join(" "))?);
+    let (mut increment, mut min, mut max, mut start, mut cycle) = (None, None, None, None, false);
+    while !parser.is_done() {
+        if parser.eat_keyword("INCREMENT") {
+            parser.eat_keyword("BY");
+            increment = Some(parse_integer(&mut parser)?);
+        } else if parser.eat_keyword("MINVALUE") {
+            min = Some(parse_integer(&mut parser)?);
+        } else if parser.eat_keyword("MAXVALUE") {
+            max = Some(parse_integer(&mut parser)?);
+        } else if parser.eat_keyword("START") {
+            parser.eat_keyword("WITH");
+            start = Some(parse_integer(&mut parser)?);
+        } else if parser.eat_keyword("CYCLE") {
+            cycle = true;
+        } else if parser.eat_keyword("NO") {
+            if parser.eat_keyword("MINVALUE") {
+                min = None;
+            } else if parser.eat_keyword("MAXVALUE") {
+                max = None;
+            } else {
+                parser.expect_keyword("CYCLE")?;
+                cycle = false;
+            }
+        } else {
+            return Err("Expected INCREMENT, MINVALUE, MAXVALUE, START or CYCLE".to_string());
+        }
+    }
+
+    // Ascending sequences default to 1.., descending ones to ..-1
+    let increment = increment.unwrap_or(1);
+    if increment == 0 {
+        return Err("INCREMENT cannot be 0".to_string());
+    }
+    let min = min.unwrap_or(if increment > 0 { 1 } else { i64::MIN });
+    let max = max.unwrap_or(if increment > 0 { i64::MAX } else { -1 });
+    if min >= max {
+        return Err(format!("MINVALUE {} must be less than MAXVALUE {}", min, max));
+    }
+    let start = start.unwrap_or(if increment > 0 { min } else { max });
+    if start < min || start > max {
+        return Err(format!("START {} must be between {} and {}", start, min, max));
+    }
+    Ok(Sequence { start, increment, min, max, cycle, last_value: start, is_called: false, owner: None })
+}
+
+fn parse_integer(parser: &mut Parser) -> Result<i64, String> {
+    let value = evaluate(&parser.parse_expr()?, &Value::Null)?;
+    value.as_i64().ok_or_else(|| format!("Expected a whole number, found {}", value))
+}
+
+pub fn drop_sequence(storage: &mut dyn StorageBackend, name: String) {
+    let mut catalog = match load_catalog(storage) {
+        Ok(c) => c,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            return;
+        }
+    };
+    match catalog.sequence(&name).map(|s| s.owner.clone()) {
+        None => {
+            println!("Sequence {} not found", name);
+            return;
+        },
+        Some(Some(owner)) => {
+            println!("Sequence {} is owned by column {} of {}", name, owner.column, owner.table);
+            return;
+        },
+        Some(None) => {},
+    }
+    catalog.remove_sequence(&name);
+    match storage.save_catalog(&catalog) {
+        Ok(_) => println!("Sequence {} removed successfully", name),
+        Err(e) => eprintln!("Error: {}", e),
+    }
+}
+
+// Gives every SERIAL column without a sequence one of its own, starting from the counter it had
+pub fn own_serial_sequences(catalog: &mut Catalog, table: &mut Table) -> Vec<String> {
+    let mut created = vec![];
+    for field in table.schema.iter_mut() {
+        let Some(serial) = field.serial.as_mut().filter(|s| s.sequence.is_none()) else { continue };
+        let start = serial.next_val.unwrap_or(1);
+        let name = catalog.add_owned_sequence(&table.name, &field.name, Sequence::serial(start));
+        *serial = SerialState { sequence: Some(name.clone()), next_val: None };
+        created.push(name);
+    }
+    created
+}
+
+// Sequence values handed out during one statement. Nothing is written until `save`,
+// so a statement that fails leaves every sequence as it was.
+pub struct Sequences {
+    catalog: Catalog,
+    database: String,
+    changed: BTreeSet<String>,
+}
+
+impl Sequences {
+    pub fn load(storage: &mut dyn StorageBackend, database: &str) -> Result<Sequences, String> {
+        Ok(Sequences { catalog: load_catalog(storage)?, database: database.to_string(), changed: BTreeSet::new() })
+    }
+
+    // `name` is as written, so it resolves against the current database
+    fn sequence_mut(&mut self, name: &str) -> Result<(String, &mut Sequence), String> {
+        let resolved = resolve_table_name(name, &self.database);
+        match self.catalog.sequence_mut(&resolved) {
+            Some(sequence) => Ok((resolved, sequence)),
+            None => Err(format!("Sequence {} not found", name)),
+        }
+    }
+
+    pub fn next_value(&mut self, name: &str) -> Result<i64, String> {
+        let (resolved, sequence) = self.sequence_mut(name)?;
+        let value = sequence.next_value(name)?;
+        self.changed.insert(resolved);
+        Ok(value)
+    }
+
+    // Next value for a SERIAL column. Columns from before sequences need `own_serial_sequences` first.
+    pub fn serial_value(&mut self, field: &FieldDef) -> Result<Value, String> {
+        let name = serial_sequence(field)?;
+        let sequence = self.catalog.sequence_mut(&name).ok_or_else(|| format!("Sequence {} not found", name))?;
+        let value = sequence.next_value(&name)?;
+        self.changed.insert(name);
+        Ok(Value::from(value))
+    }
+
+    // A SERIAL value set by hand moves the column's sequence past it
+    pub fn advance_past(&mut self, field: &FieldDef, value: i64) {
+        let Ok(name) = serial_sequence(field) else { return };
+        if let Some(sequence) = self.catalog.sequence_mut(&name) {
+            if sequence.increment > 0 && value >= sequence.upcoming() && value <= sequence.max {
+                sequence.last_value = value;
+                sequence.is_called = true;
+                self.changed.insert(name);
+            }
+        }
+    }
+
+    pub fn restart(&mut self, table: &Table) {
+        for field in table.schema.iter() {
+            let Ok(name) = serial_sequence(field) else { continue };
+            if let Some(sequence) = self.catalog.sequence_mut(&name) {
+                sequence.restart();
+                self.changed.insert(name);
+            }
+        }
+    }
+
+    pub fn drop_owned(&mut self, field: &FieldDef) {
+        let Ok(name) = serial_sequence(field) else { return };
+        if self.catalog.sequence(&name).is_some_and(|s| s.owner.is_some()) {
+            self.catalog.remove_sequence(&name);
+            self.changed.insert(name);
+        }
+    }
+
+    pub fn rename_owner_column(&mut self, table: &str, from: &str, to: &str) {
+        for (database, entry) in self.catalog.databases.iter_mut() {
+            for (name, sequence) in entry.sequences.iter_mut() {
+                if let Some(owner) = sequence.owner.as_mut().filter(|o| o.table == table && o.column == from) {
+                    owner.column = to.to_string();
+                    self.changed.insert(resolve_table_name(name, database));
+                }
+            }
+        }
+    }
+
+    // Changes the schema when a column is given a sequence, so the caller saves the table
+    pub fn own_serial_sequences(&mut self, table: &mut Table) {
+        let created = own_serial_sequences(&mut self.catalog, table);
+        self.changed.extend(created);
+    }
+
+    // Writes the sequences that changed into the catalog as it is now, leaving the rest alone
+    pub fn save(self, storage: &mut dyn StorageBackend) -> Result<(), String> {
+        if self.changed.is_empty() {
+            return Ok(());
+        }
+        let mut catalog = load_catalog(storage)?;
+        for name in &self.changed {
+            match self.catalog.sequence(name) {
+                Some(sequence) => { catalog.set_sequence(name, sequence.clone()); },
+                None => { catalog.remove_sequence(name); },
+            }
+        }
+        storage.save_catalog(&catalog)
+    }
+}
+
+// nextval(name), currval(name) and setval(name, value [, is_called])
+pub fn is_sequence_function(name: &str) -> bool {
+    matches!(name, "NEXTVAL" | "CURRVAL" | "SETVAL")
+}
+
+impl EvalContext for Sequences {
+    fn call(&mut self, name: &str, args: &[Value]) -> Option<Result<Value, String>> {
+        if !is_sequence_function(name) {
+            return None;
+        }
+        let Some(Value::String(sequence)) = args.first() else {
+            return Some(Err(format!("{} expects a sequence name", name)));
+        };
+        let result = match (name, &args[1..]) {
+            ("NEXTVAL", []) => self.next_value(sequence).map(Value::from),
+            // Sessions aren't tracked, so this is the last value anyone was given
+            ("CURRVAL", []) => self.sequence_mut(sequence).and_then(|(_, s)| {
+                if s.is_called { Ok(Value::from(s.last_value)) } else { Err(format!("nextval has not been called for {}", sequence)) }
+            }),
+            ("SETVAL", [value]) | ("SETVAL", [value, Value::Bool(_)]) => {
+                let is_called = args.get(2).and_then(|v| v.as_bool()).unwrap_or(true);
+                match value.as_i64() {
+                    Some(n) => self.sequence_mut(sequence).and_then(|(resolved, s)| {
+                        s.set_value(sequence, n, is_called)?;
+                        Ok(resolved)
+                    }).map(|resolved| {
+                        self.changed.insert(resolved);
+                        Value::from(n)
+                    }),
+                    None => Err(format!("SETVAL expects a whole number, got {}", value)),
+                }
+            },
+            _ => Err(format!("Wrong arguments for {}", name)),
+        };
+        Some(result)
+    }
+}
+
+fn serial_sequence(field: &FieldDef) -> Result<String, String> {
+    field.serial.as_ref().and_then(|s| s.sequence.clone()).ok_or_else(|| format!("Column {} has no sequence", field.name))
+}
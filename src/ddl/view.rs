@@ -0,0 +1,136 @@
+use crate::{catalog::{load_catalog, split_table_name}, ddl::create::rebuild_indexes, dql::{query::run_view, statement::parse_query}, models::View, storage::StorageBackend};
+
+// Syntax: CREATE [MATERIALIZED] VIEW <name> AS {SELECT | WITH} ...
+// `query` is what follows SELECT, or the whole query from WITH. It is run once so a view that
+// can't run is never stored, and kept as it reads back from the parsed tree.
+pub fn create_view(storage: &mut dyn StorageBackend, name: String, query: Vec<String>, materialized: bool) {
+    let mut catalog = match load_catalog(storage) {
+        Ok(c) => c,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            return;
+        }
+    };
+    let (database, _) = split_table_name(&name);
+    if !catalog.has_database(&database) {
+        println!("Database {} not found", database);
+        return;
+    }
+    if catalog.view(&name).is_some() {
+        println!("View {} already exists", name);
+        return;
+    }
+    if catalog.table(&name).is_some() {
+        println!("Table {} already exists", name);
+        return;
+    }
+
+    let view = match parse_query(&query) {
+        Ok(parsed) => View { query: parsed.to_string(), materialized },
+        Err(e) => {
+            println!("Error: {}", e);
+            return;
+        }
+    };
+    let table = match run_view(storage, &name, &view) {
+        Ok(t) => t,
+        Err(e) => {
+            println!("Error: {}", e);
+            return;
+        }
+    };
+    if materialized {
+        if let Err(e) = storage.save_table(&table) {
+            eprintln!("Error: {}", e);
+            return;
+        }
+        catalog.register_table(&table);
+    }
+    catalog.set_view(&name, view);
+    match storage.save_catalog(&catalog) {
+        Ok(_) => println!("View {} created", name),
+        Err(e) => eprintln!("Error: {}", e),
+    }
+}
+
+// Syntax: REFRESH MATERIALIZED VIEW <name>
+// Indexes on columns the query still gives are rebuilt; the rest go.
+pub fn refresh_view(storage: &mut dyn StorageBackend, name: String) {
+    let mut catalog = match load_catalog(storage) {
+        Ok(c) => c,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            return;
+        }
+    };
+    let view = match catalog.view(&name) {
+        Some(view) if view.materialized => view.clone(),
+        _ => {
+            println!("Materialized view {} not found", name);
+            return;
+        }
+    };
+    let fresh = match run_view(storage, &name, &view) {
+        Ok(t) => t,
+        Err(e) => {
+            println!("Error: {}", e);
+            return;
+        }
+    };
+    let mut table = match storage.load_schema(&name) {
+        Ok(t) => t,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            return;
+        }
+    };
+
+    let schema_changed = table.schema.len() != fresh.schema.len()
+        || table.schema.iter().zip(&fresh.schema).any(|(old, new)| old.name != new.name || old.data_type != new.data_type);
+    table.indexes.retain(|column, _| fresh.schema.iter().any(|f| &f.name == column));
+    table.schema = fresh.schema;
+    table.data = fresh.data;
+    rebuild_indexes(&mut table);
+    if let Err(e) = storage.save_table(&table) {
+        eprintln!("Error: {}", e);
+        return;
+    }
+    catalog.update_table(&table, schema_changed);
+    match storage.save_catalog(&catalog) {
+        Ok(_) => println!("Materialized view {} refreshed", name),
+        Err(e) => eprintln!("Error: {}", e),
+    }
+}
+
+// A materialized view's table goes with it
+pub fn drop_view(storage: &mut dyn StorageBackend, name: String) {
+    let mut catalog = match load_catalog(storage) {
+        Ok(c) => c,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            return;
+        }
+    };
+    if let Some(view) = catalog.dependent_views(&name).iter().find(|v| **v != name) {
+        println!("View {} is read by view {}; drop that view first", name, view);
+        return;
+    }
+    let view = match catalog.remove_view(&name) {
+        Some(view) => view,
+        None => {
+            println!("View {} not found", name);
+            return;
+        }
+    };
+    if view.materialized {
+        if let Err(e) = storage.drop_table(&name) {
+            eprintln!("Error: {}", e);
+            return;
+        }
+        catalog.unregister_table(&name);
+    }
+    match storage.save_catalog(&catalog) {
+        Ok(_) => println!("View {} removed successfully", name),
+        Err(e) => eprintln!("Error: {}", e),
+    }
+}
@@ -2,21 +2,64 @@ use std::collections::HashMap;
 
 use serde_json::Value;
 
-use crate::{dql::select::{build_query, evaluate_query}, models::Table, storage::save_to_disk};
+use crate::{catalog::split_table_name, ddl::{constraint::check_references, create::{rebuild_indexes, reindex_rows}, sequence::Sequences}, dml::returning::{dml_return, parse_returning, split_returning, DmlReturn}, dql::query::matching_rows, models::Table, storage::StorageBackend};
 
-pub fn delete(mut table: Table, delete_query_tokens: Vec<String>) {
-    let query = build_query(delete_query_tokens);
+// Syntax: [WHERE <expr>] [RETURNING * | <column>, ...]
+// Without WHERE every row is deleted. Returns the deleted rows.
+pub fn delete(storage: &mut dyn StorageBackend, mut table: Table, mut delete_query_tokens: Vec<String>) -> Result<DmlReturn, String> {
+    let returning = match split_returning(&mut delete_query_tokens) {
+        Some(tokens) => Some(parse_returning(&tokens, &table)?),
+        None => None,
+    };
 
-    let filtered_store: HashMap<Value, Value> = evaluate_query(&table, &query);
+    if delete_query_tokens.is_empty() {
+        let all_rows = std::mem::take(&mut table.data);
+        check_references(storage, &table)?;
+        rebuild_indexes(&mut table);
+        storage.truncate_table(&table)?;
+        return Ok(dml_return(&table, all_rows, returning.as_ref()));
+    }
+    if !delete_query_tokens.iter().any(|t| t.eq_ignore_ascii_case("WHERE")) {
+        return Err("Expected WHERE".to_string());
+    }
+    let filtered_store: HashMap<Value, Value> = matching_rows(storage, &table, delete_query_tokens)?;
 
-    if filtered_store.is_empty() {
-        println!("Entry not found");
-        return;
+    if !filtered_store.is_empty() {
+        let keys: Vec<Value> = filtered_store.keys().cloned().collect();
+        for key in &keys {
+            table.data.remove(key);
+        }
+        check_references(storage, &table)?;
+        reindex_rows(&mut table, &filtered_store, &keys);
+        storage.save_rows(&table, &keys)?;
     }
+    Ok(dml_return(&table, filtered_store, returning.as_ref()))
+}
+
+// Syntax: [RESTART IDENTITY | CONTINUE IDENTITY]
+// Only the schema is needed, since no row is looked at.
+pub fn truncate(storage: &mut dyn StorageBackend, mut table: Table, tokens: Vec<String>) -> Result<(), String> {
+    let options: Vec<String> = tokens.iter().map(|t| t.to_uppercase()).collect();
+    let restart_identity = match options.iter().map(|o| o.as_str()).collect::<Vec<&str>>().as_slice() {
+        [] | ["CONTINUE", "IDENTITY"] => false,
+        ["RESTART", "IDENTITY"] => true,
+        _ => return Err("Expected RESTART IDENTITY or CONTINUE IDENTITY".to_string()),
+    };
 
-    for item in &filtered_store {
-        println!("Removing item: {:?}", item);
-        table.data.remove(&item.0);
+    table.data.clear();
+    check_references(storage, &table)?;
+    rebuild_indexes(&mut table);
+    let mut sequences = Sequences::load(storage, &split_table_name(&table.name).0)?;
+    if restart_identity {
+        // Columns from before sequences are given one that starts over
+        for serial in table.schema.iter_mut().filter_map(|f| f.serial.as_mut()) {
+            if serial.sequence.is_none() {
+                serial.next_val = Some(1);
+            }
+        }
+        sequences.own_serial_sequences(&mut table);
+        sequences.restart(&table);
     }
-    save_to_disk(&table.name, &table);
+    storage.truncate_table(&table)?;
+    sequences.save(storage)
 }
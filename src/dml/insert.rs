@@ -1,84 +1,391 @@
-use std::collections::HashMap;
-use serde_json::Value;
+use std::collections::{HashMap, HashSet};
+use serde_json::{Map, Value};
 
-use crate::{models::{FieldDataType, FieldDef, Table}, storage::save_to_disk};
+use crate::{ddl::{constraint::{parse_column_list, parse_single_column, validate_rows}, convert::check_type, create::reindex_rows, sequence::Sequences}, dml::{returning::{dml_return, parse_returning, split_returning, DmlReturn}, update::{apply_assignments, parse_assignment_list, Assignment}}, dql::{expr::{evaluate_in, tokenize, Expr, Parser}, query::needs_query_engine, select::{build_query, select}}, models::{ConstraintKind, FieldDataType, Table}, storage::{btree::compare_keys, StorageBackend}};
 
-pub fn insert(mut table: Table, new_data_tokens: Vec<String>) {
-    let (new_key, new_row) = generate_row_data(&mut table.schema, new_data_tokens.into());
+// Syntax:
+//   [INTO] <table> [(<column>, ...)] VALUES (<expr>, ...) [, (<expr>, ...) ...]
+//   [INTO] <table> [(<column>, ...)] SELECT ...
+//   <table> <column> <value> [<column> <value> ...]
+// The first two forms can end with ON CONFLICT [(<key>)] DO NOTHING | DO UPDATE SET <column> = <expr>, ...
+// Any form can end with RETURNING * | RETURNING <column>, ...
+// Returns the rows inserted or updated. Either every row goes in or none do.
+// `table` comes without rows, which are read as they're needed.
+pub fn insert(storage: &mut dyn StorageBackend, database: &str, mut table: Table, mut new_data_tokens: Vec<String>) -> Result<DmlReturn, String> {
+    let returning = match split_returning(&mut new_data_tokens) {
+        Some(tokens) => Some(parse_returning(&tokens, &table)?),
+        None => None,
+    };
+    // Sequence values are only kept if the insert goes through
+    let mut sequences = Sequences::load(storage, database)?;
+    sequences.own_serial_sequences(&mut table);
+    let is_statement = new_data_tokens.first().is_some_and(|t| {
+        t.starts_with('(') || t.eq_ignore_ascii_case("VALUES") || t.eq_ignore_ascii_case("SELECT")
+    });
+    let (rows, on_conflict) = if is_statement {
+        let conflict_position = new_data_tokens.windows(2)
+            .position(|w| w[0].eq_ignore_ascii_case("ON") && w[1].eq_ignore_ascii_case("CONFLICT"));
+        let on_conflict = match conflict_position {
+            Some(p) => Some(parse_on_conflict(&new_data_tokens.split_off(p)[2..], &table)?),
+            None => None,
+        };
+        (parse_statement(storage, database, &table, &mut sequences, &new_data_tokens)?, on_conflict)
+    } else {
+        let new_row = generate_row_data(&table, &mut sequences, new_data_tokens)?;
+        (vec![Value::Object(new_row.into_iter().collect())], None)
+    };
+
+    // Only the rows the new ones could conflict with are read, unless a constraint has to see every row
+    match table.schema.iter().find(|f| f.primary_key) {
+        Some(key) if !checks_every_row(&table) => {
+            let keys: Vec<Value> = rows.iter().map(|row| row[&key.name].clone()).collect();
+            table.data = storage.get_rows(&table.name, &keys)?;
+        },
+        _ => table.data = storage.load_table(&table.name)?.data,
+    }
+    let keys = insert_rows(storage, &mut table, &mut sequences, rows, on_conflict.as_ref())?;
+    sequences.save(storage)?;
+    let affected = keys.into_iter().filter_map(|k| table.data.get(&k).cloned().map(|row| (k, row))).collect();
+    Ok(dml_return(&table, affected, returning.as_ref()))
+}
+
+// Uniqueness is checked across the whole table, and a foreign key to the table itself can point
+// at any of its rows
+fn checks_every_row(table: &Table) -> bool {
+    table.constraints.iter().any(|c| match &c.kind {
+        ConstraintKind::Unique { .. } => true,
+        ConstraintKind::ForeignKey { ref_table, .. } => *ref_table == table.name,
+        ConstraintKind::Check { .. } => false,
+    })
+}
+
+enum OnConflict {
+    DoNothing,
+    // Bare columns read the existing row, EXCLUDED.<column> the row that was going in
+    DoUpdate(Vec<Assignment>),
+}
+
+fn parse_on_conflict(tokens: &[String], table: &Table) -> Result<OnConflict, String> {
+    // Conflicts are found through the primary key, so that is the only target
+    let key = table.schema.iter().find(|f| f.primary_key).map(|f| f.name.clone())
+        .ok_or_else(|| format!("Table {} has no primary key to conflict on", table.name))?;
+    let mut parser = Parser::new(tokenize(&tokens.join(" "))?);
+    if parser.peek_symbol("(") {
+        let target = parse_single_column(&mut parser)?;
+        if target != key {
+            return Err(format!("ON CONFLICT target must be the primary key {}", key));
+        }
+    }
+    parser.expect_keyword("DO")?;
+
+    let on_conflict = if parser.eat_keyword("NOTHING") {
+        OnConflict::DoNothing
+    } else {
+        parser.expect_keyword("UPDATE")?;
+        parser.expect_keyword("SET")?;
+        let mut assignments = parse_assignment_list(&mut parser, table)?;
+        if assignments.iter().any(|a| a.column == key) {
+            return Err(format!("Cannot update the conflict key {}", key));
+        }
+        for assignment in assignments.iter_mut() {
+            assignment.value.visit_columns_mut(&mut |name| {
+                if let Some((qualifier, column)) = name.split_once('.') {
+                    if qualifier.eq_ignore_ascii_case("EXCLUDED") {
+                        *name = format!("EXCLUDED.{}", column);
+                    } else if Some(qualifier) == table.name.rsplit('.').next() {
+                        *name = column.to_string();
+                    }
+                }
+            });
+        }
+        OnConflict::DoUpdate(assignments)
+    };
+    parser.expect_end()?;
+    Ok(on_conflict)
+}
+
+// Adds complete rows, then checks constraints and saves the ones that changed. Returns the keys of every
+// row inserted or updated.
+fn insert_rows(storage: &mut dyn StorageBackend, table: &mut Table, sequences: &mut Sequences, rows: Vec<Value>, on_conflict: Option<&OnConflict>) -> Result<Vec<Value>, String> {
+    let key_column = table.schema.iter().find(|f| f.primary_key).map(|f| f.name.clone());
+    // Tables without a primary key number their rows
+    let mut next_row = table.data.keys().filter_map(|k| k.as_u64()).max().unwrap_or(0) + 1;
+
+    let mut keys = vec![];
+    let mut touched: HashSet<Value> = HashSet::new();
+    // Rows as they were before DO UPDATE changed them
+    let mut old: HashMap<Value, Value> = HashMap::new();
+    for row in rows {
+        let new_key = match &key_column {
+            Some(column) => row[column].clone(),
+            None => {
+                next_row += 1;
+                Value::from(next_row - 1)
+            }
+        };
+        if new_key == Value::Null {
+            return Err("Key is missing".to_string());
+        }
+        // Each row can only be touched once, so a key repeated within the statement is skipped by
+        // DO NOTHING and fails otherwise
+        let existing = table.data.get(&new_key).filter(|_| !touched.contains(&new_key));
+        match (existing, on_conflict) {
+            (None, _) if !table.data.contains_key(&new_key) => {
+                table.data.insert(new_key.clone(), row);
+            },
+            (_, Some(OnConflict::DoNothing)) => continue,
+            (Some(existing), Some(OnConflict::DoUpdate(assignments))) => {
+                let mut source = existing.clone();
+                if let (Value::Object(source), Value::Object(excluded)) = (&mut source, &row) {
+                    for (column, value) in excluded {
+                        source.insert(format!("EXCLUDED.{}", column), value.clone());
+                    }
+                }
+                let mut updated = existing.clone();
+                apply_assignments(assignments, &source, &mut updated, sequences).map_err(|e| format!("Row {}: {}", new_key, e))?;
+                old.insert(new_key.clone(), existing.clone());
+                table.data.insert(new_key.clone(), updated);
+            },
+            _ => return Err(format!("Key {} is already in use", new_key)),
+        }
+        touched.insert(new_key.clone());
+        keys.push(new_key);
+    }
+
+    validate_rows(storage, table, &keys)?;
+    reindex_rows(table, &old, &keys);
+    storage.save_rows(table, &keys)?;
+    Ok(keys)
+}
+
+fn parse_statement(storage: &mut dyn StorageBackend, database: &str, table: &Table, sequences: &mut Sequences, tokens: &[String]) -> Result<Vec<Value>, String> {
+    // The SELECT part goes to the query engine untouched
+    let select_position = tokens.iter().position(|t| t.eq_ignore_ascii_case("SELECT"));
+    let head = &tokens[..select_position.unwrap_or(tokens.len())];
+    let mut parser = Parser::new(tokenize(&head.join(" "))?);
+
+    let columns = if parser.peek_symbol("(") {
+        parse_column_list(&mut parser)?
+    } else {
+        table.schema.iter().map(|f| f.name.clone()).collect()
+    };
+    for (i, column) in columns.iter().enumerate() {
+        if !table.schema.iter().any(|f| &f.name == column) {
+            return Err(format!("Column {} not found", column));
+        }
+        if columns[..i].contains(column) {
+            return Err(format!("Column {} is listed more than once", column));
+        }
+    }
+
+    let value_lists = match select_position {
+        Some(p) => {
+            parser.expect_end()?;
+            select_values(storage, database, tokens[p + 1..].to_vec())?
+        },
+        None => {
+            parser.expect_keyword("VALUES")?;
+            let mut lists = vec![];
+            loop {
+                lists.push(parse_value_list(&mut parser, sequences)?);
+                if !parser.eat_symbol(",") {
+                    break;
+                }
+            }
+            parser.expect_end()?;
+            lists
+        },
+    };
+
+    value_lists.into_iter().map(|values| {
+        if values.len() != columns.len() {
+            return Err(format!("Expected {} values, got {}", columns.len(), values.len()));
+        }
+        build_row(table, sequences, columns.iter().cloned().zip(values).collect())
+    }).collect()
+}
 
-    if new_key == Value::Null {
-        eprintln!("Error: Key is missing");
-        return
+fn parse_value_list(parser: &mut Parser, sequences: &mut Sequences) -> Result<Vec<Value>, String> {
+    let empty_row = Value::Object(Map::new());
+    parser.expect_symbol("(")?;
+    let mut values = vec![];
+    loop {
+        let value = match parser.parse_expr()? {
+            // There is no row to read from, so a bare word is text, the same as in WHERE clauses
+            Expr::Column(name) => Value::String(name),
+            expr => evaluate_in(&expr, &empty_row, sequences)?,
+        };
+        values.push(value);
+        if !parser.eat_symbol(",") {
+            break;
+        }
     }
+    parser.expect_symbol(")")?;
+    Ok(values)
+}
 
-    if table.data.contains_key(&new_key) {
-        eprintln!("Error: Key is already in use");
-        return
+// Selected values in the order the columns were listed, or in table order for `*`
+fn select_values(storage: &mut dyn StorageBackend, database: &str, query: Vec<String>) -> Result<Vec<Vec<Value>>, String> {
+    // Query engine results already list their columns in select order
+    let listed: Option<Vec<String>> = if needs_query_engine(&query) {
+        None
+    } else {
+        Some(build_query(query.clone()).select)
+    };
+    let results = select(storage, database, query);
+    if let Some(field) = results.missing.first() {
+        return Err(format!("Field not found: {}", field));
     }
+    let columns: Vec<String> = match listed {
+        Some(listed) if listed != ["*"] => listed,
+        _ => results.schema.iter().map(|(_, f)| f.name.clone()).collect(),
+    };
+
+    let mut rows: Vec<(Value, Value)> = results.filtered.into_iter().collect();
+    rows.sort_by(|a, b| compare_keys(&a.0, &b.0));
+    Ok(rows.into_iter().map(|(_, row)| {
+        columns.iter().map(|c| row.get(c).cloned().unwrap_or(Value::Null)).collect()
+    }).collect())
+}
 
-    let json_row: serde_json::Map<String, Value> = new_row.into_iter().collect();
-    table.data.insert(new_key, Value::Object(json_row));
-    save_to_disk(&table.name, &table);
+// Columns left out are NULL, apart from SERIAL columns, which take their sequence's next value
+fn build_row(table: &Table, sequences: &mut Sequences, mut provided: HashMap<String, Value>) -> Result<Value, String> {
+    let mut row = Map::new();
+    for field in table.schema.iter() {
+        let value = match provided.remove(&field.name) {
+            Some(value) => {
+                check_type(&value, &field.data_type).map_err(|e| format!("Column {}: {}", field.name, e))?;
+                // An explicit serial value moves the sequence past it
+                if let (Some(_), Some(n)) = (&field.serial, value.as_i64()) {
+                    sequences.advance_past(field, n);
+                }
+                value
+            },
+            None if field.serial.is_some() => sequences.serial_value(field)?,
+            None => Value::Null,
+        };
+        row.insert(field.name.clone(), value);
+    }
+    Ok(Value::Object(row))
 }
 
-fn generate_row_data(schema: &mut Vec<FieldDef>, new_data_tokens: Vec<String>) -> (Value, HashMap<String, Value>) {
+fn generate_row_data(table: &Table, sequences: &mut Sequences, new_data_tokens: Vec<String>) -> Result<HashMap<String, Value>, String> {
     let mut row_data_result: HashMap<String, Value> = HashMap::new();
-    let mut row_key: Value = Value::Null;
     let mut field_name: String;
-    let mut field_value: Value = Value::Null;
-    for field in schema.iter_mut() {
+    let mut field_value: Value;
+    for field in table.schema.iter() {
         field_name = field.name.clone();
         let field_index = new_data_tokens.iter().position(|f| **f == field.name);
         let value_token = match field_index {
-            Some(v) => new_data_tokens[v + 1].clone(),
+            Some(v) => match new_data_tokens.get(v + 1) {
+                Some(token) => token.clone(),
+                None => return Err(format!("Missing value for column {}", field.name)),
+            },
             None => String::from("")
         };
         match field.data_type.as_ref().unwrap() {
             FieldDataType::NUMBER => {
-                let value = match value_token.parse::<i64>() {
+                field_value = match value_token.parse::<i64>() {
                     Ok(i) => Value::Number(i.into()),
                     Err(_) => match value_token.parse::<f64>() {
                         Ok(f) => Value::Number(serde_json::Number::from_f64(f).unwrap()),
                         Err(_) => Value::Null
                     }
                 };
-                if field.primary_key {
-                    row_key = value.clone();
-                }
-                field_value = value;
             },
             FieldDataType::BOOLEAN => {
-                let val = if value_token.to_lowercase() == "true" {
+                field_value = if value_token.to_lowercase() == "true" {
                     Value::Bool(true)
                 } else {
                     Value::Bool(false)
                 };
-                if field.primary_key {
-                    row_key = val.clone();
-                }
-                field_value = val;
             },
             FieldDataType::TEXT => {
-                if field.primary_key {
-                    row_key = Value::String(value_token.clone());
-                }
                 field_value = Value::String(value_token.clone());
             },
             FieldDataType::SERIAL => {
-                match field.serial.as_mut() {
-                    Some(next) => {
-                        let curr = next.next_val;
-                        next.next_val += 1;
-                        if field.primary_key {
-                            row_key = Value::from(curr);
-                        }
-                        field_value = Value::from(curr);
-                    },
-                    None => {eprintln!("Error: SerialState not found");}
-                }
+                field_value = sequences.serial_value(field)?;
             },
         }
         row_data_result.insert(field_name, field_value.clone());
     }
-    (row_key, row_data_result)
+    Ok(row_data_result)
 } 
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::{ddl::create::{create, CreateData}, models::{IndexNumber, IndexStore}, storage::memory::MemoryBackend};
+
+    fn tokens(text: &str) -> Vec<String> {
+        text.split_whitespace().map(String::from).collect()
+    }
+
+    // t(id, v) holding (1, 10)
+    fn one_row() -> MemoryBackend {
+        let mut storage = MemoryBackend::new();
+        create(&mut storage, CreateData::Table { name: "t".to_string(), schema: tokens("id NUMBER KEY v NUMBER") });
+        run_insert(&mut storage, "VALUES (1, 10)").unwrap();
+        storage
+    }
+
+    fn run_insert(storage: &mut MemoryBackend, values: &str) -> Result<usize, String> {
+        let table = storage.load_schema("t")?;
+        insert(storage, "main", table, tokens(values)).map(|r| r.count)
+    }
+
+    fn v(storage: &mut MemoryBackend, id: i64) -> Option<Value> {
+        storage.get_row("t", &json!(id)).unwrap().map(|row| row["v"].clone())
+    }
+
+    #[test]
+    fn a_used_key_fails_the_whole_insert() {
+        let mut storage = one_row();
+        assert_eq!(run_insert(&mut storage, "VALUES (5, 50), (1, 11)"), Err("Key 1 is already in use".to_string()));
+        assert_eq!(v(&mut storage, 5), None);
+        assert_eq!(v(&mut storage, 1), Some(json!(10)));
+    }
+
+    #[test]
+    fn do_nothing_skips_used_and_repeated_keys() {
+        let mut storage = one_row();
+        assert_eq!(run_insert(&mut storage, "VALUES (1, 99), (2, 20), (2, 30) ON CONFLICT DO NOTHING"), Ok(1));
+        assert_eq!(v(&mut storage, 1), Some(json!(10)));
+        assert_eq!(v(&mut storage, 2), Some(json!(20)));
+    }
+
+    #[test]
+    fn do_update_reads_the_row_and_excluded() {
+        let mut storage = one_row();
+        assert_eq!(run_insert(&mut storage, "VALUES (1, 5), (3, 30) ON CONFLICT (id) DO UPDATE SET v = v + EXCLUDED.v"), Ok(2));
+        assert_eq!(v(&mut storage, 1), Some(json!(15)));
+        assert_eq!(v(&mut storage, 3), Some(json!(30)));
+    }
+
+    #[test]
+    fn do_update_touches_each_row_once() {
+        let mut storage = one_row();
+        assert_eq!(run_insert(&mut storage, "VALUES (1, 1), (1, 2) ON CONFLICT DO UPDATE SET v = EXCLUDED.v"), Err("Key 1 is already in use".to_string()));
+        assert_eq!(v(&mut storage, 1), Some(json!(10)));
+    }
+
+    #[test]
+    fn conflicts_are_only_on_the_primary_key() {
+        let mut storage = one_row();
+        assert_eq!(run_insert(&mut storage, "VALUES (2, 10) ON CONFLICT (v) DO NOTHING"), Err("ON CONFLICT target must be the primary key id".to_string()));
+        assert_eq!(run_insert(&mut storage, "VALUES (1, 10) ON CONFLICT DO UPDATE SET id = 2"), Err("Cannot update the conflict key id".to_string()));
+    }
+
+    #[test]
+    fn do_update_moves_the_row_in_indexes() {
+        let mut storage = one_row();
+        create(&mut storage, CreateData::Index { table: "t".to_string(), column: "v".to_string() });
+        run_insert(&mut storage, "VALUES (1, 7), (2, 10) ON CONFLICT DO UPDATE SET v = EXCLUDED.v").unwrap();
+        let table = storage.load_table("t").unwrap();
+        let IndexStore::Number(index) = &table.indexes["v"].index_data else { panic!("v should have a number index") };
+        assert_eq!(index[&IndexNumber::Int(7)], vec![json!(1)]);
+        assert_eq!(index[&IndexNumber::Int(10)], vec![json!(2)]);
+    }
+}
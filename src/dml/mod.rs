@@ -3,3 +3,5 @@ pub mod insert;
 pub mod delete;
 
 pub mod update;
+
+pub mod returning;
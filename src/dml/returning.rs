@@ -0,0 +1,53 @@
+use std::collections::HashMap;
+
+use serde_json::Value;
+
+use crate::{dql::{expr::{tokenize, Parser}, select::SelectReturn}, models::Table};
+
+pub struct DmlReturn {
+    pub count: usize,
+    // Only set when the statement asked for RETURNING
+    pub returning: Option<SelectReturn>,
+}
+
+// Takes `RETURNING *` or `RETURNING <column>, ...` off the end of the tokens
+pub fn split_returning(tokens: &mut Vec<String>) -> Option<Vec<String>> {
+    let position = tokens.iter().rposition(|t| t.eq_ignore_ascii_case("RETURNING"))?;
+    let mut returning = tokens.split_off(position);
+    returning.remove(0);
+    Some(returning)
+}
+
+pub fn parse_returning(tokens: &[String], table: &Table) -> Result<Vec<String>, String> {
+    let mut parser = Parser::new(tokenize(&tokens.join(" "))?);
+    if parser.eat_symbol("*") {
+        parser.expect_end()?;
+        return Ok(vec!["*".to_string()]);
+    }
+    let mut columns = vec![];
+    loop {
+        let column = parser.parse_identifier()?;
+        if !table.schema.iter().any(|f| f.name == column) {
+            return Err(format!("Column {} not found", column));
+        }
+        columns.push(column);
+        if !parser.eat_symbol(",") {
+            break;
+        }
+    }
+    parser.expect_end()?;
+    Ok(columns)
+}
+
+// The affected rows shaped like a SELECT over the same table
+pub fn dml_return(table: &Table, rows: HashMap<Value, Value>, returning: Option<&Vec<String>>) -> DmlReturn {
+    let count = rows.len();
+    let returning = returning.map(|columns| {
+        let mut schema: Vec<_> = table.schema.iter()
+            .map(|f| (columns.contains(&f.name) || columns == &["*"], f.clone()))
+            .collect();
+        schema.sort_by_key(|(_, f)| !f.primary_key);
+        SelectReturn { filtered: rows, missing: vec![], schema }
+    });
+    DmlReturn { count, returning }
+}
@@ -1,91 +1,155 @@
+use std::collections::HashMap;
+
 use serde_json::Value;
 
-use crate::{dql::select::{build_query, evaluate_query}, models::{FieldDataType, Table}, storage::save_to_disk};
+use crate::{dml::returning::{dml_return, parse_returning, split_returning, DmlReturn}, ddl::{constraint::{cascade_key_changes, validate_cascaded_rows, validate_rows}, convert::check_type, create::reindex_rows, sequence::Sequences}, dql::{expr::{evaluate_in, tokenize, Expr, EvalContext, Parser}, query::matching_rows}, models::{FieldDataType, Table}, storage::{btree::compare_keys, StorageBackend}};
+
+pub struct Assignment {
+    pub column: String,
+    pub data_type: Option<FieldDataType>,
+    pub value: Expr,
+}
 
-pub fn update(mut table: Table, mut tokens: Vec<String>) {
+// Syntax: SET <column> = <expr> [, <column> = <expr> ...] WHERE <expr> [RETURNING * | <column>, ...]
+// Returns the updated rows. Nothing is saved unless every row updates cleanly.
+pub fn update(storage: &mut dyn StorageBackend, database: &str, mut table: Table, mut tokens: Vec<String>) -> Result<DmlReturn, String> {
+    let returning = match split_returning(&mut tokens) {
+        Some(returning_tokens) => Some(parse_returning(&returning_tokens, &table)?),
+        None => None,
+    };
     // Split tokens into "set" and "query" tokens
-    let query_position = tokens.iter().position(|t| t == &"where" || t == &"WHERE");
+    let query_position = tokens.iter().position(|t| t.eq_ignore_ascii_case("where"));
     let query_tokens: Vec<String> = match query_position {
         Some(p) => {
             tokens.split_off(p)
         }
         None => {
-            println!("Error: No query entered");
-            return;
+            return Err("No query entered".to_string());
         }
     };
 
-    if tokens.len() < 1 {
-        println!("Error: No changes entered");
-        return;
+    if tokens.is_empty() {
+        return Err("No changes entered".to_string());
     }
+    let assignments = parse_assignments(&tokens, &table)?;
 
-    let default_query_options = vec!["*".to_string(), "FROM".to_string(), table.name.clone()];
-    let mut final_query = Vec::with_capacity(query_tokens.len() + default_query_options.len());
-    final_query.extend(default_query_options.into_iter());
-    final_query.extend(query_tokens.into_iter());
+    // In key order, so sequence calls number the rows predictably
+    let mut update_rows: Vec<(Value, Value)> = matching_rows(storage, &table, query_tokens)?.into_iter().collect();
+    update_rows.sort_by(|a, b| compare_keys(&a.0, &b.0));
 
+    let mut sequences = Sequences::load(storage, database)?;
+    let mut updated: HashMap<Value, Value> = HashMap::new();
+    for (key, row) in update_rows.iter() {
+        // Every expression sees the row as it was before this update
+        let mut new_row = row.clone();
+        apply_assignments(&assignments, row, &mut new_row, &mut sequences).map_err(|e| format!("Row {}: {}", key, e))?;
+        updated.insert(key.clone(), new_row);
+    }
 
-    let query = build_query(final_query);
-
-    let mut update_rows = evaluate_query(&table, &query);
-
-    let field_position = table.schema.iter().position(|f| f.name == tokens[1]);
-    let field = match field_position {
+    let key_column = table.schema.iter()
+        .find(|f| f.primary_key && assignments.iter().any(|a| a.column == f.name))
+        .map(|f| f.name.clone());
+    let mut keys: Vec<Value> = vec![];
+    let mut moved: HashMap<Value, Value> = HashMap::new();
+    match key_column {
+        Some(key_column) => {
+            // Take every updated row out first, so rows can swap keys with each other
+            for key in updated.keys() {
+                table.data.remove(key);
+            }
+            for (old_key, row) in updated {
+                let new_key = row[&key_column].clone();
+                if new_key.is_null() {
+                    return Err(format!("Row {}: primary key cannot be NULL", old_key));
+                }
+                if table.data.contains_key(&new_key) {
+                    return Err(format!("Key {} is already in use", new_key));
+                }
+                if new_key != old_key {
+                    moved.insert(old_key, new_key.clone());
+                }
+                table.data.insert(new_key.clone(), row);
+                keys.push(new_key);
+            }
+        },
         None => {
-            println!("Column not found");
-            return;
+            keys = updated.keys().cloned().collect();
+            table.data.extend(updated);
         },
-        Some(f) => &table.schema[f]
-    };
-    let change = UpdateChange {
-        left_hand: tokens[1].clone(),
-        right_hand: build_right_hand_change(&field.data_type, &tokens[3]),
-    };
+    }
 
-    for (key, value) in update_rows.iter_mut() {
-        value[&change.left_hand] = change.right_hand.clone();
-        table.data.insert(key.clone(), value.clone());
+    // Rows as they were, under the keys they had. The old key of a row that moved gets deleted.
+    let mut old: HashMap<Value, Value> = update_rows.into_iter().collect();
+    let cascaded = if moved.is_empty() {
+        vec![]
+    } else {
+        cascade_key_changes(storage, &mut table, &moved, &mut old)?
+    };
+    let mut changed: Vec<Value> = old.keys().cloned().collect();
+    changed.extend(keys.iter().filter(|k| !old.contains_key(*k)).cloned());
+    reindex_rows(&mut table, &old, &changed);
+    validate_rows(storage, &table, &keys)?;
+    let mut cascaded_changes = vec![];
+    for (mut other, other_old) in cascaded {
+        let other_changed: Vec<Value> = other_old.keys().cloned().collect();
+        reindex_rows(&mut other, &other_old, &other_changed);
+        validate_cascaded_rows(storage, &other, &other_changed, &table)?;
+        cascaded_changes.push((other, other_changed));
     }
 
-    save_to_disk(&table.name, &table);
-    // run over set tokens, and apply changes
+    storage.save_rows(&table, &changed)?;
+    for (other, other_changed) in cascaded_changes {
+        storage.save_rows(&other, &other_changed)?;
+    }
+    sequences.save(storage)?;
+    let affected = keys.into_iter().filter_map(|k| table.data.get(&k).cloned().map(|row| (k, row))).collect();
+    Ok(dml_return(&table, affected, returning.as_ref()))
 }
 
-struct UpdateChange {
-    left_hand: String,
-    right_hand: Value,
+fn parse_assignments(tokens: &[String], table: &Table) -> Result<Vec<Assignment>, String> {
+    let mut parser = Parser::new(tokenize(&tokens.join(" "))?);
+    parser.expect_keyword("SET")?;
+    let assignments = parse_assignment_list(&mut parser, table)?;
+    parser.expect_end()?;
+    Ok(assignments)
 }
 
-fn build_right_hand_change(data_type: &Option<FieldDataType>, value: &String) -> Value {
-    match data_type {
-        Some(FieldDataType::TEXT) => {
-            Value::String(value.to_string())
-        },
-        Some(FieldDataType::NUMBER) => {
-            if value.parse::<i64>().is_ok() {
-                Value::Number(value.parse::<i64>().unwrap().into())
-            } else if value.parse::<f64>().is_ok() {
-                Value::Number(serde_json::Number::from_f64(value.parse::<f64>().unwrap()).unwrap())
-            } else {
-                println!("Error: Could not parse value as number");
-                return Value::Null
-            }
-        },
-        Some(FieldDataType::BOOLEAN) => {
-            if value.parse::<bool>().is_ok() {
-                return Value::Bool(value.parse::<bool>().unwrap())
+// <column> = <expr> [, <column> = <expr> ...], stopping at the first token that can't continue the list
+pub fn parse_assignment_list(parser: &mut Parser, table: &Table) -> Result<Vec<Assignment>, String> {
+    let mut assignments: Vec<Assignment> = vec![];
+    loop {
+        let column = parser.parse_identifier()?;
+        let field = table.schema.iter().find(|f| f.name == column).ok_or_else(|| format!("Column {} not found", column))?;
+        if field.data_type == Some(FieldDataType::SERIAL) {
+            return Err("Cannot manually change serial columns".to_string());
+        }
+        if assignments.iter().any(|a| a.column == column) {
+            return Err(format!("Column {} is set more than once", column));
+        }
+        parser.expect_symbol("=")?;
+
+        let mut value = parser.parse_expr()?;
+        // A bare word that isn't a column is text, the same as in WHERE clauses
+        if let Expr::Column(name) = &value {
+            if !name.contains('.') && !table.schema.iter().any(|f| &f.name == name) {
+                value = Expr::Literal(Value::String(name.clone()));
             }
-            println!("Error: Could not parse value as boolean");
-            Value::Null
-        },
-        Some(FieldDataType::SERIAL) => {
-            println!("Error: Cannot manually change serial columns");
-            Value::Null
         }
-        None => {
-            println!("Error: Schema column data type not found");
-            Value::Null
+        assignments.push(Assignment { column, data_type: field.data_type.clone(), value });
+
+        if !parser.eat_symbol(",") {
+            break;
         }
     }
+    Ok(assignments)
+}
+
+// Evaluates every assignment against `source` and writes the results into `target`
+pub fn apply_assignments(assignments: &[Assignment], source: &Value, target: &mut Value, context: &mut dyn EvalContext) -> Result<(), String> {
+    for assignment in assignments {
+        let value = evaluate_in(&assignment.value, source, context)?;
+        check_type(&value, &assignment.data_type).map_err(|e| format!("Column {}: {}", assignment.column, e))?;
+        target[&assignment.column] = value;
+    }
+    Ok(())
 }
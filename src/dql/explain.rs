@@ -0,0 +1,96 @@
+use std::time::{Duration, Instant};
+
+use crate::{dql::{query::{explain_query, needs_query_engine}, select::explain_select}, storage::StorageBackend};
+
+// One step of a plan as EXPLAIN prints it, with the steps it reads rows from as children
+pub struct ExplainNode {
+    pub label: String,
+    pub details: Vec<String>,
+    pub estimated_rows: Option<usize>,
+    // Only filled in by EXPLAIN ANALYZE
+    pub actual: Option<OperatorStats>,
+    pub children: Vec<ExplainNode>,
+}
+
+// What a step produced over every time it ran. Times are the step's own, not counting the steps
+// below it.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct OperatorStats {
+    pub rows: usize,
+    // More than one for a correlated subquery, which runs for each row around it
+    pub loops: usize,
+    pub time: Duration,
+}
+
+impl OperatorStats {
+    pub fn once(rows: usize, started: Instant) -> OperatorStats {
+        OperatorStats { rows, loops: 1, time: started.elapsed() }
+    }
+
+    pub fn record(&mut self, rows: usize, started: Instant) {
+        self.add(&OperatorStats::once(rows, started));
+    }
+
+    pub fn add(&mut self, other: &OperatorStats) {
+        self.rows += other.rows;
+        self.loops += other.loops;
+        self.time += other.time;
+    }
+}
+
+impl ExplainNode {
+    pub fn new(label: impl Into<String>) -> ExplainNode {
+        ExplainNode { label: label.into(), details: vec![], estimated_rows: None, actual: None, children: vec![] }
+    }
+
+    fn render(&self, depth: usize, lines: &mut Vec<String>) {
+        let indent = "   ".repeat(depth);
+        let arrow = if depth == 0 { "" } else { "-> " };
+        let mut line = format!("{}{}{}", indent, arrow, self.label);
+        if let Some(rows) = self.estimated_rows {
+            line.push_str(&format!(" (estimated rows={})", rows));
+        }
+        if let Some(actual) = &self.actual {
+            line.push_str(&format!(" (actual rows={} loops={} time={})", actual.rows, actual.loops, format_duration(actual.time)));
+        }
+        lines.push(line);
+        let detail_indent = if depth == 0 { "   ".to_string() } else { format!("{}      ", indent) };
+        for detail in &self.details {
+            lines.push(format!("{}{}", detail_indent, detail));
+        }
+        for child in &self.children {
+            child.render(depth + 1, lines);
+        }
+    }
+}
+
+fn format_duration(duration: Duration) -> String {
+    format!("{:.3}ms", duration.as_secs_f64() * 1000.0)
+}
+
+// Syntax: EXPLAIN [ANALYZE] {SELECT | WITH} ...
+// ANALYZE runs the query and adds what each step actually produced and how long it took.
+pub fn explain(storage: &mut dyn StorageBackend, database: &str, mut tokens: Vec<String>) -> Result<Vec<String>, String> {
+    let analyze = tokens.first().is_some_and(|t| t.eq_ignore_ascii_case("ANALYZE"));
+    if analyze {
+        tokens.remove(0);
+    }
+    let query = match tokens.first().map(|t| t.to_uppercase()).as_deref() {
+        Some("SELECT") => tokens[1..].to_vec(),
+        Some("WITH") => tokens,
+        _ => return Err("Expected SELECT or WITH after EXPLAIN".to_string()),
+    };
+
+    let started = Instant::now();
+    let plan = if needs_query_engine(&query) {
+        explain_query(storage, database, query, analyze)?
+    } else {
+        explain_select(storage, database, query, analyze)?
+    };
+    let mut lines = vec![];
+    plan.render(0, &mut lines);
+    if analyze {
+        lines.push(format!("Execution time: {}", format_duration(started.elapsed())));
+    }
+    Ok(lines)
+}
@@ -0,0 +1,979 @@
+use std::{cmp::Ordering, fmt, rc::Rc};
+
+use serde_json::{Number, Value};
+
+use crate::{ddl::convert::convert_value, dql::{functions::call_function, statement::SelectStatement, window::WindowSpec}, models::FieldDataType};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Token {
+    Number(String),
+    Text(String),
+    Ident(String),
+    // Double-quoted identifiers never match keywords
+    QuotedIdent(String),
+    Symbol(String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expr {
+    Literal(Value),
+    Column(String),
+    Unary { op: UnaryOp, expr: Box<Expr> },
+    Binary { left: Box<Expr>, op: BinaryOp, right: Box<Expr> },
+    IsNull { expr: Box<Expr>, negated: bool },
+    Function { name: String, args: Vec<Expr> },
+    InList { expr: Box<Expr>, list: Vec<Expr>, negated: bool },
+    InSubquery { expr: Box<Expr>, query: Box<SelectStatement>, negated: bool },
+    Exists(Box<SelectStatement>),
+    // A subquery used as a value, which must give at most one row of one column
+    Subquery(Box<SelectStatement>),
+    Cast { expr: Box<Expr>, data_type: FieldDataType },
+    // With an operand, each WHEN is a value to compare it with; without, a condition
+    Case { operand: Option<Box<Expr>>, branches: Vec<(Expr, Expr)>, else_result: Option<Box<Expr>> },
+    // <name>(<args>) OVER (...), only in a select list
+    Window { name: String, args: Vec<Expr>, spec: WindowSpec },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum UnaryOp {
+    Neg,
+    Not,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BinaryOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Mod,
+    Concat,
+    Eq,
+    NotEq,
+    Lt,
+    Gt,
+    LtEq,
+    GtEq,
+    And,
+    Or,
+}
+
+pub fn tokenize(input: &str) -> Result<Vec<Token>, String> {
+    let chars: Vec<char> = input.chars().collect();
+    let mut tokens = vec![];
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        if c.is_whitespace() {
+            i += 1;
+        } else if c == '\'' || c == '"' {
+            // A doubled quote inside the literal stands for the quote itself
+            let mut text = String::new();
+            i += 1;
+            loop {
+                match chars.get(i) {
+                    None => return Err("Unterminated quoted text".to_string()),
+                    Some(q) if *q == c && chars.get(i + 1) == Some(&c) => {
+                        text.push(c);
+                        i += 2;
+                    },
+                    Some(q) if *q == c => {
+                        i += 1;
+                        break;
+                    },
+                    Some(other) => {
+                        text.push(*other);
+                        i += 1;
+                    },
+                }
+            }
+            tokens.push(if c == '\'' { Token::Text(text) } else { Token::QuotedIdent(text) });
+        } else if c.is_ascii_digit() || (c == '.' && chars.get(i + 1).is_some_and(|n| n.is_ascii_digit())) {
+            let start = i;
+            while i < chars.len() && (chars[i].is_ascii_digit() || chars[i] == '.') {
+                i += 1;
+            }
+            // An exponent, which is also how very small and very large numbers print
+            if matches!(chars.get(i), Some('e' | 'E')) {
+                let digits = if matches!(chars.get(i + 1), Some('+' | '-')) { i + 2 } else { i + 1 };
+                if chars.get(digits).is_some_and(|d| d.is_ascii_digit()) {
+                    i = digits;
+                    while i < chars.len() && chars[i].is_ascii_digit() {
+                        i += 1;
+                    }
+                }
+            }
+            tokens.push(Token::Number(chars[start..i].iter().collect()));
+        } else if c.is_alphanumeric() || c == '_' {
+            let start = i;
+            while i < chars.len() && (chars[i].is_alphanumeric() || chars[i] == '_') {
+                i += 1;
+            }
+            tokens.push(Token::Ident(chars[start..i].iter().collect()));
+        } else {
+            let pair: String = chars[i..(i + 2).min(chars.len())].iter().collect();
+            if ["<=", ">=", "!=", "<>", "||"].contains(&pair.as_str()) {
+                tokens.push(Token::Symbol(pair));
+                i += 2;
+            } else if "+-*/%=<>(),.;".contains(c) {
+                tokens.push(Token::Symbol(c.to_string()));
+                i += 1;
+            } else {
+                return Err(format!("Unexpected character: {}", c));
+            }
+        }
+    }
+    Ok(tokens)
+}
+
+// Parses a whole expression, rejecting anything left over after it
+pub fn parse_expression(input: &str) -> Result<Expr, String> {
+    let mut parser = Parser::new(tokenize(input)?);
+    let expr = parser.parse_expr()?;
+    parser.expect_end()?;
+    Ok(expr)
+}
+
+pub struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    pub fn new(tokens: Vec<Token>) -> Parser {
+        Parser { tokens, pos: 0 }
+    }
+
+    pub fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    // Looks past the next token; `peek_at(0)` is the same as `peek()`
+    pub fn peek_at(&self, offset: usize) -> Option<&Token> {
+        self.tokens.get(self.pos + offset)
+    }
+
+    pub fn next_token(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.pos).cloned();
+        if token.is_some() {
+            self.pos += 1;
+        }
+        token
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.pos >= self.tokens.len()
+    }
+
+    pub fn expect_end(&self) -> Result<(), String> {
+        match self.peek() {
+            None => Ok(()),
+            Some(token) => Err(format!("Unexpected {}", describe_token(token))),
+        }
+    }
+
+    pub fn peek_keyword(&self, keyword: &str) -> bool {
+        matches!(self.peek(), Some(Token::Ident(word)) if word.eq_ignore_ascii_case(keyword))
+    }
+
+    pub fn eat_keyword(&mut self, keyword: &str) -> bool {
+        if self.peek_keyword(keyword) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    pub fn expect_keyword(&mut self, keyword: &str) -> Result<(), String> {
+        if self.eat_keyword(keyword) {
+            Ok(())
+        } else {
+            Err(format!("Expected {}", keyword))
+        }
+    }
+
+    pub fn peek_symbol(&self, symbol: &str) -> bool {
+        matches!(self.peek(), Some(Token::Symbol(s)) if s == symbol)
+    }
+
+    pub fn eat_symbol(&mut self, symbol: &str) -> bool {
+        if self.peek_symbol(symbol) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    pub fn expect_symbol(&mut self, symbol: &str) -> Result<(), String> {
+        if self.eat_symbol(symbol) {
+            Ok(())
+        } else {
+            Err(format!("Expected '{}'", symbol))
+        }
+    }
+
+    pub fn parse_identifier(&mut self) -> Result<String, String> {
+        match self.next_token() {
+            Some(Token::Ident(name)) | Some(Token::QuotedIdent(name)) => Ok(name),
+            Some(token) => Err(format!("Expected a name, found {}", describe_token(&token))),
+            None => Err("Expected a name".to_string()),
+        }
+    }
+
+    pub fn parse_expr(&mut self) -> Result<Expr, String> {
+        self.parse_or()
+    }
+
+    fn parse_or(&mut self) -> Result<Expr, String> {
+        let mut left = self.parse_and()?;
+        while self.eat_keyword("OR") {
+            let right = self.parse_and()?;
+            left = Expr::Binary { left: Box::new(left), op: BinaryOp::Or, right: Box::new(right) };
+        }
+        Ok(left)
+    }
+
+    fn parse_and(&mut self) -> Result<Expr, String> {
+        let mut left = self.parse_not()?;
+        while self.eat_keyword("AND") {
+            let right = self.parse_not()?;
+            left = Expr::Binary { left: Box::new(left), op: BinaryOp::And, right: Box::new(right) };
+        }
+        Ok(left)
+    }
+
+    fn parse_not(&mut self) -> Result<Expr, String> {
+        if self.eat_keyword("NOT") {
+            let expr = self.parse_not()?;
+            return Ok(Expr::Unary { op: UnaryOp::Not, expr: Box::new(expr) });
+        }
+        self.parse_comparison()
+    }
+
+    fn parse_comparison(&mut self) -> Result<Expr, String> {
+        let left = self.parse_additive()?;
+
+        if self.eat_keyword("IS") {
+            let negated = self.eat_keyword("NOT");
+            self.expect_keyword("NULL")?;
+            return Ok(Expr::IsNull { expr: Box::new(left), negated });
+        }
+
+        let negated = self.peek_keyword("NOT") && matches!(self.peek_at(1), Some(Token::Ident(word)) if word.eq_ignore_ascii_case("IN"));
+        if negated {
+            self.pos += 1;
+        }
+        if self.eat_keyword("IN") {
+            self.expect_symbol("(")?;
+            if self.eat_keyword("SELECT") {
+                let query = self.parse_select()?;
+                self.expect_symbol(")")?;
+                return Ok(Expr::InSubquery { expr: Box::new(left), query: Box::new(query), negated });
+            }
+            let mut list = vec![];
+            loop {
+                list.push(self.parse_expr()?);
+                if self.eat_symbol(")") {
+                    break;
+                }
+                self.expect_symbol(",")?;
+            }
+            return Ok(Expr::InList { expr: Box::new(left), list, negated });
+        }
+
+        let op = match self.peek() {
+            Some(Token::Symbol(s)) => match s.as_str() {
+                "=" => BinaryOp::Eq,
+                "!=" | "<>" => BinaryOp::NotEq,
+                "<" => BinaryOp::Lt,
+                ">" => BinaryOp::Gt,
+                "<=" => BinaryOp::LtEq,
+                ">=" => BinaryOp::GtEq,
+                _ => return Ok(left),
+            },
+            _ => return Ok(left),
+        };
+        self.pos += 1;
+        let right = self.parse_additive()?;
+        Ok(Expr::Binary { left: Box::new(left), op, right: Box::new(right) })
+    }
+
+    fn parse_additive(&mut self) -> Result<Expr, String> {
+        let mut left = self.parse_multiplicative()?;
+        loop {
+            let op = if self.eat_symbol("+") {
+                BinaryOp::Add
+            } else if self.eat_symbol("-") {
+                BinaryOp::Sub
+            } else if self.eat_symbol("||") {
+                BinaryOp::Concat
+            } else {
+                return Ok(left);
+            };
+            let right = self.parse_multiplicative()?;
+            left = Expr::Binary { left: Box::new(left), op, right: Box::new(right) };
+        }
+    }
+
+    fn parse_multiplicative(&mut self) -> Result<Expr, String> {
+        let mut left = self.parse_unary()?;
+        loop {
+            let op = if self.eat_symbol("*") {
+                BinaryOp::Mul
+            } else if self.eat_symbol("/") {
+                BinaryOp::Div
+            } else if self.eat_symbol("%") {
+                BinaryOp::Mod
+            } else {
+                return Ok(left);
+            };
+            let right = self.parse_unary()?;
+            left = Expr::Binary { left: Box::new(left), op, right: Box::new(right) };
+        }
+    }
+
+    fn parse_unary(&mut self) -> Result<Expr, String> {
+        if self.eat_symbol("-") {
+            let expr = self.parse_unary()?;
+            return Ok(Expr::Unary { op: UnaryOp::Neg, expr: Box::new(expr) });
+        }
+        if self.eat_symbol("+") {
+            return self.parse_unary();
+        }
+        self.parse_primary()
+    }
+
+    fn parse_primary(&mut self) -> Result<Expr, String> {
+        match self.next_token() {
+            Some(Token::Number(n)) => parse_number(&n).map(Expr::Literal),
+            Some(Token::Text(t)) => Ok(Expr::Literal(Value::String(t))),
+            Some(Token::Symbol(s)) if s == "(" => {
+                if self.eat_keyword("SELECT") {
+                    let query = self.parse_select()?;
+                    self.expect_symbol(")")?;
+                    return Ok(Expr::Subquery(Box::new(query)));
+                }
+                let expr = self.parse_expr()?;
+                self.expect_symbol(")")?;
+                Ok(expr)
+            },
+            Some(Token::QuotedIdent(name)) => self.parse_column(name),
+            Some(Token::Ident(word)) => {
+                match word.to_uppercase().as_str() {
+                    "TRUE" => return Ok(Expr::Literal(Value::Bool(true))),
+                    "FALSE" => return Ok(Expr::Literal(Value::Bool(false))),
+                    "NULL" => return Ok(Expr::Literal(Value::Null)),
+                    "EXISTS" if self.peek_symbol("(") => {
+                        self.expect_symbol("(")?;
+                        self.expect_keyword("SELECT")?;
+                        let query = self.parse_select()?;
+                        self.expect_symbol(")")?;
+                        return Ok(Expr::Exists(Box::new(query)));
+                    },
+                    "CAST" if self.peek_symbol("(") => {
+                        self.expect_symbol("(")?;
+                        let expr = self.parse_expr()?;
+                        self.expect_keyword("AS")?;
+                        let type_name = self.parse_identifier()?;
+                        let data_type = parse_data_type(&type_name).ok_or_else(|| format!("{} is an invalid data type", type_name))?;
+                        self.expect_symbol(")")?;
+                        return Ok(Expr::Cast { expr: Box::new(expr), data_type });
+                    },
+                    "CASE" => return self.parse_case(),
+                    _ => {},
+                }
+                if self.eat_symbol("(") {
+                    let mut args = vec![];
+                    let count_rows = word.eq_ignore_ascii_case("COUNT") && self.peek_symbol("*")
+                        && matches!(self.peek_at(1), Some(Token::Symbol(s)) if s == ")");
+                    if count_rows {
+                        // COUNT(*) has no argument to evaluate
+                        self.pos += 2;
+                    } else if !self.eat_symbol(")") {
+                        loop {
+                            args.push(self.parse_expr()?);
+                            if self.eat_symbol(")") {
+                                break;
+                            }
+                            self.expect_symbol(",")?;
+                        }
+                    }
+                    if self.eat_keyword("OVER") {
+                        let spec = self.parse_window_spec()?;
+                        return Ok(Expr::Window { name: word.to_uppercase(), args, spec });
+                    }
+                    return Ok(Expr::Function { name: word.to_uppercase(), args });
+                }
+                self.parse_column(word)
+            },
+            Some(token) => Err(format!("Unexpected {}", describe_token(&token))),
+            None => Err("Unexpected end of expression".to_string()),
+        }
+    }
+
+    // CASE [<operand>] WHEN <expr> THEN <expr> ... [ELSE <expr>] END, after CASE
+    fn parse_case(&mut self) -> Result<Expr, String> {
+        let operand = if self.peek_keyword("WHEN") { None } else { Some(Box::new(self.parse_expr()?)) };
+        let mut branches = vec![];
+        while self.eat_keyword("WHEN") {
+            let condition = self.parse_expr()?;
+            self.expect_keyword("THEN")?;
+            branches.push((condition, self.parse_expr()?));
+        }
+        if branches.is_empty() {
+            return Err("Expected WHEN".to_string());
+        }
+        let else_result = if self.eat_keyword("ELSE") { Some(Box::new(self.parse_expr()?)) } else { None };
+        self.expect_keyword("END")?;
+        Ok(Expr::Case { operand, branches, else_result })
+    }
+
+    // Columns can be qualified, as in `orders.total`
+    fn parse_column(&mut self, name: String) -> Result<Expr, String> {
+        if self.eat_symbol(".") {
+            let column = self.parse_identifier()?;
+            return Ok(Expr::Column(format!("{}.{}", name, column)));
+        }
+        Ok(Expr::Column(name))
+    }
+}
+
+fn describe_token(token: &Token) -> String {
+    match token {
+        Token::Number(n) => n.clone(),
+        Token::Text(t) => format!("'{}'", t),
+        Token::Ident(i) => i.clone(),
+        Token::QuotedIdent(i) => format!("\"{}\"", i),
+        Token::Symbol(s) => format!("'{}'", s),
+    }
+}
+
+fn parse_data_type(name: &str) -> Option<FieldDataType> {
+    match name.to_uppercase().as_str() {
+        "TEXT" => Some(FieldDataType::TEXT),
+        "NUMBER" => Some(FieldDataType::NUMBER),
+        "BOOLEAN" => Some(FieldDataType::BOOLEAN),
+        "SERIAL" => Some(FieldDataType::SERIAL),
+        _ => None,
+    }
+}
+
+fn parse_number(text: &str) -> Result<Value, String> {
+    if let Ok(i) = text.parse::<i64>() {
+        return Ok(Value::Number(i.into()));
+    }
+    text.parse::<f64>()
+        .ok()
+        .and_then(Number::from_f64)
+        .map(Value::Number)
+        .ok_or_else(|| format!("Invalid number: {}", text))
+}
+
+// Answers what an expression can't work out from the row alone: functions that need more than
+// their arguments, such as sequence calls, and subqueries
+pub trait EvalContext {
+    // None when the context doesn't provide the function
+    fn call(&mut self, name: &str, args: &[Value]) -> Option<Result<Value, String>>;
+
+    // Rows of a subquery run for `row`, which its correlated columns are read from
+    fn subquery(&mut self, _query: &SelectStatement, _row: &Value) -> Result<Rc<Vec<Vec<Value>>>, String> {
+        Err("Subqueries are not allowed here".to_string())
+    }
+}
+
+struct NoContext;
+
+impl EvalContext for NoContext {
+    fn call(&mut self, _name: &str, _args: &[Value]) -> Option<Result<Value, String>> {
+        None
+    }
+}
+
+pub fn evaluate(expr: &Expr, row: &Value) -> Result<Value, String> {
+    evaluate_in(expr, row, &mut NoContext)
+}
+
+pub fn evaluate_in(expr: &Expr, row: &Value, context: &mut dyn EvalContext) -> Result<Value, String> {
+    match expr {
+        Expr::Literal(value) => Ok(value.clone()),
+        Expr::Column(name) => row.get(name).cloned().ok_or_else(|| format!("Column {} not found", name)),
+        Expr::Unary { op, expr } => {
+            let value = evaluate_in(expr, row, context)?;
+            match (op, value) {
+                (_, Value::Null) => Ok(Value::Null),
+                (UnaryOp::Not, Value::Bool(b)) => Ok(Value::Bool(!b)),
+                (UnaryOp::Neg, Value::Number(n)) => match n.as_i64() {
+                    Some(i) => Ok(Value::from(-i)),
+                    None => float_value(-n.as_f64().unwrap_or(0.0)),
+                },
+                (UnaryOp::Not, other) => Err(format!("Cannot apply NOT to {}", other)),
+                (UnaryOp::Neg, other) => Err(format!("Cannot negate {}", other)),
+            }
+        },
+        Expr::IsNull { expr, negated } => {
+            let is_null = evaluate_in(expr, row, context)?.is_null();
+            Ok(Value::Bool(is_null != *negated))
+        },
+        Expr::Binary { left, op: BinaryOp::And, right } => {
+            let l = truth(&evaluate_in(left, row, context)?)?;
+            if l == Some(false) {
+                return Ok(Value::Bool(false));
+            }
+            let r = truth(&evaluate_in(right, row, context)?)?;
+            Ok(match (l, r) {
+                (_, Some(false)) => Value::Bool(false),
+                (Some(true), Some(true)) => Value::Bool(true),
+                _ => Value::Null,
+            })
+        },
+        Expr::Binary { left, op: BinaryOp::Or, right } => {
+            let l = truth(&evaluate_in(left, row, context)?)?;
+            if l == Some(true) {
+                return Ok(Value::Bool(true));
+            }
+            let r = truth(&evaluate_in(right, row, context)?)?;
+            Ok(match (l, r) {
+                (_, Some(true)) => Value::Bool(true),
+                (Some(false), Some(false)) => Value::Bool(false),
+                _ => Value::Null,
+            })
+        },
+        Expr::Binary { left, op, right } => {
+            let l = evaluate_in(left, row, context)?;
+            let r = evaluate_in(right, row, context)?;
+            binary(*op, &l, &r)
+        },
+        Expr::Function { name, args } => {
+            let values = args.iter().map(|arg| evaluate_in(arg, row, context)).collect::<Result<Vec<Value>, String>>()?;
+            match context.call(name, &values) {
+                Some(result) => result,
+                None => call_function(name, &values),
+            }
+        },
+        Expr::InList { expr, list, negated } => {
+            let value = evaluate_in(expr, row, context)?;
+            let mut candidates = vec![];
+            for item in list {
+                candidates.push(evaluate_in(item, row, context)?);
+            }
+            Ok(is_in(&value, candidates.iter(), *negated))
+        },
+        Expr::InSubquery { expr, query, negated } => {
+            let value = evaluate_in(expr, row, context)?;
+            let rows = context.subquery(query, row)?;
+            Ok(is_in(&value, rows.iter().map(|r| &r[0]), *negated))
+        },
+        Expr::Exists(query) => Ok(Value::Bool(!context.subquery(query, row)?.is_empty())),
+        Expr::Subquery(query) => {
+            let rows = context.subquery(query, row)?;
+            match rows.as_slice() {
+                [] => Ok(Value::Null),
+                [only] => Ok(only[0].clone()),
+                _ => Err("A subquery used as a value returned more than one row".to_string()),
+            }
+        },
+        Expr::Cast { expr, data_type } => convert_value(&evaluate_in(expr, row, context)?, data_type),
+        Expr::Case { operand, branches, else_result } => {
+            let operand = match operand {
+                Some(operand) => Some(evaluate_in(operand, row, context)?),
+                None => None,
+            };
+            for (condition, result) in branches {
+                let value = evaluate_in(condition, row, context)?;
+                let matched = match &operand {
+                    // NULL matches nothing, not even NULL
+                    Some(operand) => compare_values(operand, &value) == Some(Ordering::Equal),
+                    None => truth(&value)? == Some(true),
+                };
+                if matched {
+                    return evaluate_in(result, row, context);
+                }
+            }
+            match else_result {
+                Some(expr) => evaluate_in(expr, row, context),
+                None => Ok(Value::Null),
+            }
+        },
+        Expr::Window { .. } => Err("Window functions are only allowed in the select list".to_string()),
+    }
+}
+
+// NULL when nothing matches but a NULL was compared, since it might have matched
+fn is_in<'a>(value: &Value, candidates: impl Iterator<Item = &'a Value>, negated: bool) -> Value {
+    let mut result = Value::Bool(negated);
+    for candidate in candidates {
+        if value.is_null() || candidate.is_null() {
+            result = Value::Null;
+        } else if compare_values(value, candidate) == Some(Ordering::Equal) {
+            return Value::Bool(!negated);
+        }
+    }
+    result
+}
+
+// True, false, or unknown for NULL
+pub fn truth(value: &Value) -> Result<Option<bool>, String> {
+    match value {
+        Value::Bool(b) => Ok(Some(*b)),
+        Value::Null => Ok(None),
+        other => Err(format!("Expected true or false, found {}", other)),
+    }
+}
+
+fn binary(op: BinaryOp, l: &Value, r: &Value) -> Result<Value, String> {
+    if l.is_null() || r.is_null() {
+        return Ok(Value::Null);
+    }
+    match op {
+        BinaryOp::Add | BinaryOp::Sub | BinaryOp::Mul | BinaryOp::Div | BinaryOp::Mod => arithmetic(op, l, r),
+        BinaryOp::Concat => Ok(Value::String(format!("{}{}", value_to_text(l), value_to_text(r)))),
+        BinaryOp::Eq | BinaryOp::NotEq | BinaryOp::Lt | BinaryOp::Gt | BinaryOp::LtEq | BinaryOp::GtEq => {
+            // Values of different types never match, the same as a WHERE clause
+            let result = match compare_values(l, r) {
+                Some(ordering) => match op {
+                    BinaryOp::Eq => ordering == Ordering::Equal,
+                    BinaryOp::NotEq => ordering != Ordering::Equal,
+                    BinaryOp::Lt => ordering == Ordering::Less,
+                    BinaryOp::Gt => ordering == Ordering::Greater,
+                    BinaryOp::LtEq => ordering != Ordering::Greater,
+                    _ => ordering != Ordering::Less,
+                },
+                None => false,
+            };
+            Ok(Value::Bool(result))
+        },
+        BinaryOp::And | BinaryOp::Or => unreachable!(),
+    }
+}
+
+pub fn arithmetic(op: BinaryOp, l: &Value, r: &Value) -> Result<Value, String> {
+    let (Value::Number(a), Value::Number(b)) = (l, r) else {
+        return Err(format!("Cannot do arithmetic on {} and {}", l, r));
+    };
+
+    if let (Some(x), Some(y)) = (a.as_i64(), b.as_i64()) {
+        let result = match op {
+            BinaryOp::Add => x.checked_add(y),
+            BinaryOp::Sub => x.checked_sub(y),
+            BinaryOp::Mul => x.checked_mul(y),
+            BinaryOp::Div if y == 0 => return Err("Division by zero".to_string()),
+            // Whole-number division stays whole, anything else becomes a float
+            BinaryOp::Div if x % y == 0 => x.checked_div(y),
+            BinaryOp::Div => return float_value(x as f64 / y as f64),
+            BinaryOp::Mod if y == 0 => return Err("Division by zero".to_string()),
+            _ => x.checked_rem(y),
+        };
+        return result.map(Value::from).ok_or_else(|| "Number out of range".to_string());
+    }
+
+    let x = a.as_f64().unwrap_or(0.0);
+    let y = b.as_f64().unwrap_or(0.0);
+    let result = match op {
+        BinaryOp::Add => x + y,
+        BinaryOp::Sub => x - y,
+        BinaryOp::Mul => x * y,
+        BinaryOp::Div | BinaryOp::Mod if y == 0.0 => return Err("Division by zero".to_string()),
+        BinaryOp::Div => x / y,
+        _ => x % y,
+    };
+    float_value(result)
+}
+
+pub fn float_value(f: f64) -> Result<Value, String> {
+    Number::from_f64(f).map(Value::Number).ok_or_else(|| format!("Invalid number: {}", f))
+}
+
+// Orders two values of the same type, or None when the types don't compare
+pub fn compare_values(l: &Value, r: &Value) -> Option<Ordering> {
+    match (l, r) {
+        (Value::Number(a), Value::Number(b)) => match (a.as_i64(), b.as_i64()) {
+            (Some(x), Some(y)) => Some(x.cmp(&y)),
+            _ => a.as_f64()?.partial_cmp(&b.as_f64()?),
+        },
+        (Value::String(a), Value::String(b)) => Some(a.cmp(b)),
+        (Value::Bool(a), Value::Bool(b)) => Some(a.cmp(b)),
+        _ => None,
+    }
+}
+
+// Plain text for a value, without the quotes JSON would put around strings
+pub fn value_to_text(value: &Value) -> String {
+    match value {
+        Value::String(s) => s.clone(),
+        Value::Null => String::new(),
+        other => other.to_string(),
+    }
+}
+
+impl Expr {
+    // Calls `f` with every column name in the expression
+    pub fn visit_columns_mut(&mut self, f: &mut impl FnMut(&mut String)) {
+        match self {
+            Expr::Literal(_) => {},
+            Expr::Column(name) => f(name),
+            Expr::Unary { expr, .. } | Expr::IsNull { expr, .. } => expr.visit_columns_mut(f),
+            Expr::Binary { left, right, .. } => {
+                left.visit_columns_mut(f);
+                right.visit_columns_mut(f);
+            },
+            Expr::Function { args, .. } => {
+                for arg in args {
+                    arg.visit_columns_mut(f);
+                }
+            },
+            Expr::InList { expr, list, .. } => {
+                expr.visit_columns_mut(f);
+                for item in list {
+                    item.visit_columns_mut(f);
+                }
+            },
+            // Columns inside a subquery belong to its own FROM
+            Expr::InSubquery { expr, .. } => expr.visit_columns_mut(f),
+            Expr::Exists(_) | Expr::Subquery(_) => {},
+            Expr::Cast { expr, .. } => expr.visit_columns_mut(f),
+            Expr::Case { operand, branches, else_result } => {
+                if let Some(operand) = operand {
+                    operand.visit_columns_mut(f);
+                }
+                for (condition, result) in branches {
+                    condition.visit_columns_mut(f);
+                    result.visit_columns_mut(f);
+                }
+                if let Some(expr) = else_result {
+                    expr.visit_columns_mut(f);
+                }
+            },
+            Expr::Window { args, spec, .. } => {
+                for expr in args.iter_mut().chain(spec.partition_by.iter_mut()).chain(spec.order_by.iter_mut().map(|o| &mut o.expr)) {
+                    expr.visit_columns_mut(f);
+                }
+            },
+        }
+    }
+
+    // The expressions directly under this one, leaving out subqueries
+    pub fn children_mut(&mut self) -> Vec<&mut Expr> {
+        match self {
+            Expr::Literal(_) | Expr::Column(_) | Expr::Exists(_) | Expr::Subquery(_) => vec![],
+            Expr::Unary { expr, .. } | Expr::IsNull { expr, .. } | Expr::InSubquery { expr, .. } | Expr::Cast { expr, .. } => vec![expr],
+            Expr::Binary { left, right, .. } => vec![left, right],
+            Expr::Function { args, .. } => args.iter_mut().collect(),
+            Expr::InList { expr, list, .. } => {
+                let mut children = vec![expr.as_mut()];
+                children.extend(list.iter_mut());
+                children
+            },
+            Expr::Case { operand, branches, else_result } => {
+                let mut children: Vec<&mut Expr> = operand.iter_mut().map(|e| e.as_mut()).collect();
+                for (condition, result) in branches.iter_mut() {
+                    children.push(condition);
+                    children.push(result);
+                }
+                children.extend(else_result.iter_mut().map(|e| e.as_mut()));
+                children
+            },
+            Expr::Window { args, spec, .. } => {
+                let mut children: Vec<&mut Expr> = args.iter_mut().collect();
+                children.extend(spec.partition_by.iter_mut());
+                children.extend(spec.order_by.iter_mut().map(|o| &mut o.expr));
+                children
+            },
+        }
+    }
+
+    pub fn children(&self) -> Vec<&Expr> {
+        match self {
+            Expr::Literal(_) | Expr::Column(_) | Expr::Exists(_) | Expr::Subquery(_) => vec![],
+            Expr::Unary { expr, .. } | Expr::IsNull { expr, .. } | Expr::InSubquery { expr, .. } | Expr::Cast { expr, .. } => vec![expr],
+            Expr::Binary { left, right, .. } => vec![left, right],
+            Expr::Function { args, .. } => args.iter().collect(),
+            Expr::InList { expr, list, .. } => {
+                let mut children = vec![expr.as_ref()];
+                children.extend(list.iter());
+                children
+            },
+            Expr::Case { operand, branches, else_result } => {
+                let mut children: Vec<&Expr> = operand.iter().map(|e| e.as_ref()).collect();
+                for (condition, result) in branches {
+                    children.push(condition);
+                    children.push(result);
+                }
+                children.extend(else_result.iter().map(|e| e.as_ref()));
+                children
+            },
+            Expr::Window { args, spec, .. } => {
+                let mut children: Vec<&Expr> = args.iter().collect();
+                children.extend(spec.partition_by.iter());
+                children.extend(spec.order_by.iter().map(|o| &o.expr));
+                children
+            },
+        }
+    }
+
+    // Subqueries anywhere in the expression, but not ones nested inside them
+    pub fn subqueries(&self) -> Vec<&SelectStatement> {
+        let mut found = match self {
+            Expr::InSubquery { query, .. } | Expr::Exists(query) | Expr::Subquery(query) => vec![query.as_ref()],
+            _ => vec![],
+        };
+        for child in self.children() {
+            found.extend(child.subqueries());
+        }
+        found
+    }
+
+    pub fn has_subquery(&self) -> bool {
+        match self {
+            Expr::Literal(_) | Expr::Column(_) => false,
+            Expr::Unary { expr, .. } | Expr::IsNull { expr, .. } => expr.has_subquery(),
+            Expr::Binary { left, right, .. } => left.has_subquery() || right.has_subquery(),
+            Expr::Function { args, .. } => args.iter().any(|a| a.has_subquery()),
+            Expr::InList { expr, list, .. } => expr.has_subquery() || list.iter().any(|e| e.has_subquery()),
+            Expr::InSubquery { .. } | Expr::Exists(_) | Expr::Subquery(_) => true,
+            Expr::Cast { expr, .. } => expr.has_subquery(),
+            Expr::Case { operand, branches, else_result } => {
+                operand.as_ref().is_some_and(|e| e.has_subquery())
+                    || branches.iter().any(|(c, r)| c.has_subquery() || r.has_subquery())
+                    || else_result.as_ref().is_some_and(|e| e.has_subquery())
+            },
+            Expr::Window { args, spec, .. } => {
+                args.iter().chain(&spec.partition_by).chain(spec.order_by.iter().map(|o| &o.expr)).any(|e| e.has_subquery())
+            },
+        }
+    }
+
+    pub fn columns(&self) -> Vec<String> {
+        let mut columns = vec![];
+        self.clone().visit_columns_mut(&mut |name| columns.push(name.clone()));
+        columns
+    }
+
+    fn precedence(&self) -> u8 {
+        match self {
+            Expr::Binary { op, .. } => op.precedence(),
+            Expr::Unary { op: UnaryOp::Not, .. } => 3,
+            Expr::IsNull { .. } | Expr::InList { .. } | Expr::InSubquery { .. } => 4,
+            _ => 8,
+        }
+    }
+}
+
+impl BinaryOp {
+    fn precedence(&self) -> u8 {
+        match self {
+            BinaryOp::Or => 1,
+            BinaryOp::And => 2,
+            BinaryOp::Eq | BinaryOp::NotEq | BinaryOp::Lt | BinaryOp::Gt | BinaryOp::LtEq | BinaryOp::GtEq => 4,
+            BinaryOp::Add | BinaryOp::Sub | BinaryOp::Concat => 5,
+            BinaryOp::Mul | BinaryOp::Div | BinaryOp::Mod => 6,
+        }
+    }
+
+    fn symbol(&self) -> &'static str {
+        match self {
+            BinaryOp::Add => "+",
+            BinaryOp::Sub => "-",
+            BinaryOp::Mul => "*",
+            BinaryOp::Div => "/",
+            BinaryOp::Mod => "%",
+            BinaryOp::Concat => "||",
+            BinaryOp::Eq => "=",
+            BinaryOp::NotEq => "!=",
+            BinaryOp::Lt => "<",
+            BinaryOp::Gt => ">",
+            BinaryOp::LtEq => "<=",
+            BinaryOp::GtEq => ">=",
+            BinaryOp::And => "AND",
+            BinaryOp::Or => "OR",
+        }
+    }
+}
+
+// Writes the expression back out as text that parses to the same tree
+impl fmt::Display for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let wrap = |f: &mut fmt::Formatter, child: &Expr, needs_parens: bool| {
+            if needs_parens {
+                write!(f, "({})", child)
+            } else {
+                write!(f, "{}", child)
+            }
+        };
+        match self {
+            Expr::Literal(Value::String(s)) => write!(f, "'{}'", s.replace('\'', "''")),
+            Expr::Literal(Value::Bool(b)) => write!(f, "{}", if *b { "TRUE" } else { "FALSE" }),
+            Expr::Literal(Value::Null) => write!(f, "NULL"),
+            Expr::Literal(other) => write!(f, "{}", other),
+            Expr::Column(name) => write!(f, "{}", name),
+            Expr::Unary { op: UnaryOp::Neg, expr } => {
+                write!(f, "-")?;
+                wrap(f, expr, expr.precedence() < 8)
+            },
+            Expr::Unary { op: UnaryOp::Not, expr } => {
+                write!(f, "NOT ")?;
+                wrap(f, expr, expr.precedence() < 3)
+            },
+            Expr::IsNull { expr, negated } => {
+                wrap(f, expr, expr.precedence() <= 4)?;
+                write!(f, " IS {}NULL", if *negated { "NOT " } else { "" })
+            },
+            Expr::Binary { left, op, right } => {
+                // Comparisons don't chain, and the right side of a left-associative operator needs grouping
+                let own = op.precedence();
+                wrap(f, left, left.precedence() < own || (own == 4 && left.precedence() == 4))?;
+                write!(f, " {} ", op.symbol())?;
+                wrap(f, right, right.precedence() <= own)
+            },
+            Expr::Function { name, args } => {
+                write!(f, "{}(", name)?;
+                for (i, arg) in args.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}", arg)?;
+                }
+                write!(f, ")")
+            },
+            Expr::InList { expr, list, negated } => {
+                wrap(f, expr, expr.precedence() <= 4)?;
+                write!(f, " {}IN (", if *negated { "NOT " } else { "" })?;
+                for (i, item) in list.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}", item)?;
+                }
+                write!(f, ")")
+            },
+            Expr::InSubquery { expr, query, negated } => {
+                wrap(f, expr, expr.precedence() <= 4)?;
+                write!(f, " {}IN ({})", if *negated { "NOT " } else { "" }, query)
+            },
+            Expr::Exists(query) => write!(f, "EXISTS ({})", query),
+            Expr::Subquery(query) => write!(f, "({})", query),
+            Expr::Cast { expr, data_type } => write!(f, "CAST({} AS {:?})", expr, data_type),
+            Expr::Case { operand, branches, else_result } => {
+                write!(f, "CASE")?;
+                if let Some(operand) = operand {
+                    write!(f, " {}", operand)?;
+                }
+                for (condition, result) in branches {
+                    write!(f, " WHEN {} THEN {}", condition, result)?;
+                }
+                if let Some(expr) = else_result {
+                    write!(f, " ELSE {}", expr)?;
+                }
+                write!(f, " END")
+            },
+            Expr::Window { name, args, spec } => {
+                if name == "COUNT" && args.is_empty() {
+                    return write!(f, "COUNT(*) OVER {}", spec);
+                }
+                let args: Vec<String> = args.iter().map(|a| a.to_string()).collect();
+                write!(f, "{}({}) OVER {}", name, args.join(", "), spec)
+            },
+        }
+    }
+}
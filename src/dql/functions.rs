@@ -0,0 +1,247 @@
+use std::{cell::RefCell, cmp::Ordering, collections::HashMap, rc::Rc};
+
+use serde_json::{Number, Value};
+
+use crate::{ddl::sequence::is_sequence_function, dql::expr::{arithmetic, compare_values, float_value, value_to_text, BinaryOp}};
+
+// Scalar functions every expression can call. Names are upper case, as the parser stores them.
+pub struct Function {
+    pub name: &'static str,
+    pub min_args: usize,
+    // None when any number of arguments is allowed
+    pub max_args: Option<usize>,
+    // A NULL argument makes the result NULL without calling the function
+    pub strict: bool,
+    pub call: fn(&[Value]) -> Result<Value, String>,
+}
+
+pub const FUNCTIONS: &[Function] = &[
+    Function { name: "UPPER", min_args: 1, max_args: Some(1), strict: true, call: upper },
+    Function { name: "LOWER", min_args: 1, max_args: Some(1), strict: true, call: lower },
+    Function { name: "LENGTH", min_args: 1, max_args: Some(1), strict: true, call: length },
+    Function { name: "SUBSTR", min_args: 2, max_args: Some(3), strict: true, call: substr },
+    Function { name: "TRIM", min_args: 1, max_args: Some(2), strict: true, call: trim },
+    Function { name: "REPLACE", min_args: 3, max_args: Some(3), strict: true, call: replace },
+    Function { name: "CONCAT", min_args: 1, max_args: None, strict: false, call: concat },
+    Function { name: "ABS", min_args: 1, max_args: Some(1), strict: true, call: abs },
+    Function { name: "ROUND", min_args: 1, max_args: Some(2), strict: true, call: round },
+    Function { name: "FLOOR", min_args: 1, max_args: Some(1), strict: true, call: floor },
+    Function { name: "CEIL", min_args: 1, max_args: Some(1), strict: true, call: ceil },
+    Function { name: "CEILING", min_args: 1, max_args: Some(1), strict: true, call: ceil },
+    Function { name: "MOD", min_args: 2, max_args: Some(2), strict: true, call: modulo },
+    Function { name: "COALESCE", min_args: 1, max_args: None, strict: false, call: coalesce },
+    Function { name: "NULLIF", min_args: 2, max_args: Some(2), strict: false, call: nullif },
+];
+
+pub fn lookup(name: &str) -> Option<&'static Function> {
+    FUNCTIONS.iter().find(|f| f.name == name)
+}
+
+pub type UserFunction = Rc<dyn Fn(&[Value]) -> Result<Value, String>>;
+
+// Functions registered from Rust, by upper case name with how many arguments they take. They
+// are called with NULL arguments like any other value.
+thread_local! {
+    static REGISTERED: RefCell<HashMap<String, (usize, UserFunction)>> = RefCell::new(HashMap::new());
+}
+
+// Built-in names can't be taken, but a registered function can be replaced
+pub fn register_function(name: &str, arity: usize, call: UserFunction) -> Result<(), String> {
+    let name = name.to_uppercase();
+    if lookup(&name).is_some() || is_sequence_function(&name) {
+        return Err(format!("{} is a built-in function", name));
+    }
+    REGISTERED.with(|registered| registered.borrow_mut().insert(name, (arity, call)));
+    Ok(())
+}
+
+fn registered(name: &str) -> Option<(usize, UserFunction)> {
+    REGISTERED.with(|registered| registered.borrow().get(name).cloned())
+}
+
+// Fails for unknown names and the wrong number of arguments, so queries can be checked before they run
+pub fn check_call(name: &str, arg_count: usize) -> Result<(), String> {
+    match registered(name) {
+        Some((arity, _)) if arity != arg_count => Err(format!("{} takes {} argument(s), got {}", name, arity, arg_count)),
+        Some(_) => Ok(()),
+        None => check_builtin(name, arg_count).map(|_| ()),
+    }
+}
+
+fn check_builtin(name: &str, arg_count: usize) -> Result<&'static Function, String> {
+    let function = lookup(name).ok_or_else(|| format!("Unknown function: {}", name))?;
+    let expected = match function.max_args {
+        Some(max) if max == function.min_args => max.to_string(),
+        Some(max) => format!("{} to {}", function.min_args, max),
+        None => format!("at least {}", function.min_args),
+    };
+    if arg_count < function.min_args || function.max_args.is_some_and(|max| arg_count > max) {
+        return Err(format!("{} takes {} argument(s), got {}", name, expected, arg_count));
+    }
+    Ok(function)
+}
+
+pub fn call_function(name: &str, args: &[Value]) -> Result<Value, String> {
+    if let Some((_, call)) = registered(name) {
+        check_call(name, args.len())?;
+        return call(args).map_err(|e| format!("{}: {}", name, e));
+    }
+    let function = check_builtin(name, args.len())?;
+    if function.strict && args.iter().any(|a| a.is_null()) {
+        return Ok(Value::Null);
+    }
+    (function.call)(args).map_err(|e| format!("{}: {}", name, e))
+}
+
+fn text(value: &Value) -> Result<&str, String> {
+    match value {
+        Value::String(s) => Ok(s),
+        other => Err(format!("expected text, got {}", other)),
+    }
+}
+
+fn number(value: &Value) -> Result<&Number, String> {
+    match value {
+        Value::Number(n) => Ok(n),
+        other => Err(format!("expected a number, got {}", other)),
+    }
+}
+
+// Positions and counts, where 2.0 is as good as 2
+fn integer(value: &Value) -> Result<i64, String> {
+    let n = number(value)?;
+    n.as_i64()
+        .or_else(|| n.as_f64().filter(|f| f.fract() == 0.0 && f.abs() < i64::MAX as f64).map(|f| f as i64))
+        .ok_or_else(|| format!("expected a whole number, got {}", n))
+}
+
+// Rounded results are whole numbers unless they don't fit one
+fn whole(f: f64) -> Result<Value, String> {
+    if f.abs() < i64::MAX as f64 {
+        Ok(Value::from(f as i64))
+    } else {
+        float_value(f)
+    }
+}
+
+fn upper(args: &[Value]) -> Result<Value, String> {
+    Ok(Value::String(text(&args[0])?.to_uppercase()))
+}
+
+fn lower(args: &[Value]) -> Result<Value, String> {
+    Ok(Value::String(text(&args[0])?.to_lowercase()))
+}
+
+fn length(args: &[Value]) -> Result<Value, String> {
+    Ok(Value::from(text(&args[0])?.chars().count()))
+}
+
+// SUBSTR(text, start [, count]), counting characters from 1. Characters before the first still
+// use up the count.
+fn substr(args: &[Value]) -> Result<Value, String> {
+    let chars: Vec<char> = text(&args[0])?.chars().collect();
+    let start = integer(&args[1])?;
+    let after_last = chars.len() as i64 + 1;
+    let end = match args.get(2) {
+        Some(count) => {
+            let count = integer(count)?;
+            if count < 0 {
+                return Err("negative substring length".to_string());
+            }
+            start.saturating_add(count).min(after_last)
+        },
+        None => after_last,
+    };
+    let begin = start.max(1);
+    if end <= begin {
+        return Ok(Value::String(String::new()));
+    }
+    Ok(Value::String(chars[(begin - 1) as usize..(end - 1) as usize].iter().collect()))
+}
+
+// TRIM(text [, characters]) takes whitespace, or any of the given characters, off both ends
+fn trim(args: &[Value]) -> Result<Value, String> {
+    let s = text(&args[0])?;
+    let trimmed = match args.get(1) {
+        Some(characters) => {
+            let characters: Vec<char> = text(characters)?.chars().collect();
+            s.trim_matches(characters.as_slice())
+        },
+        None => s.trim(),
+    };
+    Ok(Value::String(trimmed.to_string()))
+}
+
+fn replace(args: &[Value]) -> Result<Value, String> {
+    let s = text(&args[0])?;
+    let from = text(&args[1])?;
+    let to = text(&args[2])?;
+    if from.is_empty() {
+        return Ok(Value::String(s.to_string()));
+    }
+    Ok(Value::String(s.replace(from, to)))
+}
+
+// Any values, as text. NULLs are left out rather than making the result NULL.
+fn concat(args: &[Value]) -> Result<Value, String> {
+    Ok(Value::String(args.iter().map(value_to_text).collect()))
+}
+
+fn abs(args: &[Value]) -> Result<Value, String> {
+    let n = number(&args[0])?;
+    match n.as_i64() {
+        Some(i) => i.checked_abs().map(Value::from).ok_or_else(|| "Number out of range".to_string()),
+        None => float_value(n.as_f64().unwrap_or(0.0).abs()),
+    }
+}
+
+// ROUND(number [, places]) rounds halves away from zero. Negative places round to tens, hundreds, ...
+fn round(args: &[Value]) -> Result<Value, String> {
+    let n = number(&args[0])?;
+    let places = match args.get(1) {
+        Some(places) => integer(places)?.clamp(-308, 308) as i32,
+        None => 0,
+    };
+    if n.as_i64().is_some() && places >= 0 {
+        return Ok(args[0].clone());
+    }
+    let factor = 10f64.powi(places);
+    let rounded = (n.as_f64().unwrap_or(0.0) * factor).round() / factor;
+    if places <= 0 {
+        whole(rounded)
+    } else {
+        float_value(rounded)
+    }
+}
+
+fn floor(args: &[Value]) -> Result<Value, String> {
+    let n = number(&args[0])?;
+    match n.as_i64() {
+        Some(_) => Ok(args[0].clone()),
+        None => whole(n.as_f64().unwrap_or(0.0).floor()),
+    }
+}
+
+fn ceil(args: &[Value]) -> Result<Value, String> {
+    let n = number(&args[0])?;
+    match n.as_i64() {
+        Some(_) => Ok(args[0].clone()),
+        None => whole(n.as_f64().unwrap_or(0.0).ceil()),
+    }
+}
+
+fn modulo(args: &[Value]) -> Result<Value, String> {
+    arithmetic(BinaryOp::Mod, &args[0], &args[1])
+}
+
+fn coalesce(args: &[Value]) -> Result<Value, String> {
+    Ok(args.iter().find(|v| !v.is_null()).cloned().unwrap_or(Value::Null))
+}
+
+// NULLIF(a, b) is NULL when a equals b, and a otherwise
+fn nullif(args: &[Value]) -> Result<Value, String> {
+    if compare_values(&args[0], &args[1]) == Some(Ordering::Equal) {
+        return Ok(Value::Null);
+    }
+    Ok(args[0].clone())
+}
@@ -0,0 +1,215 @@
+use std::collections::HashMap;
+
+use serde_json::{json, Value};
+
+use crate::catalog::{load_catalog, resolve_table_name, split_table_name, INFORMATION_SCHEMA};
+use crate::models::{FieldDataType, FieldDef, Table};
+use crate::storage::StorageBackend;
+
+// Read-only tables built from the catalog and table schemas whenever they are queried
+pub fn virtual_table(storage: &mut dyn StorageBackend, name: &str) -> Result<Table, String> {
+    match name.strip_prefix(INFORMATION_SCHEMA).and_then(|n| n.strip_prefix('.')) {
+        Some("tables") => tables_table(storage),
+        Some("columns") => columns_table(storage),
+        Some("column_stats") => column_stats_table(storage),
+        _ => Err(format!("Table {} not found", name)),
+    }
+}
+
+pub fn is_virtual_table(name: &str) -> bool {
+    split_table_name(name).0 == INFORMATION_SCHEMA
+}
+
+pub fn show_databases(storage: &mut dyn StorageBackend) -> Result<Table, String> {
+    let catalog = load_catalog(storage)?;
+    let rows = catalog.databases.iter().map(|(name, entry)| {
+        json!({ "name": name, "tables": entry.tables.len() })
+    });
+    Ok(build_table("databases", vec![
+        column("name", FieldDataType::TEXT, true),
+        column("tables", FieldDataType::NUMBER, false),
+    ], rows, "name"))
+}
+
+pub fn show_tables(storage: &mut dyn StorageBackend, database: &str) -> Result<Table, String> {
+    let catalog = load_catalog(storage)?;
+    let entry = catalog.databases.get(database).ok_or_else(|| format!("Database {} not found", database))?;
+    let rows = entry.tables.iter().map(|(name, table)| {
+        json!({ "name": name, "schema_version": table.schema_version, "indexes": table.indexes.join(", ") })
+    });
+    Ok(build_table("tables", vec![
+        column("name", FieldDataType::TEXT, true),
+        column("schema_version", FieldDataType::NUMBER, false),
+        column("indexes", FieldDataType::TEXT, false),
+    ], rows, "name"))
+}
+
+pub fn show_sequences(storage: &mut dyn StorageBackend, database: &str) -> Result<Table, String> {
+    let catalog = load_catalog(storage)?;
+    let entry = catalog.databases.get(database).ok_or_else(|| format!("Database {} not found", database))?;
+    let rows = entry.sequences.iter().map(|(name, sequence)| {
+        json!({
+            "name": name,
+            "last_value": if sequence.is_called { Some(sequence.last_value) } else { None },
+            "increment": sequence.increment,
+            "min": sequence.min,
+            "max": sequence.max,
+            "cycle": sequence.cycle,
+            "owned_by": sequence.owner.as_ref().map(|o| format!("{}.{}", o.table, o.column)),
+        })
+    });
+    Ok(build_table("sequences", vec![
+        column("name", FieldDataType::TEXT, true),
+        column("last_value", FieldDataType::NUMBER, false),
+        column("increment", FieldDataType::NUMBER, false),
+        column("min", FieldDataType::NUMBER, false),
+        column("max", FieldDataType::NUMBER, false),
+        column("cycle", FieldDataType::BOOLEAN, false),
+        column("owned_by", FieldDataType::TEXT, false),
+    ], rows, "name"))
+}
+
+pub fn show_views(storage: &mut dyn StorageBackend, database: &str) -> Result<Table, String> {
+    let catalog = load_catalog(storage)?;
+    let entry = catalog.databases.get(database).ok_or_else(|| format!("Database {} not found", database))?;
+    let rows = entry.views.iter().map(|(name, view)| {
+        json!({ "name": name, "materialized": view.materialized, "query": view.query })
+    });
+    Ok(build_table("views", vec![
+        column("name", FieldDataType::TEXT, true),
+        column("materialized", FieldDataType::BOOLEAN, false),
+        column("query", FieldDataType::TEXT, false),
+    ], rows, "name"))
+}
+
+pub fn describe(storage: &mut dyn StorageBackend, name: &str) -> Result<Table, String> {
+    let table = storage.load_schema(name)?;
+    let rows = table.schema.iter().enumerate().map(|(i, field)| {
+        json!({
+            "position": i + 1,
+            "column": field.name,
+            "data_type": type_name(&field.data_type),
+            "primary_key": field.primary_key,
+            "sequence": field.serial.as_ref().and_then(|s| s.sequence.clone()),
+            "indexed": table.indexes.contains_key(&field.name),
+        })
+    });
+    Ok(build_table(name, vec![
+        column("position", FieldDataType::NUMBER, true),
+        column("column", FieldDataType::TEXT, false),
+        column("data_type", FieldDataType::TEXT, false),
+        column("primary_key", FieldDataType::BOOLEAN, false),
+        column("sequence", FieldDataType::TEXT, false),
+        column("indexed", FieldDataType::BOOLEAN, false),
+    ], rows, "position"))
+}
+
+fn tables_table(storage: &mut dyn StorageBackend) -> Result<Table, String> {
+    let catalog = load_catalog(storage)?;
+    let mut rows = vec![];
+    for (database, entry) in &catalog.databases {
+        for (name, table) in &entry.tables {
+            rows.push(json!({
+                "table_id": rows.len() + 1,
+                "table_schema": database,
+                "table_name": name,
+                "schema_version": table.schema_version,
+                "indexes": table.indexes.join(", "),
+            }));
+        }
+    }
+    Ok(build_table("information_schema.tables", vec![
+        column("table_id", FieldDataType::NUMBER, true),
+        column("table_schema", FieldDataType::TEXT, false),
+        column("table_name", FieldDataType::TEXT, false),
+        column("schema_version", FieldDataType::NUMBER, false),
+        column("indexes", FieldDataType::TEXT, false),
+    ], rows, "table_id"))
+}
+
+fn columns_table(storage: &mut dyn StorageBackend) -> Result<Table, String> {
+    let catalog = load_catalog(storage)?;
+    let mut rows = vec![];
+    for (database, entry) in &catalog.databases {
+        for name in entry.tables.keys() {
+            let table = storage.load_schema(&resolve_table_name(name, database))?;
+            for (i, field) in table.schema.iter().enumerate() {
+                rows.push(json!({
+                    "column_id": rows.len() + 1,
+                    "table_schema": database,
+                    "table_name": name,
+                    "column_name": field.name,
+                    "ordinal_position": i + 1,
+                    "data_type": type_name(&field.data_type),
+                    "primary_key": field.primary_key,
+                    "sequence": field.serial.as_ref().and_then(|s| s.sequence.clone()),
+                    "indexed": table.indexes.contains_key(&field.name),
+                }));
+            }
+        }
+    }
+    Ok(build_table("information_schema.columns", vec![
+        column("column_id", FieldDataType::NUMBER, true),
+        column("table_schema", FieldDataType::TEXT, false),
+        column("table_name", FieldDataType::TEXT, false),
+        column("column_name", FieldDataType::TEXT, false),
+        column("ordinal_position", FieldDataType::NUMBER, false),
+        column("data_type", FieldDataType::TEXT, false),
+        column("primary_key", FieldDataType::BOOLEAN, false),
+        column("sequence", FieldDataType::TEXT, false),
+        column("indexed", FieldDataType::BOOLEAN, false),
+    ], rows, "column_id"))
+}
+
+// What ANALYZE last collected, one row per column of each analyzed table
+fn column_stats_table(storage: &mut dyn StorageBackend) -> Result<Table, String> {
+    let catalog = load_catalog(storage)?;
+    let mut rows = vec![];
+    for (database, entry) in &catalog.databases {
+        for (name, table) in &entry.tables {
+            let Some(stats) = &table.stats else { continue };
+            for (column_name, column) in &stats.columns {
+                let most_common: Vec<String> = column.most_common.iter().map(|(value, count)| format!("{}: {}", value, count)).collect();
+                let histogram: Vec<String> = column.histogram.iter().map(|value| value.to_string()).collect();
+                rows.push(json!({
+                    "stats_id": rows.len() + 1,
+                    "table_schema": database,
+                    "table_name": name,
+                    "column_name": column_name,
+                    "analyzed_rows": stats.rows,
+                    "nulls": column.nulls,
+                    "distinct_values": column.distinct,
+                    "most_common": most_common.join(", "),
+                    "histogram": histogram.join(", "),
+                }));
+            }
+        }
+    }
+    Ok(build_table("information_schema.column_stats", vec![
+        column("stats_id", FieldDataType::NUMBER, true),
+        column("table_schema", FieldDataType::TEXT, false),
+        column("table_name", FieldDataType::TEXT, false),
+        column("column_name", FieldDataType::TEXT, false),
+        column("analyzed_rows", FieldDataType::NUMBER, false),
+        column("nulls", FieldDataType::NUMBER, false),
+        column("distinct_values", FieldDataType::NUMBER, false),
+        column("most_common", FieldDataType::TEXT, false),
+        column("histogram", FieldDataType::TEXT, false),
+    ], rows, "stats_id"))
+}
+
+fn type_name(data_type: &Option<FieldDataType>) -> String {
+    match data_type {
+        Some(t) => format!("{:?}", t),
+        None => "UNKNOWN".to_string(),
+    }
+}
+
+fn column(name: &str, data_type: FieldDataType, primary_key: bool) -> FieldDef {
+    FieldDef { name: name.to_string(), data_type: Some(data_type), primary_key, serial: None }
+}
+
+fn build_table(name: &str, schema: Vec<FieldDef>, rows: impl IntoIterator<Item = Value>, key: &str) -> Table {
+    let data: HashMap<Value, Value> = rows.into_iter().map(|row| (row[key].clone(), row)).collect();
+    Table { name: name.to_string(), schema, data, indexes: HashMap::new(), constraints: vec![] }
+}
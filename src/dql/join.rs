@@ -0,0 +1,353 @@
+use std::{collections::{HashMap, HashSet}, rc::Rc};
+
+use serde_json::{Map, Value};
+
+use crate::{dql::{expr::{evaluate_in, truth, BinaryOp, EvalContext, Expr}, statement::JoinKind, stats::{distinct_values, selectivity}}, models::{ColumnStats, IndexNumber, IndexStore, OrderedFloat, Table, TableStats}, storage::btree::compare_keys};
+
+// Inner joins of up to this many tables are tried in every order
+const MAX_REORDERED_TABLES: usize = 6;
+
+// How rows of the joined table are found for each row built so far
+#[derive(Debug, Clone, PartialEq)]
+pub enum JoinStrategy {
+    // ON compares the joined table's primary key, so each row is a single lookup
+    KeyLookup { column: String, probe: Expr },
+    IndexLookup { column: String, probe: Expr },
+    // The joined table is hashed on its side of an `=`, then probed
+    HashJoin { column: String, probe: Expr },
+    NestedLoop,
+}
+
+#[derive(Clone)]
+pub struct Source {
+    pub table: Rc<Table>,
+    pub alias: String,
+    // From ANALYZE, for stored tables that have been analyzed
+    pub stats: Option<Rc<TableStats>>,
+}
+
+pub struct Join {
+    pub kind: JoinKind,
+    pub source: Source,
+    pub on: Expr,
+    pub strategy: JoinStrategy,
+}
+
+impl Source {
+    // Column names as rows of a query carry them, <alias>.<column>
+    pub fn columns(&self) -> Vec<String> {
+        self.table.schema.iter().map(|f| format!("{}.{}", self.alias, f.name)).collect()
+    }
+
+    pub fn has_column(&self, name: &str) -> bool {
+        self.table.schema.iter().any(|f| f.name == name)
+    }
+
+    // Stats for a column, with the number of rows they were taken from
+    pub fn column_stats(&self, column: &str) -> Option<(&ColumnStats, usize)> {
+        let stats = self.stats.as_ref()?;
+        stats.columns.get(column).map(|c| (c, stats.rows))
+    }
+
+    // Exact for the primary key and indexed columns, estimated from stats for the rest
+    pub fn distinct_values(&self, column: &str) -> Option<usize> {
+        let rows = self.table.data.len();
+        if self.table.schema.iter().any(|f| f.primary_key && f.name == column) {
+            return Some(rows);
+        }
+        if let Some(index) = self.table.indexes.get(column) {
+            return Some(index_values(&index.index_data));
+        }
+        self.column_stats(column).map(|(stats, analyzed)| distinct_values(stats, analyzed, rows))
+    }
+
+    pub fn qualify_row(&self, row: &Value) -> Value {
+        let map: Map<String, Value> = self.table.schema.iter()
+            .map(|f| (format!("{}.{}", self.alias, f.name), row.get(&f.name).cloned().unwrap_or(Value::Null)))
+            .collect();
+        Value::Object(map)
+    }
+}
+
+// Looks for `<joined column> = <expression over earlier tables>` among the ANDed parts of ON,
+// preferring the primary key, then an indexed column
+pub fn choose_strategy(on: &Expr, source: &Source) -> JoinStrategy {
+    let prefix = format!("{}.", source.alias);
+    let mut candidates = vec![];
+    for part in conjuncts(on) {
+        let Expr::Binary { left, op: BinaryOp::Eq, right } = part else { continue };
+        for (inner, probe) in [(left, right), (right, left)] {
+            let Expr::Column(name) = inner.as_ref() else { continue };
+            let Some(column) = name.strip_prefix(&prefix) else { continue };
+            if !probe.columns().iter().any(|c| c.starts_with(&prefix)) {
+                candidates.push((column.to_string(), probe.as_ref().clone()));
+            }
+        }
+    }
+
+    let key = source.table.schema.iter().find(|f| f.primary_key).map(|f| f.name.clone());
+    if let Some((column, probe)) = candidates.iter().find(|(c, _)| Some(c) == key.as_ref()) {
+        return JoinStrategy::KeyLookup { column: column.clone(), probe: probe.clone() };
+    }
+    if let Some((column, probe)) = candidates.iter().find(|(c, _)| source.table.indexes.contains_key(c)) {
+        return JoinStrategy::IndexLookup { column: column.clone(), probe: probe.clone() };
+    }
+    match candidates.into_iter().next() {
+        Some((column, probe)) => JoinStrategy::HashJoin { column, probe },
+        None => JoinStrategy::NestedLoop,
+    }
+}
+
+// Order doesn't change what inner joins give, so the tables are joined in the order expected to do
+// the least work, and each part of the ONs goes to the first join where every table it reads is
+// in. `ons[i]` is the ON joining `sources[i + 1]`. The written order wins ties, and keeps its ONs.
+pub fn order_joins(sources: Vec<Source>, ons: Vec<Expr>) -> (Vec<Source>, Vec<Expr>) {
+    if sources.len() < 2 || sources.len() > MAX_REORDERED_TABLES {
+        return (sources, ons);
+    }
+    let conditions: Vec<Expr> = ons.iter().flat_map(conjuncts).cloned().collect();
+    // Tables each condition reads, by position; columns of enclosing queries are always there
+    let reads: Vec<Vec<usize>> = conditions.iter().map(|condition| {
+        condition.columns().iter().filter_map(|c| sources.iter().position(|s| c.starts_with(&format!("{}.", s.alias)))).collect()
+    }).collect();
+
+    let mut best: Option<(f64, Vec<usize>, Vec<Expr>)> = None;
+    for order in permutations(sources.len()) {
+        let (cost, order_ons) = order_cost(&sources, &order, &conditions, &reads);
+        if best.as_ref().is_none_or(|(best_cost, _, _)| cost < *best_cost) {
+            best = Some((cost, order, order_ons));
+        }
+    }
+    match best {
+        Some((_, order, order_ons)) if order.iter().enumerate().any(|(i, o)| i != *o) => {
+            (order.iter().map(|i| sources[*i].clone()).collect(), order_ons)
+        },
+        _ => (sources, ons),
+    }
+}
+
+// Rows read and built over the whole join, with the ON each join gets
+fn order_cost(sources: &[Source], order: &[usize], conditions: &[Expr], reads: &[Vec<usize>]) -> (f64, Vec<Expr>) {
+    let mut rows = sources[order[0]].table.data.len();
+    let mut cost = rows as f64;
+    let mut used = vec![false; conditions.len()];
+    let mut ons = vec![];
+    for (placed, next) in order.iter().enumerate().skip(1) {
+        let joined = &order[..=placed];
+        let mut on: Option<Expr> = None;
+        for (i, condition) in conditions.iter().enumerate() {
+            if used[i] || !reads[i].iter().all(|r| joined.contains(r)) {
+                continue;
+            }
+            used[i] = true;
+            on = Some(match on {
+                Some(left) => Expr::Binary { left: Box::new(left), op: BinaryOp::And, right: Box::new(condition.clone()) },
+                None => condition.clone(),
+            });
+        }
+        let on = on.unwrap_or(Expr::Literal(Value::Bool(true)));
+        let source = sources[*next].clone();
+        let strategy = choose_strategy(&on, &source);
+        let join = Join { kind: JoinKind::Inner, source, on, strategy };
+        let output = estimate_join(sources, rows, &join);
+        cost += join_cost(rows, output, &join) + output as f64;
+        rows = output;
+        ons.push(join.on);
+    }
+    (cost, ons)
+}
+
+// Every order of 0..n, starting with 0..n itself
+fn permutations(n: usize) -> Vec<Vec<usize>> {
+    let mut orders: Vec<Vec<usize>> = vec![vec![]];
+    for _ in 0..n {
+        let mut longer = vec![];
+        for order in &orders {
+            for i in (0..n).filter(|i| !order.contains(i)) {
+                let mut next = order.clone();
+                next.push(i);
+                longer.push(next);
+            }
+        }
+        orders = longer;
+    }
+    orders
+}
+
+// Rows a join gives for `left` rows coming in. `sources` are all the tables of the statement.
+pub fn estimate_join(sources: &[Source], left: usize, join: &Join) -> usize {
+    let right = join.source.table.data.len();
+    let kept: f64 = conjuncts(&join.on).into_iter().map(|part| part_selectivity(sources, part)).product();
+    let matched = (left as f64 * right as f64 * kept).round() as usize;
+    match join.kind {
+        JoinKind::Inner => matched,
+        JoinKind::Left => matched.max(left),
+        JoinKind::Right => matched.max(right),
+        JoinKind::Full => matched.max(left).max(right),
+    }
+}
+
+// `a = b` between columns of two tables keeps one pair in as many as the column with more
+// distinct values has, taking a column nothing is known about to be unique
+fn part_selectivity(sources: &[Source], part: &Expr) -> f64 {
+    fn find<'s, 'n>(sources: &'s [Source], name: &'n str) -> Option<(&'s Source, &'n str)> {
+        let (alias, column) = name.split_once('.')?;
+        sources.iter().find(|s| s.alias == alias).map(|source| (source, column))
+    }
+    if let Expr::Binary { left, op: BinaryOp::Eq, right } = part {
+        if let (Expr::Column(a), Expr::Column(b)) = (left.as_ref(), right.as_ref()) {
+            if let (Some(a), Some(b)) = (find(sources, a), find(sources, b)) {
+                let distinct = |(source, column): (&Source, &str)| source.distinct_values(column).unwrap_or(source.table.data.len());
+                return 1.0 / distinct(a).max(distinct(b)).max(1) as f64;
+            }
+        }
+    }
+    selectivity(part, &|name| find(sources, name).and_then(|(source, column)| source.column_stats(column)))
+}
+
+// Rows read to find `output` rows from `left` coming in: a lookup for each, or the whole joined
+// table to hash or to scan for each
+fn join_cost(left: usize, output: usize, join: &Join) -> f64 {
+    let (left, right, output) = (left as f64, join.source.table.data.len() as f64, output as f64);
+    match join.strategy {
+        JoinStrategy::KeyLookup { .. } => left,
+        JoinStrategy::IndexLookup { .. } => left + output,
+        JoinStrategy::HashJoin { .. } => right + left,
+        JoinStrategy::NestedLoop => left * right,
+    }
+}
+
+fn index_values(store: &IndexStore) -> usize {
+    match store {
+        IndexStore::Text(map) => map.len(),
+        IndexStore::Number(map) => map.len(),
+        IndexStore::Boolean(map) => map.len(),
+    }
+}
+
+fn conjuncts(expr: &Expr) -> Vec<&Expr> {
+    match expr {
+        Expr::Binary { left, op: BinaryOp::And, right } => {
+            let mut parts = conjuncts(left);
+            parts.extend(conjuncts(right));
+            parts
+        },
+        other => vec![other],
+    }
+}
+
+// `base` is what every row starts from, which is the enclosing query's row in a correlated subquery
+pub fn join_rows(context: &mut dyn EvalContext, left_rows: Vec<Value>, left_columns: &[String], base: &Value, join: &Join) -> Result<Vec<Value>, String> {
+    let table = &join.source.table;
+    let hashed: HashMap<Value, Vec<Value>> = match &join.strategy {
+        JoinStrategy::HashJoin { column, .. } => {
+            let mut hashed: HashMap<Value, Vec<Value>> = HashMap::new();
+            for (key, row) in sorted_rows(table) {
+                if let Some(value) = row.get(column).and_then(join_key) {
+                    hashed.entry(value).or_default().push(key.clone());
+                }
+            }
+            hashed
+        },
+        _ => HashMap::new(),
+    };
+    let all_keys: Vec<Value> = sorted_rows(table).into_iter().map(|(k, _)| k.clone()).collect();
+
+    let mut joined = vec![];
+    let mut matched: HashSet<Value> = HashSet::new();
+    for left in left_rows {
+        let candidates: Vec<Value> = match &join.strategy {
+            JoinStrategy::KeyLookup { probe, .. } => {
+                let value = evaluate_in(probe, &left, context)?;
+                join_key(&value).filter(|k| table.data.contains_key(k)).into_iter().collect()
+            },
+            JoinStrategy::IndexLookup { column, probe } => {
+                let value = evaluate_in(probe, &left, context)?;
+                let mut keys = match table.indexes.get(column) {
+                    Some(index) => index_lookup(&index.index_data, &value),
+                    None => vec![],
+                };
+                keys.sort_by(compare_keys);
+                keys
+            },
+            JoinStrategy::HashJoin { probe, .. } => {
+                let value = evaluate_in(probe, &left, context)?;
+                join_key(&value).and_then(|k| hashed.get(&k)).cloned().unwrap_or_default()
+            },
+            JoinStrategy::NestedLoop => all_keys.clone(),
+        };
+
+        let mut found = false;
+        for key in candidates {
+            let Some(row) = table.data.get(&key) else { continue };
+            let combined = combine(&left, &join.source.qualify_row(row));
+            if truth(&evaluate_in(&join.on, &combined, context)?)? == Some(true) {
+                found = true;
+                matched.insert(key);
+                joined.push(combined);
+            }
+        }
+        if !found && matches!(join.kind, JoinKind::Left | JoinKind::Full) {
+            joined.push(combine(&left, &null_row(&join.source.columns())));
+        }
+    }
+
+    // Joined rows that nothing matched come last, with NULL for every earlier table
+    if matches!(join.kind, JoinKind::Right | JoinKind::Full) {
+        let empty_left = combine(base, &null_row(left_columns));
+        for (key, row) in sorted_rows(table) {
+            if !matched.contains(key) {
+                joined.push(combine(&empty_left, &join.source.qualify_row(row)));
+            }
+        }
+    }
+    Ok(joined)
+}
+
+pub fn sorted_rows(table: &Table) -> Vec<(&Value, &Value)> {
+    let mut rows: Vec<(&Value, &Value)> = table.data.iter().collect();
+    rows.sort_by(|a, b| compare_keys(a.0, b.0));
+    rows
+}
+
+fn null_row(columns: &[String]) -> Value {
+    Value::Object(columns.iter().map(|c| (c.clone(), Value::Null)).collect())
+}
+
+pub fn combine(left: &Value, right: &Value) -> Value {
+    let mut map = left.as_object().cloned().unwrap_or_default();
+    if let Value::Object(right) = right {
+        map.extend(right.iter().map(|(k, v)| (k.clone(), v.clone())));
+    }
+    Value::Object(map)
+}
+
+// NULL never joins
+fn join_key(value: &Value) -> Option<Value> {
+    if value.is_null() { None } else { Some(normalize_value(value)) }
+}
+
+// Whole numbers are the same however they were stored
+pub fn normalize_value(value: &Value) -> Value {
+    match value {
+        Value::Number(n) if n.as_i64().is_none() => match n.as_f64() {
+            Some(f) if f.fract() == 0.0 && f.abs() < i64::MAX as f64 => Value::from(f as i64),
+            _ => value.clone(),
+        },
+        other => other.clone(),
+    }
+}
+
+// Primary keys of the rows the index holds for `value`
+fn index_lookup(store: &IndexStore, value: &Value) -> Vec<Value> {
+    let found = match (store, value) {
+        (IndexStore::Text(map), Value::String(s)) => map.get(s),
+        (IndexStore::Number(map), Value::Number(n)) => match n.as_i64() {
+            Some(i) => map.get(&IndexNumber::Int(i)),
+            None => n.as_f64().and_then(|f| map.get(&IndexNumber::Float(OrderedFloat(f)))),
+        },
+        (IndexStore::Boolean(map), Value::Bool(b)) => map.get(b),
+        _ => None,
+    };
+    found.cloned().unwrap_or_default()
+}
@@ -1 +1,19 @@
 pub mod select;
+
+pub mod information_schema;
+
+pub mod expr;
+
+pub mod join;
+
+pub mod statement;
+
+pub mod query;
+
+pub mod functions;
+
+pub mod window;
+
+pub mod explain;
+
+pub mod stats;
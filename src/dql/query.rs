@@ -0,0 +1,949 @@
+use std::{collections::{BTreeSet, HashMap, HashSet}, rc::Rc, time::Instant};
+
+use serde_json::{Map, Value};
+
+use crate::{catalog::{load_catalog, resolve_table_name, split_table_name}, ddl::sequence::{is_sequence_function, Sequences}, dql::{explain::{ExplainNode, OperatorStats}, expr::{evaluate_in, tokenize, truth, EvalContext, Expr, Parser, Token}, functions::check_call, information_schema::{is_virtual_table, virtual_table}, join::{choose_strategy, combine, estimate_join, join_rows, normalize_value, order_joins, sorted_rows, Join, JoinStrategy, Source}, select::{build_query, evaluate_query, SelectReturn}, stats::{selectivity, table_stats}, statement::{parse_query, parse_stored_query, Cte, JoinKind, Query, SelectItem, SelectStatement, SetExpr, SetOperator, TableRef}, window::{check_window, evaluate_window, window_column}}, models::{FieldDataType, FieldDef, Table, TableStats, View}, storage::StorageBackend};
+
+// Queries the single-table select can't answer: joins, subqueries, set operations, WITH and
+// any expression beyond `<column> <op> <value>`.
+// Rows are JSON objects keyed <alias>.<column> while the query runs; output columns only keep
+// the alias when more than one table is involved.
+
+// A statement with its tables loaded, columns qualified and joins given a strategy
+pub struct Plan {
+    pub distinct: bool,
+    pub columns: Vec<FieldDef>,
+    pub items: Vec<Expr>,
+    pub from: Source,
+    pub joins: Vec<Join>,
+    pub where_clause: Option<Expr>,
+    // Window functions of the select list, which reads each one's result from window_column(i)
+    pub windows: Vec<Expr>,
+    // Columns of enclosing queries this one reads. A subquery without any runs only once.
+    pub outer_refs: BTreeSet<String>,
+}
+
+pub struct QueryContext<'a> {
+    storage: &'a mut dyn StorageBackend,
+    database: String,
+    // Each table is loaded once, however many times the statement scans it, with its stats
+    tables: HashMap<String, (Rc<Table>, Option<Rc<TableStats>>)>,
+    // Tables built from WITH, which hide stored tables of the same name
+    ctes: HashMap<String, Rc<Table>>,
+    // Subqueries are planned along with the statement around them, keyed by the address of
+    // their statement inside that plan
+    plans: HashMap<usize, Rc<Plan>>,
+    results: HashMap<usize, Rc<Vec<Vec<Value>>>>,
+    // Views being expanded, so one that reads itself is caught
+    expanding: Vec<String>,
+    // Set while EXPLAIN ANALYZE runs a statement, keyed by the address of each plan run
+    stats: Option<HashMap<usize, PlanStats>>,
+    // Loaded on the first nextval, currval or setval, and written by `save_sequences`
+    sequences: Option<Sequences>,
+}
+
+// What each step of one plan produced, summed over every time the plan ran
+#[derive(Default, Clone)]
+struct PlanStats {
+    scan: OperatorStats,
+    joins: Vec<OperatorStats>,
+    filter: OperatorStats,
+    windows: OperatorStats,
+    output: OperatorStats,
+}
+
+struct Scope<'s> {
+    sources: &'s [Source],
+    // Columns of enclosing queries, already qualified
+    outer: &'s [String],
+}
+
+// A recursive CTE that keeps finding rows after this many rounds is assumed to be in a cycle
+const MAX_RECURSION: usize = 1000;
+
+// The single-table select only takes `*` or a list of column names before FROM, one table after
+// it, and a WHERE it can read
+pub fn needs_query_engine(tokens: &[String]) -> bool {
+    let Some(from) = tokens.iter().position(|t| t == "FROM") else { return true };
+    let where_at = tokens.iter().position(|t| t == "WHERE").unwrap_or(tokens.len());
+    let plain_select = tokenize(&tokens[..from].join(" ")).is_ok_and(|t| is_column_list(&t));
+    let plain_from = where_at > from + 1 && tokens[from + 1..where_at].len() == 1 && is_table_name(&tokens[from + 1]);
+    !plain_select || !plain_from || (where_at < tokens.len() && !is_simple_where(&tokens[where_at + 1..]))
+}
+
+fn is_table_name(token: &str) -> bool {
+    tokenize(token).is_ok_and(|t| match t.as_slice() {
+        [Token::Ident(_)] => true,
+        [Token::Ident(_), Token::Symbol(dot), Token::Ident(_)] => dot == ".",
+        _ => false,
+    })
+}
+
+// `<column> <op> <value>` clauses joined by AND or OR, each part a word of its own
+fn is_simple_where(tokens: &[String]) -> bool {
+    tokens.len() % 4 == 3 && tokens.iter().enumerate().all(|(i, t)| match i % 4 {
+        0 => tokenize(t).is_ok_and(|t| matches!(t.as_slice(), [Token::Ident(_)])),
+        1 => ["=", "!=", ">", "<"].contains(&t.as_str()),
+        // Values are compared as written, so quoted text is left to the query engine
+        2 => !t.starts_with(['\'', '"']) && !t.contains('('),
+        _ => t == "AND" || t == "OR",
+    })
+}
+
+fn is_column_list(tokens: &[Token]) -> bool {
+    if matches!(tokens, [Token::Symbol(s)] if s == "*") {
+        return true;
+    }
+    tokens.iter().enumerate().all(|(i, t)| match t {
+        Token::Ident(word) => i % 2 == 0 && !["DISTINCT", "ALL"].iter().any(|k| word.eq_ignore_ascii_case(k)),
+        Token::Symbol(s) => i % 2 == 1 && s == ",",
+        _ => false,
+    })
+}
+
+// `tokens` are the ones after SELECT, or a whole statement starting with WITH
+pub fn select_query(storage: &mut dyn StorageBackend, database: &str, tokens: Vec<String>) -> Result<SelectReturn, String> {
+    let query = parse_query(&tokens)?;
+    let mut context = QueryContext::new(storage, database);
+    let (columns, rows) = context.run_query(&query)?;
+    context.save_sequences()?;
+    select_return(columns, rows)
+}
+
+// CTEs, and views the query reads, are run even without ANALYZE, as the plan needs their columns
+pub fn explain_query(storage: &mut dyn StorageBackend, database: &str, tokens: Vec<String>, analyze: bool) -> Result<ExplainNode, String> {
+    let query = parse_query(&tokens)?;
+    let mut context = QueryContext::new(storage, database);
+    let mut ctes = vec![];
+    for cte in &query.with {
+        let started = Instant::now();
+        let table = context.materialize(cte, query.recursive)?;
+        let mut node = ExplainNode::new(format!("CTE {}", cte.name));
+        node.details.push(format!("Query: {}", cte.query));
+        node.actual = Some(OperatorStats::once(table.data.len(), started)).filter(|_| analyze);
+        ctes.push(node);
+        context.ctes.insert(cte.name.clone(), Rc::new(table));
+    }
+    let mut nodes = vec![];
+    context.explain_set(&query.body, analyze, &mut nodes)?;
+    let mut node = nodes.pop().ok_or("Nothing to explain")?;
+    node.children.extend(ctes);
+    context.save_sequences()?;
+    Ok(node)
+}
+
+// The rows of the view stored under `name`, or None when there isn't one. Materialized views
+// are read like any table, so they give None too.
+pub fn view_table(storage: &mut dyn StorageBackend, name: &str) -> Result<Option<Table>, String> {
+    match load_catalog(storage)?.view(name).filter(|v| !v.materialized).cloned() {
+        Some(view) => run_view(storage, name, &view).map(Some),
+        None => Ok(None),
+    }
+}
+
+// Runs a view's query whether or not it is materialized. Columns that only gave NULLs are TEXT.
+pub fn run_view(storage: &mut dyn StorageBackend, name: &str, view: &View) -> Result<Table, String> {
+    let mut context = QueryContext::new(storage, &split_table_name(name).0);
+    let mut table = context.expand_view(name, view)?;
+    for field in table.schema.iter_mut() {
+        field.data_type.get_or_insert(FieldDataType::TEXT);
+    }
+    Ok(table)
+}
+
+// Rows of `table` matching `WHERE <expr>`, keyed by primary key, for UPDATE and DELETE.
+// WHERE clauses the single-table select can read still go through it.
+pub fn matching_rows(storage: &mut dyn StorageBackend, table: &Table, tokens: Vec<String>) -> Result<HashMap<Value, Value>, String> {
+    if tokens.first().is_some_and(|t| t == "WHERE") && is_simple_where(&tokens[1..]) {
+        let mut query = vec!["*".to_string(), "FROM".to_string(), table.name.clone()];
+        query.extend(tokens);
+        let stats = table_stats(storage, &table.name);
+        return Ok(evaluate_query(table, &build_query(query), stats.as_ref()));
+    }
+    let mut parser = Parser::new(tokenize(&tokens.join(" "))?);
+    parser.expect_keyword("WHERE")?;
+    let mut expr = parser.parse_expr()?;
+    parser.expect_end()?;
+
+    let (database, name) = split_table_name(&table.name);
+    let mut context = QueryContext::new(storage, &database);
+    let stats = table_stats(context.storage, &table.name).map(Rc::new);
+    let sources = [Source { table: Rc::new(table.clone()), alias: name, stats }];
+    context.bind(&mut expr, &Scope { sources: &sources, outer: &[] }, true, &mut BTreeSet::new())?;
+    let mut matched = HashMap::new();
+    for (key, row) in table.data.iter() {
+        if truth(&evaluate_in(&expr, &sources[0].qualify_row(row), &mut context)?)? == Some(true) {
+            matched.insert(key.clone(), row.clone());
+        }
+    }
+    context.save_sequences()?;
+    Ok(matched)
+}
+
+// Results have no key of their own, so rows are numbered in the order they were produced
+fn select_return(mut columns: Vec<FieldDef>, rows: Vec<Vec<Value>>) -> Result<SelectReturn, String> {
+    infer_types(&mut columns, &rows);
+    // Rows are looked up by column name, so each name can only be used once
+    for (i, column) in columns.iter().enumerate() {
+        if columns[..i].iter().any(|c| c.name == column.name) {
+            return Err(format!("Column {} is selected more than once; give one an alias", column.name));
+        }
+    }
+    // Only NULLs gave no type to go by
+    for column in columns.iter_mut() {
+        column.data_type.get_or_insert(FieldDataType::TEXT);
+    }
+    let filtered = rows.into_iter().enumerate().map(|(i, values)| {
+        let row: Map<String, Value> = columns.iter().map(|c| c.name.clone()).zip(values).collect();
+        (Value::from(i as u64 + 1), Value::Object(row))
+    }).collect();
+    Ok(SelectReturn { filtered, missing: vec![], schema: columns.into_iter().map(|f| (true, f)).collect() })
+}
+
+// Subquery columns take their type from the values they gave
+fn infer_types(columns: &mut [FieldDef], rows: &[Vec<Value>]) {
+    for (i, column) in columns.iter_mut().enumerate() {
+        if column.data_type.is_none() {
+            column.data_type = rows.iter().map(|r| &r[i]).find(|v| !v.is_null()).map(|v| match v {
+                Value::Number(_) => FieldDataType::NUMBER,
+                Value::Bool(_) => FieldDataType::BOOLEAN,
+                _ => FieldDataType::TEXT,
+            });
+        }
+    }
+}
+
+impl<'a> QueryContext<'a> {
+    pub fn new(storage: &'a mut dyn StorageBackend, database: &str) -> QueryContext<'a> {
+        QueryContext { storage, database: database.to_string(), tables: HashMap::new(), ctes: HashMap::new(), plans: HashMap::new(), results: HashMap::new(), expanding: vec![], stats: None, sequences: None }
+    }
+
+    // Keeps the sequence values the statement handed out
+    pub fn save_sequences(self) -> Result<(), String> {
+        match self.sequences {
+            Some(sequences) => sequences.save(self.storage),
+            None => Ok(()),
+        }
+    }
+
+    fn source(&mut self, table: &TableRef) -> Result<Source, String> {
+        if let Some(cte) = self.ctes.get(&table.name) {
+            return Ok(Source { table: cte.clone(), alias: table.alias().to_string(), stats: None });
+        }
+        let resolved = resolve_table_name(&table.name, &self.database);
+        let (loaded, stats) = match self.tables.get(&resolved) {
+            Some(loaded) => loaded.clone(),
+            None => {
+                let catalog = load_catalog(self.storage)?;
+                let view = catalog.view(&resolved).filter(|v| !v.materialized).cloned();
+                let stats = catalog.stats(&resolved).cloned().map(Rc::new);
+                let (loaded, stats) = if is_virtual_table(&resolved) {
+                    (virtual_table(self.storage, &resolved)?, None)
+                } else if let Some(view) = view {
+                    (self.expand_view(&resolved, &view)?, None)
+                } else {
+                    (self.storage.load_table(&resolved)?, stats)
+                };
+                let loaded = (Rc::new(loaded), stats);
+                self.tables.insert(resolved, loaded.clone());
+                loaded
+            },
+        };
+        Ok(Source { table: loaded, alias: table.alias().to_string(), stats })
+    }
+
+    pub fn run_query(&mut self, query: &Query) -> Result<(Vec<FieldDef>, Vec<Vec<Value>>), String> {
+        for cte in &query.with {
+            let table = self.materialize(cte, query.recursive)?;
+            self.ctes.insert(cte.name.clone(), Rc::new(table));
+        }
+        self.run_set(&query.body)
+    }
+
+    pub fn run_set(&mut self, set: &SetExpr) -> Result<(Vec<FieldDef>, Vec<Vec<Value>>), String> {
+        match set {
+            SetExpr::Select(statement) => {
+                let plan = self.plan(statement, &[])?;
+                let rows = self.run(&plan, &Value::Object(Map::new()))?;
+                Ok((plan.columns, rows))
+            },
+            SetExpr::Operation { op, all, left, right } => {
+                let (mut columns, left_rows) = self.run_set(left)?;
+                let (mut right_columns, right_rows) = self.run_set(right)?;
+                infer_types(&mut columns, &left_rows);
+                infer_types(&mut right_columns, &right_rows);
+                check_compatible(*op, &mut columns, &right_columns)?;
+                Ok((columns, combine_sets(*op, *all, left_rows, right_rows)))
+            },
+        }
+    }
+
+    // Runs a CTE into a temporary table, numbered like any table without a primary key
+    fn materialize(&mut self, cte: &Cte, recursive: bool) -> Result<Table, String> {
+        let (columns, rows) = match &cte.query {
+            SetExpr::Operation { op: SetOperator::Union, all, left, right } if recursive => self.recurse(cte, left, right, *all)?,
+            query => self.run_set(query)?,
+        };
+        let mut schema = derived_schema(&cte.name, &cte.columns, columns)?;
+        infer_types(&mut schema, &rows);
+        Ok(derived_table(&cte.name, &schema, rows))
+    }
+
+    // Runs a view's query into a table the same way. It sees stored tables only, and names
+    // them from the view's own database.
+    fn expand_view(&mut self, name: &str, view: &View) -> Result<Table, String> {
+        if self.expanding.iter().any(|n| n == name) {
+            return Err(format!("View {} reads from itself", name));
+        }
+        let query = parse_stored_query(&view.query)?;
+        let ctes = std::mem::take(&mut self.ctes);
+        let database = std::mem::replace(&mut self.database, split_table_name(name).0);
+        self.expanding.push(name.to_string());
+        let result = self.run_query(&query);
+        self.expanding.pop();
+        self.database = database;
+        self.ctes = ctes;
+
+        let (columns, rows) = result?;
+        let mut schema = derived_schema(name, &[], columns)?;
+        infer_types(&mut schema, &rows);
+        Ok(derived_table(name, &schema, rows))
+    }
+
+    // Runs `step` over the rows the last round found, under the CTE's name, until a round finds
+    // none. UNION drops rows already found, which is also what stops a cycle.
+    fn recurse(&mut self, cte: &Cte, anchor: &SetExpr, step: &SetExpr, all: bool) -> Result<(Vec<FieldDef>, Vec<Vec<Value>>), String> {
+        let (mut columns, mut rows) = self.run_set(anchor)?;
+        infer_types(&mut columns, &rows);
+        let schema = derived_schema(&cte.name, &cte.columns, columns.clone())?;
+        let mut seen: HashSet<Vec<Value>> = HashSet::new();
+        if !all {
+            rows.retain(|row| seen.insert(row_key(row)));
+        }
+
+        let mut working = rows.clone();
+        let mut rounds = 0;
+        while !working.is_empty() {
+            rounds += 1;
+            if rounds > MAX_RECURSION {
+                return Err(format!("{} still found rows after {} rounds; use UNION instead of UNION ALL if rows repeat", cte.name, MAX_RECURSION));
+            }
+            self.ctes.insert(cte.name.clone(), Rc::new(derived_table(&cte.name, &schema, working)));
+            let (mut step_columns, mut found) = self.run_set(step)?;
+            infer_types(&mut step_columns, &found);
+            check_compatible(SetOperator::Union, &mut columns, &step_columns)?;
+            if !all {
+                found.retain(|row| seen.insert(row_key(row)));
+            }
+            rows.extend(found.iter().cloned());
+            working = found;
+        }
+        self.ctes.remove(&cte.name);
+        Ok((columns, rows))
+    }
+
+    // `outer` lists the columns of enclosing queries the statement can refer to
+    pub fn plan(&mut self, statement: &SelectStatement, outer: &[String]) -> Result<Plan, String> {
+        let mut sources = match &statement.from {
+            Some(from) => vec![self.source(from)?],
+            None => vec![Source { table: Rc::new(derived_table("", &[], vec![vec![]])), alias: String::new(), stats: None }],
+        };
+        for join in &statement.joins {
+            let source = self.source(&join.table)?;
+            if sources.iter().any(|s| s.alias == source.alias) {
+                return Err(format!("Table name {} is used more than once; give one an alias", source.alias));
+            }
+            sources.push(source);
+        }
+
+        let mut outer_refs = BTreeSet::new();
+        let mut ons = vec![];
+        for (i, join) in statement.joins.iter().enumerate() {
+            // ON only sees the tables joined so far
+            let mut on = join.on.clone();
+            self.bind(&mut on, &Scope { sources: &sources[..i + 2], outer }, false, &mut outer_refs)?;
+            ons.push(on);
+        }
+        let scope = Scope { sources: &sources, outer };
+        let where_clause = match &statement.where_clause {
+            Some(expr) => {
+                let mut expr = expr.clone();
+                self.bind(&mut expr, &scope, true, &mut outer_refs)?;
+                Some(expr)
+            },
+            None => None,
+        };
+
+        let qualify = sources.len() > 1;
+        let mut columns: Vec<FieldDef> = vec![];
+        let mut items: Vec<Expr> = vec![];
+        let mut windows: Vec<Expr> = vec![];
+        for item in &statement.items {
+            let selected: Vec<&Source> = match item {
+                SelectItem::All if statement.from.is_none() => return Err("SELECT * needs a table in FROM".to_string()),
+                SelectItem::All => sources.iter().collect(),
+                SelectItem::AllOf(alias) => match sources.iter().find(|s| &s.alias == alias) {
+                    Some(source) => vec![source],
+                    None => return Err(format!("Table {} not found in FROM", alias)),
+                },
+                SelectItem::Expr { expr, alias } => {
+                    let mut bound = expr.clone();
+                    self.bind(&mut bound, &scope, false, &mut outer_refs)?;
+                    // Computed columns are named as written, and typed by the values they give
+                    let mut field = match &bound {
+                        Expr::Column(name) => output_field(&sources, name, qualify),
+                        _ => FieldDef { name: expr.to_string(), data_type: None, primary_key: false, serial: None },
+                    };
+                    if let Some(alias) = alias {
+                        field.name = alias.clone();
+                    }
+                    extract_windows(&mut bound, &mut windows, false)?;
+                    columns.push(field);
+                    items.push(bound);
+                    continue;
+                },
+            };
+            for source in selected {
+                for field in source.table.schema.iter() {
+                    let name = format!("{}.{}", source.alias, field.name);
+                    columns.push(output_field(&sources, &name, qualify));
+                    items.push(Expr::Column(name));
+                }
+            }
+        }
+
+        // Subqueries in ON are planned where they are, so those joins stay as written
+        let (sources, ons) = if statement.joins.iter().all(|j| j.kind == JoinKind::Inner) && !ons.iter().any(|on| on.has_subquery()) {
+            order_joins(sources, ons)
+        } else {
+            (sources, ons)
+        };
+        let mut sources = sources.into_iter();
+        let from = sources.next().ok_or("Expected a table")?;
+        let joins = sources.zip(&statement.joins).zip(ons).map(|((source, clause), on)| {
+            let strategy = choose_strategy(&on, &source);
+            Join { kind: clause.kind, source, on, strategy }
+        }).collect();
+        Ok(Plan { distinct: statement.distinct, columns, items, from, joins, where_clause, windows, outer_refs })
+    }
+
+    // Qualifies every column. With `bare_words`, an unqualified name that isn't a column is text,
+    // the same as in single-table WHERE clauses.
+    fn bind(&mut self, expr: &mut Expr, scope: &Scope, bare_words: bool, outer_refs: &mut BTreeSet<String>) -> Result<(), String> {
+        match expr {
+            Expr::Literal(_) => Ok(()),
+            Expr::Column(name) => {
+                match scope.resolve(name) {
+                    Ok((resolved, is_outer)) => {
+                        if is_outer {
+                            outer_refs.insert(resolved.clone());
+                        }
+                        *name = resolved;
+                    },
+                    Err(_) if bare_words && !name.contains('.') && !scope.mentions(name) => {
+                        let text = Value::String(name.clone());
+                        *expr = Expr::Literal(text);
+                    },
+                    Err(e) => return Err(e),
+                }
+                Ok(())
+            },
+            Expr::Unary { expr, .. } | Expr::IsNull { expr, .. } => self.bind(expr, scope, bare_words, outer_refs),
+            Expr::Binary { left, right, .. } => {
+                self.bind(left, scope, bare_words, outer_refs)?;
+                self.bind(right, scope, bare_words, outer_refs)
+            },
+            Expr::Function { name, args } => {
+                if !is_sequence_function(name) {
+                    check_call(name, args.len())?;
+                }
+                args.iter_mut().try_for_each(|arg| self.bind(arg, scope, bare_words, outer_refs))
+            },
+            Expr::InList { expr, list, .. } => {
+                self.bind(expr, scope, bare_words, outer_refs)?;
+                list.iter_mut().try_for_each(|item| self.bind(item, scope, bare_words, outer_refs))
+            },
+            Expr::InSubquery { expr, query, .. } => {
+                self.bind(expr, scope, bare_words, outer_refs)?;
+                self.plan_subquery(query, scope, true, outer_refs)
+            },
+            Expr::Exists(query) => self.plan_subquery(query, scope, false, outer_refs),
+            Expr::Subquery(query) => self.plan_subquery(query, scope, true, outer_refs),
+            Expr::Cast { expr, .. } => self.bind(expr, scope, bare_words, outer_refs),
+            Expr::Case { operand, branches, else_result } => {
+                if let Some(operand) = operand {
+                    self.bind(operand, scope, bare_words, outer_refs)?;
+                }
+                for (condition, result) in branches {
+                    self.bind(condition, scope, bare_words, outer_refs)?;
+                    self.bind(result, scope, bare_words, outer_refs)?;
+                }
+                match else_result {
+                    Some(expr) => self.bind(expr, scope, bare_words, outer_refs),
+                    None => Ok(()),
+                }
+            },
+            Expr::Window { name, args, .. } => {
+                check_window(name, args.len())?;
+                expr.children_mut().into_iter().try_for_each(|child| self.bind(child, scope, bare_words, outer_refs))
+            },
+        }
+    }
+
+    fn plan_subquery(&mut self, query: &SelectStatement, scope: &Scope, single_column: bool, outer_refs: &mut BTreeSet<String>) -> Result<(), String> {
+        // Tables of the subquery hide enclosing tables with the same alias
+        let mut visible: Vec<String> = scope.sources.iter().flat_map(|s| s.columns()).collect();
+        visible.extend(scope.outer.iter().filter(|c| !scope.sources.iter().any(|s| c.starts_with(&format!("{}.", s.alias)))).cloned());
+        let plan = self.plan(query, &visible)?;
+        if single_column && plan.columns.len() != 1 {
+            return Err(format!("Subquery must return one column, not {}", plan.columns.len()));
+        }
+        // Columns of this query's own tables don't make it correlated
+        outer_refs.extend(plan.outer_refs.iter().filter(|c| scope.outer.contains(c)).cloned());
+        // A statement planned again may have been run with different tables before
+        let key = query as *const SelectStatement as usize;
+        self.results.remove(&key);
+        self.plans.insert(key, Rc::new(plan));
+        Ok(())
+    }
+
+    // `outer` is the enclosing query's row, empty at the top level
+    pub fn run(&mut self, plan: &Plan, outer: &Value) -> Result<Vec<Vec<Value>>, String> {
+        let mut stats = PlanStats { joins: vec![OperatorStats::default(); plan.joins.len()], ..PlanStats::default() };
+        let started = Instant::now();
+        let mut columns = plan.from.columns();
+        let mut rows: Vec<Value> = sorted_rows(&plan.from.table).into_iter()
+            .map(|(_, row)| combine(outer, &plan.from.qualify_row(row)))
+            .collect();
+        stats.scan.record(rows.len(), started);
+        for (join, join_stats) in plan.joins.iter().zip(stats.joins.iter_mut()) {
+            let started = Instant::now();
+            rows = join_rows(self, rows, &columns, outer, join)?;
+            columns.extend(join.source.columns());
+            join_stats.record(rows.len(), started);
+        }
+
+        if let Some(expr) = &plan.where_clause {
+            let started = Instant::now();
+            let mut kept = vec![];
+            for row in rows {
+                if truth(&evaluate_in(expr, &row, self)?)? == Some(true) {
+                    kept.push(row);
+                }
+            }
+            rows = kept;
+            stats.filter.record(rows.len(), started);
+        }
+
+        let started = Instant::now();
+        for (i, window) in plan.windows.iter().enumerate() {
+            let Expr::Window { name, args, spec } = window else { continue };
+            let values = evaluate_window(self, name, args, spec, &rows)?;
+            for (row, value) in rows.iter_mut().zip(values) {
+                row[window_column(i)] = value;
+            }
+        }
+        stats.windows.record(rows.len(), started);
+
+        let started = Instant::now();
+        let mut output = vec![];
+        let mut seen: HashSet<Vec<Value>> = HashSet::new();
+        for row in rows {
+            let values = plan.items.iter().map(|item| evaluate_in(item, &row, self)).collect::<Result<Vec<Value>, String>>()?;
+            if !plan.distinct || seen.insert(row_key(&values)) {
+                output.push(values);
+            }
+        }
+        stats.output.record(output.len(), started);
+
+        if let Some(recorded) = self.stats.as_mut() {
+            let entry = recorded.entry(plan as *const Plan as usize).or_insert_with(|| PlanStats { joins: vec![OperatorStats::default(); plan.joins.len()], ..PlanStats::default() });
+            entry.scan.add(&stats.scan);
+            entry.joins.iter_mut().zip(&stats.joins).for_each(|(total, join)| total.add(join));
+            entry.filter.add(&stats.filter);
+            entry.windows.add(&stats.windows);
+            entry.output.add(&stats.output);
+        }
+        Ok(output)
+    }
+
+    // Like run_set, but adds the plan tree EXPLAIN prints to `nodes`. Each statement is only run,
+    // with its steps measured, when `analyze` is set.
+    fn explain_set(&mut self, set: &SetExpr, analyze: bool, nodes: &mut Vec<ExplainNode>) -> Result<(Vec<FieldDef>, Vec<Vec<Value>>), String> {
+        match set {
+            SetExpr::Select(statement) => {
+                let plan = self.plan(statement, &[])?;
+                let mut rows = vec![];
+                if analyze {
+                    // Addresses are only told apart while the plans they belong to are alive
+                    self.stats = Some(HashMap::new());
+                    let result = self.run(&plan, &Value::Object(Map::new()));
+                    if result.is_err() {
+                        self.stats = None;
+                    }
+                    rows = result?;
+                }
+                nodes.push(self.describe(&plan));
+                self.stats = None;
+                Ok((plan.columns, rows))
+            },
+            SetExpr::Operation { op, all, left, right } => {
+                let mut children = vec![];
+                let (mut columns, left_rows) = self.explain_set(left, analyze, &mut children)?;
+                let (mut right_columns, right_rows) = self.explain_set(right, analyze, &mut children)?;
+                infer_types(&mut columns, &left_rows);
+                infer_types(&mut right_columns, &right_rows);
+                check_compatible(*op, &mut columns, &right_columns)?;
+                let started = Instant::now();
+                let rows = combine_sets(*op, *all, left_rows, right_rows);
+                let mut node = ExplainNode::new(if *all { format!("{} ALL", op) } else { op.to_string() });
+                if analyze {
+                    node.actual = Some(OperatorStats::once(rows.len(), started));
+                }
+                node.children = children;
+                nodes.push(node);
+                Ok((columns, rows))
+            },
+        }
+    }
+
+    // Project over Window over Filter over the joins, each over the steps before it, with the
+    // scan of FROM at the bottom. Subqueries hang off the step that evaluates them.
+    fn describe(&self, plan: &Plan) -> ExplainNode {
+        let stats = self.stats.as_ref().and_then(|s| s.get(&(plan as *const Plan as usize))).cloned();
+        let mut node = self.describe_scan(&plan.from);
+        node.actual = stats.as_ref().map(|s| s.scan);
+        let sources: Vec<Source> = std::iter::once(&plan.from).chain(plan.joins.iter().map(|j| &j.source)).cloned().collect();
+        for (i, join) in plan.joins.iter().enumerate() {
+            let estimate = node.estimated_rows.map(|left| estimate_join(&sources, left, join));
+            let mut join_node = ExplainNode::new(format!("{} {} using {}", join.kind, join.source.alias, describe_strategy(&join.strategy, &join.source.alias)));
+            join_node.details.push(format!("On: {}", join.on));
+            join_node.estimated_rows = estimate;
+            join_node.actual = stats.as_ref().map(|s| s.joins[i]);
+            join_node.children = vec![node, self.describe_join_source(join)];
+            join_node.children.extend(self.describe_subqueries([&join.on]));
+            node = join_node;
+        }
+
+        if let Some(expr) = &plan.where_clause {
+            let mut filter = ExplainNode::new("Filter");
+            filter.details.push(format!("Condition: {}", expr));
+            let column = |name: &str| {
+                let (alias, column) = name.split_once('.')?;
+                sources.iter().find(|s| s.alias == alias)?.column_stats(column)
+            };
+            filter.estimated_rows = node.estimated_rows.map(|rows| (rows as f64 * selectivity(expr, &column)).round() as usize);
+            filter.actual = stats.as_ref().map(|s| s.filter);
+            filter.children = vec![node];
+            filter.children.extend(self.describe_subqueries([expr]));
+            node = filter;
+        }
+
+        if !plan.windows.is_empty() {
+            let mut window = ExplainNode::new("Window");
+            window.details.extend(plan.windows.iter().map(|w| w.to_string()));
+            window.estimated_rows = node.estimated_rows;
+            window.actual = stats.as_ref().map(|s| s.windows);
+            window.children = vec![node];
+            window.children.extend(self.describe_subqueries(&plan.windows));
+            node = window;
+        }
+
+        let mut project = ExplainNode::new(if plan.distinct { "Distinct project" } else { "Project" });
+        project.details.push(format!("Columns: {}", plan.columns.iter().map(|c| c.name.as_str()).collect::<Vec<&str>>().join(", ")));
+        project.estimated_rows = node.estimated_rows.filter(|_| !plan.distinct);
+        project.actual = stats.as_ref().map(|s| s.output);
+        project.children = vec![node];
+        project.children.extend(self.describe_subqueries(&plan.items));
+        project
+    }
+
+    fn describe_scan(&self, source: &Source) -> ExplainNode {
+        let name = &source.table.name;
+        if name.is_empty() {
+            let mut node = ExplainNode::new("Single row");
+            node.estimated_rows = Some(1);
+            return node;
+        }
+        let mut node = if self.ctes.values().any(|cte| Rc::ptr_eq(cte, &source.table)) {
+            ExplainNode::new(format!("CTE scan on {}", name))
+        } else {
+            ExplainNode::new(format!("Full scan on {}", name))
+        };
+        if &source.alias != name && !name.ends_with(&format!(".{}", source.alias)) {
+            node.label.push_str(&format!(" as {}", source.alias));
+        }
+        node.estimated_rows = Some(source.table.data.len());
+        node
+    }
+
+    // Lookups only read the rows they find, so the table isn't scanned
+    fn describe_join_source(&self, join: &Join) -> ExplainNode {
+        let mut node = self.describe_scan(&join.source);
+        match &join.strategy {
+            JoinStrategy::KeyLookup { .. } => node.label = node.label.replacen("Full scan", "Key lookup", 1),
+            JoinStrategy::IndexLookup { column, .. } => {
+                node.label = format!("{} using index on {}", node.label.replacen("Full scan", "Index lookup", 1), column);
+            },
+            JoinStrategy::HashJoin { column, .. } => node.label = format!("{}, hashed on {}", node.label, column),
+            JoinStrategy::NestedLoop => return node,
+        }
+        if !matches!(join.strategy, JoinStrategy::HashJoin { .. }) {
+            node.estimated_rows = None;
+        }
+        node
+    }
+
+    fn describe_subqueries<'e>(&self, exprs: impl IntoIterator<Item = &'e Expr>) -> Vec<ExplainNode> {
+        let mut nodes = vec![];
+        for expr in exprs {
+            for query in expr.subqueries() {
+                let Some(plan) = self.plans.get(&(query as *const SelectStatement as usize)) else { continue };
+                let label = if plan.outer_refs.is_empty() { "Subquery, run once" } else { "Correlated subquery, run for each row" };
+                let mut node = ExplainNode::new(label);
+                node.children.push(self.describe(plan));
+                nodes.push(node);
+            }
+        }
+        nodes
+    }
+}
+
+impl EvalContext for QueryContext<'_> {
+    fn call(&mut self, name: &str, args: &[Value]) -> Option<Result<Value, String>> {
+        if !is_sequence_function(name) {
+            return None;
+        }
+        if self.sequences.is_none() {
+            match Sequences::load(self.storage, &self.database) {
+                Ok(sequences) => self.sequences = Some(sequences),
+                Err(e) => return Some(Err(e)),
+            }
+        }
+        self.sequences.as_mut()?.call(name, args)
+    }
+
+    fn subquery(&mut self, query: &SelectStatement, row: &Value) -> Result<Rc<Vec<Vec<Value>>>, String> {
+        let key = query as *const SelectStatement as usize;
+        if let Some(rows) = self.results.get(&key) {
+            return Ok(rows.clone());
+        }
+        let plan = self.plans.get(&key).cloned().ok_or("Subquery was not planned")?;
+        let rows = Rc::new(self.run(&plan, row)?);
+        if plan.outer_refs.is_empty() {
+            self.results.insert(key, rows.clone());
+        }
+        Ok(rows)
+    }
+}
+
+impl Scope<'_> {
+    // The qualified name, and whether it belongs to an enclosing query
+    fn resolve(&self, name: &str) -> Result<(String, bool), String> {
+        if let Some((alias, column)) = name.split_once('.') {
+            if let Some(source) = self.sources.iter().find(|s| s.alias == alias) {
+                if !source.has_column(column) {
+                    return Err(format!("Column {} not found", name));
+                }
+                return Ok((name.to_string(), false));
+            }
+            if !self.outer.iter().any(|c| c == name) {
+                return Err(format!("Table {} not found in FROM", alias));
+            }
+            return Ok((name.to_string(), true));
+        }
+        let found: Vec<&Source> = self.sources.iter().filter(|s| s.has_column(name)).collect();
+        match found.as_slice() {
+            [source] => return Ok((format!("{}.{}", source.alias, name), false)),
+            [] => {},
+            _ => return Err(format!("Column {} is ambiguous", name)),
+        }
+        let suffix = format!(".{}", name);
+        let found: Vec<&String> = self.outer.iter().filter(|c| c.ends_with(&suffix)).collect();
+        match found.as_slice() {
+            [column] => Ok((column.to_string(), true)),
+            [] => Err(format!("Column {} not found", name)),
+            _ => Err(format!("Column {} is ambiguous", name)),
+        }
+    }
+
+    fn mentions(&self, name: &str) -> bool {
+        let suffix = format!(".{}", name);
+        self.sources.iter().any(|s| s.has_column(name)) || self.outer.iter().any(|c| c.ends_with(&suffix))
+    }
+}
+
+// The column list of a CTE, or the names its SELECT gave without their alias
+fn derived_schema(name: &str, listed: &[String], columns: Vec<FieldDef>) -> Result<Vec<FieldDef>, String> {
+    if !listed.is_empty() && listed.len() != columns.len() {
+        return Err(format!("{} lists {} columns but its SELECT gives {}", name, listed.len(), columns.len()));
+    }
+    let mut schema: Vec<FieldDef> = vec![];
+    for (i, mut field) in columns.into_iter().enumerate() {
+        field.name = match listed.get(i) {
+            Some(name) => name.clone(),
+            None => match field.name.split_once('.') {
+                Some((_, column)) if !field.name.contains(['(', ' ']) => column.to_string(),
+                _ => field.name.clone(),
+            },
+        };
+        if schema.iter().any(|f| f.name == field.name) {
+            return Err(format!("Column {} appears twice in {}; give one an alias", field.name, name));
+        }
+        schema.push(field);
+    }
+    Ok(schema)
+}
+
+// Both sides need as many columns, and types that compare. Unknown types take the other side's.
+fn check_compatible(op: SetOperator, left: &mut [FieldDef], right: &[FieldDef]) -> Result<(), String> {
+    if left.len() != right.len() {
+        return Err(format!("Each side of {} must have the same number of columns, not {} and {}", op, left.len(), right.len()));
+    }
+    // SERIAL values are numbers
+    let comparable = |data_type: &Option<FieldDataType>| match data_type {
+        Some(FieldDataType::SERIAL) => Some(FieldDataType::NUMBER),
+        other => other.clone(),
+    };
+    for (l, r) in left.iter_mut().zip(right) {
+        match (comparable(&l.data_type), comparable(&r.data_type)) {
+            (Some(a), Some(b)) if a != b => {
+                return Err(format!("Column {} is {:?} on one side of {} and {:?} on the other", l.name, a, op, b));
+            },
+            (None, Some(_)) => l.data_type = r.data_type.clone(),
+            _ => {},
+        }
+    }
+    Ok(())
+}
+
+// Without ALL, the result has each row once. With it, INTERSECT keeps a row as many times as
+// both sides have it, and EXCEPT takes off one copy for each time the right side has it.
+fn combine_sets(op: SetOperator, all: bool, left: Vec<Vec<Value>>, right: Vec<Vec<Value>>) -> Vec<Vec<Value>> {
+    if op == SetOperator::Union {
+        let mut rows = left;
+        rows.extend(right);
+        if !all {
+            let mut seen: HashSet<Vec<Value>> = HashSet::new();
+            rows.retain(|row| seen.insert(row_key(row)));
+        }
+        return rows;
+    }
+
+    let mut counts: HashMap<Vec<Value>, usize> = HashMap::new();
+    for row in &right {
+        *counts.entry(row_key(row)).or_default() += 1;
+    }
+    let mut seen: HashSet<Vec<Value>> = HashSet::new();
+    let mut kept = vec![];
+    for row in left {
+        let key = row_key(&row);
+        let in_right = counts.get(&key).copied().unwrap_or(0);
+        let keep = if all {
+            if in_right > 0 {
+                counts.insert(key, in_right - 1);
+            }
+            (op == SetOperator::Intersect) == (in_right > 0)
+        } else {
+            seen.insert(key) && (op == SetOperator::Intersect) == (in_right > 0)
+        };
+        if keep {
+            kept.push(row);
+        }
+    }
+    kept
+}
+
+// Moves each window function out of `expr` into `windows`, leaving a column that reads its result
+fn extract_windows(expr: &mut Expr, windows: &mut Vec<Expr>, inside_window: bool) -> Result<(), String> {
+    if let Expr::Window { .. } = expr {
+        if inside_window {
+            return Err("Window functions can't be nested".to_string());
+        }
+        for child in expr.children_mut() {
+            extract_windows(child, windows, true)?;
+        }
+        let column = Expr::Column(window_column(windows.len()));
+        windows.push(std::mem::replace(expr, column));
+        return Ok(());
+    }
+    expr.children_mut().into_iter().try_for_each(|child| extract_windows(child, windows, inside_window))
+}
+
+fn describe_strategy(strategy: &JoinStrategy, alias: &str) -> String {
+    match strategy {
+        JoinStrategy::KeyLookup { column, probe } => format!("key lookup on {}.{} = {}", alias, column, probe),
+        JoinStrategy::IndexLookup { column, probe } => format!("index lookup on {}.{} = {}", alias, column, probe),
+        JoinStrategy::HashJoin { column, probe } => format!("hash join on {}.{} = {}", alias, column, probe),
+        JoinStrategy::NestedLoop => "nested loop".to_string(),
+    }
+}
+
+// NULLs count as equal to each other here, unlike in `=`
+fn row_key(row: &[Value]) -> Vec<Value> {
+    row.iter().map(normalize_value).collect()
+}
+
+fn derived_table(name: &str, schema: &[FieldDef], rows: Vec<Vec<Value>>) -> Table {
+    let data = rows.into_iter().enumerate().map(|(i, values)| {
+        let row: Map<String, Value> = schema.iter().map(|f| f.name.clone()).zip(values).collect();
+        (Value::from(i as u64 + 1), Value::Object(row))
+    }).collect();
+    Table { name: name.to_string(), schema: schema.to_vec(), data, indexes: HashMap::new(), constraints: vec![] }
+}
+
+fn output_field(sources: &[Source], name: &str, qualify: bool) -> FieldDef {
+    let (alias, column) = name.split_once('.').unwrap_or(("", name));
+    // A column of an enclosing query keeps its alias, since it isn't one of these tables
+    let field = sources.iter().find(|s| s.alias == alias).and_then(|s| s.table.schema.iter().find(|f| f.name == column));
+    let name = if qualify || field.is_none() { name } else { column };
+    FieldDef { name: name.to_string(), data_type: field.and_then(|f| f.data_type.clone()), primary_key: false, serial: None }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::{ddl::create::{create, CreateData}, dml::insert::insert, storage::memory::MemoryBackend};
+
+    fn tokens(text: &str) -> Vec<String> {
+        text.split_whitespace().map(String::from).collect()
+    }
+
+    // Values of one column in row order
+    fn column(results: &SelectReturn, name: &str) -> Vec<Value> {
+        (1..=results.filtered.len() as u64).map(|i| results.filtered[&json!(i)][name].clone()).collect()
+    }
+
+    #[test]
+    fn recursive_ctes_follow_rows_until_none_are_found() {
+        let mut storage = MemoryBackend::new();
+        create(&mut storage, CreateData::Table { name: "t".to_string(), schema: tokens("id NUMBER KEY parent NUMBER") });
+        let table = storage.load_schema("t").unwrap();
+        insert(&mut storage, "main", table, tokens("VALUES (1, NULL), (2, 1), (3, 2), (4, 1), (5, 9)")).unwrap();
+
+        let query = "WITH RECURSIVE r(n) AS (SELECT id FROM t WHERE id = 1 UNION ALL SELECT t.id FROM t JOIN r ON t.parent = r.n) SELECT * FROM r";
+        let results = select_query(&mut storage, "main", tokens(query)).unwrap();
+        let mut found: Vec<i64> = column(&results, "n").iter().filter_map(|v| v.as_i64()).collect();
+        found.sort();
+        assert_eq!(found, vec![1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn selects_without_from_give_one_row() {
+        let mut storage = MemoryBackend::new();
+        let results = select_query(&mut storage, "main", tokens("1 + 2 AS x, 'a' AS y")).unwrap();
+        assert_eq!(column(&results, "x"), vec![json!(3)]);
+        assert_eq!(column(&results, "y"), vec![json!("a")]);
+        assert!(select_query(&mut storage, "main", tokens("*")).is_err());
+    }
+
+    #[test]
+    fn recursive_ctes_can_start_without_from() {
+        let mut storage = MemoryBackend::new();
+        let query = "WITH RECURSIVE r(n) AS (SELECT 1 UNION ALL SELECT n + 1 FROM r WHERE n < 5) SELECT * FROM r";
+        let results = select_query(&mut storage, "main", tokens(query)).unwrap();
+        assert_eq!(column(&results, "n"), vec![json!(1), json!(2), json!(3), json!(4), json!(5)]);
+    }
+}
@@ -1,16 +1,60 @@
 use serde_json::{self, Value, Number};
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
+use std::time::Instant;
 use std::ops::Bound::{Excluded, Unbounded};
-use crate::models::IndexNumber;
-use crate::{models::{FieldDef, IndexStore, Table}, storage::load_from_disk};
+use crate::catalog::resolve_table_name;
+use crate::dql::explain::{ExplainNode, OperatorStats};
+use crate::dql::expr::BinaryOp;
+use crate::dql::information_schema::{is_virtual_table, virtual_table};
+use crate::dql::query::{needs_query_engine, select_query, view_table};
+use crate::dql::stats::{fraction, table_stats, DEFAULT_SELECTIVITY};
+use crate::models::{IndexNumber, OrderedFloat, TableStats};
+use crate::{models::{FieldDataType, FieldDef, IndexStore, Table}, storage::StorageBackend};
 
-pub fn select(query: Vec<String>) -> SelectReturn {
-    let built_query: Query = build_query(query);
-    let mut table: Table = load_from_disk(&built_query.from);
+pub fn select(storage: &mut dyn StorageBackend, database: &str, query: Vec<String>) -> SelectReturn {
+    if needs_query_engine(&query) {
+        return match select_query(storage, database, query) {
+            Ok(results) => results,
+            Err(e) => {
+                eprintln!("Error: {}", e);
+                SelectReturn { filtered: HashMap::new(), missing: vec![], schema: vec![] }
+            }
+        };
+    }
+    let mut built_query: Query = build_query(query);
+    built_query.from = resolve_table_name(&built_query.from, database);
+    let loaded = if is_virtual_table(&built_query.from) {
+        virtual_table(storage, &built_query.from)
+    } else {
+        match view_table(storage, &built_query.from) {
+            Ok(Some(view)) => Ok(view),
+            Ok(None) => load_for_query(storage, &built_query),
+            Err(e) => Err(e),
+        }
+    };
+    let mut table: Table = match loaded {
+        Ok(t) => t,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            return SelectReturn { filtered: HashMap::new(), missing: vec![], schema: vec![] };
+        }
+    };
+
+    let stats = built_query.where_clause.as_ref().and_then(|_| table_stats(storage, &built_query.from));
+    let filtered_store: HashMap<Value, Value> = evaluate_query(&table, &built_query, stats.as_ref());
+
+    table.schema.sort_by_key(|f| !f.primary_key);
+    let sorted_schema: Vec<(bool, FieldDef)> = table.schema.clone().into_iter().map(|x| -> (bool, FieldDef) {
+        if built_query.select.contains(&x.name) || built_query.select == vec!["*".to_string()]{
+            (true, x)
+        } else {
+            (false, x)
+        }
+    }).collect();
 
-    let filtered_store: HashMap<Value, Value> = evaluate_query(&table, &built_query);
     if filtered_store.is_empty() {
-        SelectReturn { filtered: filtered_store, missing: vec![], schema: vec![] }
+        SelectReturn { filtered: filtered_store, missing: vec![], schema: sorted_schema }
     } else {
         // List missing fields
         let all_fields: HashSet<String> = table.data.values().filter_map(|v| v.as_object()).flat_map(|obj| obj.keys().cloned()).collect();
@@ -21,15 +65,6 @@ pub fn select(query: Vec<String>) -> SelectReturn {
             }
         }
 
-        table.schema.sort_by_key(|f| !f.primary_key);
-        let sorted_schema: Vec<(bool, FieldDef)> = table.schema.clone().into_iter().map(|x| -> (bool, FieldDef) {
-            if built_query.select.contains(&x.name) || built_query.select == vec!["*".to_string()]{
-                (true, x)
-            } else {
-                (false, x)
-            }
-        }).collect();
-
         SelectReturn {
             filtered: filtered_store,
             missing: missing_fields,
@@ -38,6 +73,110 @@ pub fn select(query: Vec<String>) -> SelectReturn {
     }
 }
 
+// A lone `key = value` clause only needs one row, so the rest of the table is never read
+fn load_for_query(storage: &mut dyn StorageBackend, query: &Query) -> Result<Table, String> {
+    if let Some(clauses) = &query.where_clause {
+        if clauses.len() == 1 && matches!(clauses[0].operator, Condition::Equals) {
+            let mut table = storage.load_schema(&query.from)?;
+            if let Some(key) = lookup_key(&table, query) {
+                if let Some(row) = storage.get_row(&query.from, &key)? {
+                    table.data.insert(key, row);
+                }
+                return Ok(table);
+            }
+        }
+    }
+    storage.load_table(&query.from)
+}
+
+// The primary key a lone `key = value` clause asks for
+fn lookup_key(table: &Table, query: &Query) -> Option<Value> {
+    let clauses = query.where_clause.as_ref().filter(|c| c.len() == 1 && matches!(c[0].operator, Condition::Equals))?;
+    let key_field = table.schema.iter().find(|f| f.primary_key && f.name == clauses[0].left_hand)?;
+    key_value(&key_field.data_type, &clauses[0].right_hand)
+}
+
+// The same choices select makes, without building the result unless `analyze` is set. The table
+// is still loaded, as its size and indexes are what the estimates come from.
+pub fn explain_select(storage: &mut dyn StorageBackend, database: &str, query: Vec<String>, analyze: bool) -> Result<ExplainNode, String> {
+    let mut built_query: Query = build_query(query);
+    built_query.from = resolve_table_name(&built_query.from, database);
+    let name = built_query.from.clone();
+    let stats = table_stats(storage, &name);
+    let started = Instant::now();
+    let mut node = if is_virtual_table(&name) {
+        let table = virtual_table(storage, &name)?;
+        explain_scan(&table, &built_query, None, analyze, started)
+    } else if let Some(table) = view_table(storage, &name)? {
+        let mut node = explain_scan(&table, &built_query, None, analyze, started);
+        node.details.push("View: run in full before it is read".to_string());
+        node
+    } else {
+        let schema = storage.load_schema(&name)?;
+        match lookup_key(&schema, &built_query) {
+            Some(key) => {
+                let mut node = ExplainNode::new(format!("Key lookup on {}", name));
+                node.details.push(format!("Key: {}", describe_clauses(built_query.where_clause.as_deref().unwrap_or_default())));
+                node.estimated_rows = Some(1);
+                if analyze {
+                    let found = storage.get_row(&name, &key)?;
+                    node.actual = Some(OperatorStats::once(found.iter().count(), started));
+                }
+                node
+            },
+            None => explain_scan(&storage.load_table(&name)?, &built_query, stats.as_ref(), analyze, started),
+        }
+    };
+    node.details.insert(0, format!("Columns: {}", built_query.select.join(", ")));
+    Ok(node)
+}
+
+fn explain_scan(table: &Table, query: &Query, stats: Option<&TableStats>, analyze: bool, started: Instant) -> ExplainNode {
+    let mut node = ExplainNode::new(format!("Full scan on {}", table.name));
+    match &query.where_clause {
+        Some(clauses) => {
+            let index = find_best_index(table, clauses, stats);
+            if let Some((clause, _, hits)) = index {
+                node.label = format!("Index scan on {} using index on {}", table.name, clause.left_hand);
+                node.details.push(format!("Index condition: {} {} {} (estimated rows={})", clause.left_hand, clause.operator, clause.right_hand, hits));
+            }
+            node.details.push(format!("Filter: {}", describe_clauses(clauses)));
+            node.estimated_rows = Some(estimate_rows(table, clauses, stats, index.map(|(clause, _, hits)| (clause, hits))));
+            if stats.is_none() {
+                node.details.push("No stats; run ANALYZE for better estimates".to_string());
+            }
+        },
+        None => node.estimated_rows = Some(table.data.len()),
+    }
+    if analyze {
+        let rows = evaluate_query(table, query, stats).len();
+        node.actual = Some(OperatorStats::once(rows, started));
+    }
+    node
+}
+
+fn describe_clauses(clauses: &[WhereClause]) -> String {
+    clauses.iter().map(|clause| {
+        let connector = match clause.connector {
+            Some(Connector::And) => "AND ",
+            Some(Connector::Or) => "OR ",
+            None => "",
+        };
+        format!("{}{} {} {}", connector, clause.left_hand, clause.operator, clause.right_hand)
+    }).collect::<Vec<String>>().join(" ")
+}
+
+fn key_value(data_type: &Option<FieldDataType>, hand: &HandType) -> Option<Value> {
+    match (data_type, hand) {
+        (Some(FieldDataType::TEXT), HandType::String(s)) => Some(Value::String(s.clone())),
+        (Some(FieldDataType::TEXT), HandType::Integer(i)) => Some(Value::String(i.to_string())),
+        (Some(FieldDataType::NUMBER) | Some(FieldDataType::SERIAL), HandType::Integer(i)) => Some(Value::from(*i)),
+        (Some(FieldDataType::NUMBER), HandType::Float(f)) => Number::from_f64(*f).map(Value::Number),
+        (Some(FieldDataType::BOOLEAN), HandType::Boolean(b)) => Some(Value::Bool(*b)),
+        _ => None,
+    }
+}
+
 pub struct SelectReturn {
     pub filtered: HashMap<Value, Value>,
     pub missing: Vec<String>,
@@ -54,9 +193,8 @@ pub fn build_query(query_tokens: Vec<String>) -> Query {
     let mut current_token: TokenOption = TokenOption::CurrentToken(CurrentToken::Select);
 
     for q in query_tokens {
-        if temp_where_tokens.len() > 3 {
-            finalize_where_clause(&mut temp_where_tokens, &mut where_tokens);
-        } else if temp_where_tokens.len() > 2 && !(temp_where_tokens.contains(&String::from("AND")) || temp_where_tokens.contains(&String::from("OR"))) {
+        let has_connector = temp_where_tokens.iter().any(|t| t == "AND" || t == "OR");
+        if temp_where_tokens.len() > 3 || (temp_where_tokens.len() > 2 && !has_connector) {
             finalize_where_clause(&mut temp_where_tokens, &mut where_tokens);
         }
         match q.as_str() {
@@ -72,7 +210,10 @@ pub fn build_query(query_tokens: Vec<String>) -> Query {
             }
             _ => {
                 match current_token {
-                    TokenOption::CurrentToken(CurrentToken::Select) => select_tokens.push(q),
+                    // Columns can be listed with commas, attached to either name or on their own
+                    TokenOption::CurrentToken(CurrentToken::Select) => {
+                        select_tokens.extend(q.split(',').filter(|c| !c.is_empty()).map(String::from));
+                    },
                     TokenOption::CurrentToken(CurrentToken::From) => from_tokens.push(q),
                     TokenOption::CurrentToken(CurrentToken::Where) => temp_where_tokens.push(q),
                 }
@@ -81,15 +222,14 @@ pub fn build_query(query_tokens: Vec<String>) -> Query {
     }
     finalize_where_clause(&mut temp_where_tokens, &mut where_tokens);
 
-    let query = Query {
+    Query {
         select: select_tokens,
         from: from_tokens.join(""),
         where_clause: match where_tokens.len() {
             0 => None,
             _ => Some(where_tokens),
         }
-    };
-    query
+    }
 }
 
 fn finalize_where_clause(temp_where_tokens: &mut Vec<String>, where_tokens: &mut Vec<WhereClause>) {
@@ -117,17 +257,17 @@ pub struct Query {
 }
 
 fn build_where_clause(mut where_tokens: Vec<String>) -> WhereClause {
-    let connector = if (where_tokens[0] == String::from("AND")) || (where_tokens[0] == String::from("OR")) {
+    let connector = if (where_tokens[0] == "AND") || (where_tokens[0] == "OR") {
         let found = where_tokens.remove(0);
         match found.as_str() {
-            "AND" => Some(Connector::AND),
-            _ => Some(Connector::OR)
+            "AND" => Some(Connector::And),
+            _ => Some(Connector::Or)
         }
     } else {
         None
     };
     WhereClause {
-        left_hand: where_tokens.get(0).cloned().unwrap_or_default(),
+        left_hand: where_tokens.first().cloned().unwrap_or_default(),
         operator: match where_tokens.get(1).map(|s| s.as_str()) {
             Some("=") => Condition::Equals,
             Some("!=") => Condition::NotEquals,
@@ -136,9 +276,9 @@ fn build_where_clause(mut where_tokens: Vec<String>) -> WhereClause {
             None | Some(_) => Condition::Equals,
         },
         right_hand: match where_tokens.get(2) {
-            Some(t) if t.parse::<i32>().is_ok() => HandType::Integer(i64::from(t.parse::<i64>().unwrap())),
-            Some(t) if t.parse::<f64>().is_ok() => HandType::Float(f64::from(t.parse::<f64>().unwrap())),
-            Some(t) if t.parse::<bool>().is_ok() => HandType::Boolean(bool::from(t.parse::<bool>().unwrap())),
+            Some(t) if t.parse::<i32>().is_ok() => HandType::Integer(t.parse::<i64>().unwrap()),
+            Some(t) if t.parse::<f64>().is_ok() => HandType::Float(t.parse::<f64>().unwrap()),
+            Some(t) if t.parse::<bool>().is_ok() => HandType::Boolean(t.parse::<bool>().unwrap()),
             _ => {HandType::String(where_tokens[2].clone())},
         },
         connector,
@@ -155,8 +295,8 @@ pub struct WhereClause {
 
 #[derive(Debug)]
 enum Connector {
-    AND,
-    OR,
+    And,
+    Or,
 }
 
 #[derive(Debug)]
@@ -175,276 +315,171 @@ enum Condition {
     LessThan,
 }
 
-pub fn evaluate_query(table: &Table, query: &Query) -> HashMap<Value, Value> {
+// `stats` are the table's from ANALYZE, if it has been analyzed
+pub fn evaluate_query(table: &Table, query: &Query, stats: Option<&TableStats>) -> HashMap<Value, Value> {
     let clauses = match &query.where_clause {
         Some(clauses) => clauses,
         None => return table.data.clone(),
     };
 
-    let index = find_best_index(table, clauses);
+    let index = find_best_index(table, clauses, stats);
 
     match index {
-        Some(i) => {
-            match &i.1 {
-                &IndexStore::Text(map) => {
-                    let mut found_items: Vec<(Value, Value)> = vec![];
-                    for (key, items) in map.iter() {
-                        for item in items {
-                            let val = Value::String(key.to_string());
-                            found_items.push((val.clone(), item.clone()));
-                        }
+        Some((index_clause, store, _)) => {
+            // The index narrows down the candidate rows, then every clause is checked against the full row
+            let mut output: HashMap<Value, Value> = HashMap::new();
+            for key in index_hits(store, index_clause).unwrap_or_default() {
+                if let Some(row) = table.data.get(key) {
+                    if passes_clauses(row, clauses) {
+                        output.insert(key.clone(), row.clone());
                     }
-                    let collection: HashMap<Value, Value> = found_items.into_iter()
-                        .map(|(k, v)| (v.clone(), k.clone()))
-                        .filter(|(k, v)| passes_clauses((v.clone(), k.clone()), clauses))
-                        .collect();
-                    let mut output: HashMap<Value, Value> = HashMap::new();
-                    for item in &collection {
-                        if let Some(unwrapped) = table.data.get(&item.0) {
-                            output.insert(item.0.clone(), unwrapped.clone());
-                        }
-                    }
-                    output
-                },
-                &IndexStore::Number(map) => {
-                    let mut found_items: Vec<(Value, Value)> = vec![];
-                    for (key, items) in map.iter() {
-                        for item in items {
-                            match key {
-                                IndexNumber::Int(i) => {
-                                    if let Some(int) = Number::from_i128(*i as i128) {
-                                        let val = Value::Number(int);
-                                        found_items.push((val.clone(), item.clone()))
-                                    }
-                                },
-                                IndexNumber::Float(f) => {
-                                    if let Some(float) = Number::from_f64(f.0) {
-                                        let val = Value::Number(float);
-                                        found_items.push((val.clone(), item.clone()))
-                                    } else {
-                                        println!("Error: Invalid f64 value in index: {:?}", f.0);
-                                    }
-                                }
-                            }
-                        }
-                    }
-                    let collection: HashMap<Value, Value> = found_items.into_iter()
-                        .map(|(k, v)| (v.clone(), k.clone()))
-                        .filter(|(k, v)| passes_clauses((v.clone(), k.clone()), clauses))
-                        .collect();
-                    let mut output: HashMap<Value, Value> = HashMap::new();
-                    for item in &collection {
-                        if let Some(unwrapped) = table.data.get(&item.0) {
-                            output.insert(item.0.clone(), unwrapped.clone());
-                        }
-                    }
-                    output
-                },
-                &IndexStore::Boolean(map) => {
-                    let mut found_items: Vec<(Value, Value)> = vec![];
-                    for (key, items) in map.iter() {
-                        for item in items {
-                            let val = Value::Bool(*key);
-                            found_items.push((val.clone(), item.clone()));
-                        }
-                    }
-
-                    let collection: HashMap<Value, Value> = found_items.into_iter()
-                        .map(|(k, v)| (v.clone(), k.clone()))
-                        .filter(|(k, v)| passes_clauses((v.clone(), k.clone()), clauses))
-                        .collect();
-                    let mut output: HashMap<Value, Value> = HashMap::new();
-                    for item in &collection {
-                        if let Some(unwrapped) = table.data.get(&item.0) {
-                            output.insert(item.0.clone(), unwrapped.clone());
-                        }
-                    }
-                    output
                 }
             }
+            output
         },
         None => {
-            let output = table.data.clone().into_iter().filter(|v| passes_clauses(v.clone(), clauses) ).collect();
-            output
+            table.data.iter()
+                .filter(|(_, row)| passes_clauses(row, clauses))
+                .map(|(k, v)| (k.clone(), v.clone()))
+                .collect()
         }
     }
 }
 
-fn find_best_index<'a>(table: &'a Table, clauses: &'a Vec<WhereClause>) -> Option<(&'a WhereClause, &'a IndexStore)> {
+// Reading a row through an index costs about this many rows of a full scan
+const INDEX_ROW_COST: f64 = 4.0;
+
+// The indexed clause finding the fewest rows, with how many it's expected to find, as long as
+// reading them through the index beats scanning the table
+fn find_best_index<'a>(table: &'a Table, clauses: &'a [WhereClause], stats: Option<&TableStats>) -> Option<(&'a WhereClause, &'a IndexStore, usize)> {
     let mut best: Option<(&WhereClause, &IndexStore, usize)> = None;
 
+    // With an OR in play, rows outside any single index lookup can still match
+    if clauses.iter().any(|c| matches!(c.connector, Some(Connector::Or))) {
+        return None;
+    }
+
     for clause in clauses {
         if let Some(index) = table.indexes.get(&clause.left_hand) {
-            let hits = count_hits(&index.index_data, clause);
+            // Stats answer without reading the index; without them the index gives an exact count
+            let hits = match clause_fraction(stats, clause) {
+                Some(fraction) => (fraction * table.data.len() as f64).round() as usize,
+                None => match index_hits(&index.index_data, clause) {
+                    Some(hits) => hits.len(),
+                    None => {
+                        println!("Error: WHERE condition does not match index data type");
+                        0
+                    }
+                },
+            };
 
-            if best.map_or(true, |(_, _, best_hits)| hits < best_hits) {
+            if best.is_none_or(|(_, _, best_hits)| hits < best_hits) {
                 best = Some((clause, &index.index_data, hits));
             }
         }
     }
-    best.map(|(clause, index, _)| (clause, index))
+    best.filter(|(_, _, hits)| *hits as f64 * INDEX_ROW_COST <= table.data.len() as f64)
 }
 
-fn count_hits(index: &IndexStore, clause: &WhereClause) -> usize {
-    match index {
-        IndexStore::Text(map) => {
-            match &clause.right_hand {
-                HandType::String(right) => {
-                    match clause.operator {
-                        Condition::Equals => {
-                            map.get(right).map_or(0, |rows| rows.len())
-                        },
-                        Condition::NotEquals => {
-                            // Copied from Equals, I will change this to make sense for its arm.
-                            map.iter()
-                                .filter(|(k, _)| *k != right)
-                                .map(|(_, v)| v.len())
-                                .sum::<usize>();
-                            map.get(right).map_or(0, |rows| rows.len())
-                        },
-                        Condition::GreaterThan => {
-                            map.range::<String, _>((Excluded(right), Unbounded))
-                                .map(|(_, v)| v.len())
-                                .sum::<usize>()
-                        },
-                        Condition::LessThan => {
-                            map.range::<String, _>((Unbounded, Excluded(right)))
-                                .map(|(_, v)| v.len())
-                                .sum::<usize>()
-                        }
-                    }
-                },
-                _ => {
-                    println!("Error: WHERE condition does not match index data type");
-                    return 0;
-                }
-            }
-        },
-        IndexStore::Boolean(map) => {
-            match &clause.right_hand {
-                HandType::Boolean(right) => {
-                    match clause.operator {
-                        Condition::Equals => {
-                            map.get(right).map_or(0, |rows| rows.len())
-                        },
-                        Condition::NotEquals => {
-                            map.iter()
-                                .filter(|(k, _)| k != &right)
-                                .map(|(_, v)| v.len())
-                                .sum()
-                        },
-                        _ => {0},
-                    }
-                },
-                _ => {
-                    println!("Error: WHERE condition does not match index data type");
-                    return 0;
-                }
-            }
-        },
-        IndexStore::Number(map) => {
-            match &clause.right_hand {
-                HandType::Integer(i) => {
-                    let cmp_val = *i as f64;
-                    match clause.operator {
-                        Condition::Equals => {
-                            map.iter().filter(|(k, _)| {
-                                match k {
-                                    IndexNumber::Int(i) => (*i as f64) == cmp_val,
-                                    IndexNumber::Float(f) => f.0 == cmp_val,
-                                }
-                            }).map(|(_, v)| v.len())
-                                .sum::<usize>()
-                        },
-                        Condition::NotEquals => {
-                            map.iter().filter(|(k, _)| {
-                                match k {
-                                    IndexNumber::Int(i) => (*i as f64) != cmp_val,
-                                    IndexNumber::Float(f) => f.0 != cmp_val,
-                                }
-                            }).map(|(_, v)| v.len())
-                                .sum::<usize>()
-                        },
-                        Condition::GreaterThan => {
-                            map.iter().filter(|(k, _)| {
-                                match k {
-                                    IndexNumber::Int(i) => (*i as f64) > cmp_val,
-                                    IndexNumber::Float(f) => f.0 > cmp_val,
-                                }
-                            }).map(|(_, v)| v.len())
-                                .sum::<usize>()
-                        },
-                        Condition::LessThan => {
-                            map.iter().filter(|(k, _)| {
-                                match k {
-                                    IndexNumber::Int(i) => (*i as f64) < cmp_val,
-                                    IndexNumber::Float(f) => f.0 < cmp_val,
-                                }
-                            }).map(|(_, v)| v.len())
-                                .sum::<usize>()
-                        },
-                    }
-                },
-                HandType::Float(f) => {
-                    let cmp_val = *f;
-                    match clause.operator {
-                        Condition::Equals => {
-                            map.iter().filter(|(k, _)| {
-                                match k {
-                                    IndexNumber::Int(i) => (*i as f64) == cmp_val,
-                                    IndexNumber::Float(f) => f.0 == cmp_val,
-                                }
-                            }).map(|(_, v)| v.len())
-                                .sum::<usize>()
-                        },
-                        Condition::NotEquals => {
-                            map.iter().filter(|(k, _)| {
-                                match k {
-                                    IndexNumber::Int(i) => (*i as f64) != cmp_val,
-                                    IndexNumber::Float(f) => f.0 != cmp_val,
-                                }
-                            }).map(|(_, v)| v.len())
-                                .sum::<usize>()
-                        },
-                        Condition::GreaterThan => {
-                            map.iter().filter(|(k, _)| {
-                                match k {
-                                    IndexNumber::Int(i) => (*i as f64) > cmp_val,
-                                    IndexNumber::Float(f) => f.0 > cmp_val,
-                                }
-                            }).map(|(_, v)| v.len())
-                                .sum::<usize>()
-                        },
-                        Condition::LessThan => {
-                            map.iter().filter(|(k, _)| {
-                                match k {
-                                    IndexNumber::Int(i) => (*i as f64) < cmp_val,
-                                    IndexNumber::Float(f) => f.0 < cmp_val,
-                                }
-                            }).map(|(_, v)| v.len())
-                                .sum::<usize>()
-                        },
-                    }
-                },
-                _ => {
-                    println!("Error: WHERE condition does not match index data type");
-                    return 0;
-                }
-            }
-        },
+// Primary keys of the rows whose indexed value meets the clause, read from the index in order.
+// None when the value isn't of the index's type.
+fn index_hits<'a>(index: &'a IndexStore, clause: &WhereClause) -> Option<Vec<&'a Value>> {
+    match (index, &clause.right_hand) {
+        (IndexStore::Text(map), HandType::String(right)) => Some(matching_entries(map, right, &clause.operator)),
+        (IndexStore::Number(map), HandType::Integer(i)) => Some(matching_entries(map, &IndexNumber::Int(*i), &clause.operator)),
+        (IndexStore::Number(map), HandType::Float(f)) => Some(matching_entries(map, &IndexNumber::Float(OrderedFloat(*f)), &clause.operator)),
+        (IndexStore::Boolean(map), HandType::Boolean(right)) => Some(matching_entries(map, right, &clause.operator)),
+        _ => None,
+    }
+}
+
+fn matching_entries<'a, K: Ord>(map: &'a BTreeMap<K, Vec<Value>>, right: &K, operator: &Condition) -> Vec<&'a Value> {
+    let entries: Box<dyn Iterator<Item = (&K, &Vec<Value>)>> = match operator {
+        Condition::Equals => Box::new(map.get_key_value(right).into_iter()),
+        Condition::NotEquals => Box::new(map.iter().filter(|(k, _)| *k != right)),
+        Condition::GreaterThan => Box::new(map.range((Excluded(right), Unbounded))),
+        Condition::LessThan => Box::new(map.range((Unbounded, Excluded(right)))),
+    };
+    entries.flat_map(|(_, keys)| keys).collect()
+}
+
+// Fraction of rows the clause keeps going by ANALYZE's stats, if there are any for its column
+fn clause_fraction(stats: Option<&TableStats>, clause: &WhereClause) -> Option<f64> {
+    let stats = stats?;
+    let column = stats.columns.get(&clause.left_hand)?;
+    fraction(column, stats.rows, condition_op(&clause.operator), &hand_value(&clause.right_hand))
+}
+
+// Rows the clauses keep, combined in order the way passes_clauses does. `index` is the clause an
+// index scan reads with the rows it finds.
+fn estimate_rows(table: &Table, clauses: &[WhereClause], stats: Option<&TableStats>, index: Option<(&WhereClause, usize)>) -> usize {
+    let rows = table.data.len() as f64;
+    let clause_estimate = |clause: &WhereClause| match index {
+        Some((indexed, hits)) if std::ptr::eq(indexed, clause) && rows > 0.0 => hits as f64 / rows,
+        _ => clause_fraction(stats, clause).unwrap_or(DEFAULT_SELECTIVITY),
+    };
+    let mut kept = clause_estimate(&clauses[0]);
+    for clause in clauses.iter().skip(1) {
+        let fraction = clause_estimate(clause);
+        kept = match clause.connector {
+            Some(Connector::Or) => kept + fraction - kept * fraction,
+            _ => kept * fraction,
+        };
+    }
+    (kept * rows).round() as usize
+}
+
+fn hand_value(hand: &HandType) -> Value {
+    match hand {
+        HandType::String(s) => Value::String(s.clone()),
+        HandType::Integer(i) => Value::from(*i),
+        HandType::Float(f) => Number::from_f64(*f).map(Value::Number).unwrap_or(Value::Null),
+        HandType::Boolean(b) => Value::Bool(*b),
+    }
+}
+
+fn condition_op(condition: &Condition) -> BinaryOp {
+    match condition {
+        Condition::Equals => BinaryOp::Eq,
+        Condition::NotEquals => BinaryOp::NotEq,
+        Condition::GreaterThan => BinaryOp::Gt,
+        Condition::LessThan => BinaryOp::Lt,
     }
 }
 
-fn passes_clauses(mut v: (Value, Value), clauses: &Vec<WhereClause>) -> bool {
+impl fmt::Display for HandType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HandType::String(s) => write!(f, "'{}'", s),
+            HandType::Integer(i) => write!(f, "{}", i),
+            HandType::Float(x) => write!(f, "{}", x),
+            HandType::Boolean(b) => write!(f, "{}", b),
+        }
+    }
+}
+
+impl fmt::Display for Condition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let symbol = match self {
+            Condition::Equals => "=",
+            Condition::NotEquals => "!=",
+            Condition::GreaterThan => ">",
+            Condition::LessThan => "<",
+        };
+        write!(f, "{}", symbol)
+    }
+}
+
+fn passes_clauses(row: &Value, clauses: &[WhereClause]) -> bool {
     let mut result = {
-        evaluate_clause(&mut v, &clauses[0])
+        evaluate_clause(row.get(&clauses[0].left_hand).unwrap_or(&Value::Null), &clauses[0])
     };
 
     for clause in clauses.iter().skip(1) {
-        let clause_result = evaluate_clause(&mut v, clause);
+        let clause_result = evaluate_clause(row.get(&clause.left_hand).unwrap_or(&Value::Null), clause);
         match clause.connector {
-            Some(Connector::AND) => result = result && clause_result,
-            Some(Connector::OR) => result = result || clause_result,
+            Some(Connector::And) => result = result && clause_result,
+            Some(Connector::Or) => result = result || clause_result,
             None => { result = result && clause_result}
         }
     }
@@ -452,10 +487,7 @@ fn passes_clauses(mut v: (Value, Value), clauses: &Vec<WhereClause>) -> bool {
     result
 }
 
-fn evaluate_clause(data: &mut (Value, Value), clause: &WhereClause) -> bool {
-    //let left = data.1.get(&clause.left_hand).unwrap_or(&Value::Null);
-    let left = data.0.clone();
-    //let left = &data.1.take();
+fn evaluate_clause(left: &Value, clause: &WhereClause) -> bool {
     let right = &clause.right_hand;
     match (left, right) {
         (Value::Number(l), HandType::Integer(r)) => {
@@ -475,7 +507,7 @@ fn evaluate_clause(data: &mut (Value, Value), clause: &WhereClause) -> bool {
                     Condition::LessThan => l_f < r_f,
                 }
             } else {
-                return false
+                false
             }
         },
         (Value::Number(l), HandType::Float(r)) => {
@@ -495,23 +527,23 @@ fn evaluate_clause(data: &mut (Value, Value), clause: &WhereClause) -> bool {
                     Condition::LessThan => l_f < r_f,
                 }
             } else {
-                return false
+                false
             }
         },
         (Value::String(l), HandType::String(r)) => {
             match clause.operator {
-                Condition::Equals => *l == *r,
-                Condition::NotEquals => *l != *r,
-                Condition::GreaterThan => l > *r,
-                Condition::LessThan => l < *r,
+                Condition::Equals => l == r,
+                Condition::NotEquals => l != r,
+                Condition::GreaterThan => l > r,
+                Condition::LessThan => l < r,
             }
         },
         (Value::Bool(l), HandType::Boolean(r)) => {
             match clause.operator {
-                Condition::Equals => l == *r,
-                Condition::NotEquals => l != *r,
-                Condition::GreaterThan => l > *r,
-                Condition::LessThan => l < *r,
+                Condition::Equals => l == r,
+                Condition::NotEquals => l != r,
+                Condition::GreaterThan => *l & !*r,
+                Condition::LessThan => !*l & *r,
             }
         },
         (_, _) => {
@@ -519,3 +551,32 @@ fn evaluate_clause(data: &mut (Value, Value), clause: &WhereClause) -> bool {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::{ddl::create::{create, CreateData}, dml::insert::insert, storage::memory::MemoryBackend};
+
+    fn tokens(text: &str) -> Vec<String> {
+        text.split_whitespace().map(String::from).collect()
+    }
+
+    #[test]
+    fn column_lists_can_use_commas() {
+        let mut storage = MemoryBackend::new();
+        create(&mut storage, CreateData::Table { name: "t".to_string(), schema: tokens("id NUMBER KEY a TEXT b NUMBER c NUMBER") });
+        let table = storage.load_schema("t").unwrap();
+        insert(&mut storage, "main", table, tokens("VALUES (1, 'x', 2, 3)")).unwrap();
+
+        for query in ["a, b FROM t", "a,b FROM t", "a , b FROM t"] {
+            assert!(!needs_query_engine(&tokens(query)));
+            let results = select(&mut storage, "main", tokens(query));
+            assert!(results.missing.is_empty());
+            let shown: Vec<&str> = results.schema.iter().filter(|(shown, _)| *shown).map(|(_, f)| f.name.as_str()).collect();
+            assert_eq!(shown, vec!["a", "b"]);
+            assert_eq!(results.filtered[&json!(1)]["b"], json!(2));
+        }
+    }
+}
@@ -0,0 +1,420 @@
+use std::{collections::BTreeSet, fmt};
+
+use crate::dql::expr::{tokenize, Expr, Parser, Token};
+
+// Syntax after SELECT:
+//   [DISTINCT | ALL] * | <alias>.* | <expr> [[AS] <name>], ... [FROM <table> [[AS] <alias>]
+//   {[INNER] | LEFT [OUTER] | RIGHT [OUTER] | FULL [OUTER]} JOIN <table> [[AS] <alias>] ON <expr> ...]
+//   [WHERE <expr>]
+// which can be combined with
+//   {UNION | INTERSECT | EXCEPT} [ALL] SELECT ...
+// and preceded by
+//   WITH [RECURSIVE] <name> [(<column>, ...)] AS (<query>), ...
+// Without parentheses, INTERSECT binds tighter than UNION and EXCEPT.
+// Parsing doesn't look at storage; tables and columns are checked when the query is planned.
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum JoinKind {
+    Inner,
+    Left,
+    Right,
+    Full,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TableRef {
+    pub name: String,
+    pub alias: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct JoinClause {
+    pub kind: JoinKind,
+    pub table: TableRef,
+    pub on: Expr,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum SelectItem {
+    All,
+    AllOf(String),
+    Expr { expr: Expr, alias: Option<String> },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SelectStatement {
+    pub distinct: bool,
+    pub items: Vec<SelectItem>,
+    // Without FROM the select is over a single row with no columns
+    pub from: Option<TableRef>,
+    pub joins: Vec<JoinClause>,
+    pub where_clause: Option<Expr>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SetOperator {
+    Union,
+    Intersect,
+    Except,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum SetExpr {
+    Select(Box<SelectStatement>),
+    // Without ALL, repeated rows are dropped from the result
+    Operation { op: SetOperator, all: bool, left: Box<SetExpr>, right: Box<SetExpr> },
+}
+
+// A named query the rest of the statement reads like a table. Under WITH RECURSIVE, a query
+// ending in UNION [ALL] <select> runs that select again over the rows it last found until it
+// finds no more.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Cte {
+    pub name: String,
+    pub columns: Vec<String>,
+    pub query: SetExpr,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Query {
+    pub with: Vec<Cte>,
+    pub recursive: bool,
+    pub body: SetExpr,
+}
+
+const RESERVED: [&str; 12] = ["JOIN", "INNER", "LEFT", "RIGHT", "FULL", "OUTER", "ON", "WHERE", "AS", "UNION", "INTERSECT", "EXCEPT"];
+
+impl TableRef {
+    // Columns are qualified with the alias, which defaults to the table name
+    pub fn alias(&self) -> &str {
+        match &self.alias {
+            Some(alias) => alias,
+            None => self.name.rsplit('.').next().unwrap_or(&self.name),
+        }
+    }
+}
+
+impl Query {
+    // Tables and views the query reads, as written, leaving out the CTEs it defines
+    pub fn table_names(&self) -> BTreeSet<String> {
+        let mut names = BTreeSet::new();
+        for cte in &self.with {
+            cte.query.add_table_names(&mut names);
+        }
+        self.body.add_table_names(&mut names);
+        for cte in &self.with {
+            names.remove(&cte.name);
+        }
+        names
+    }
+}
+
+impl SetExpr {
+    fn add_table_names(&self, names: &mut BTreeSet<String>) {
+        match self {
+            SetExpr::Select(statement) => statement.add_table_names(names),
+            SetExpr::Operation { left, right, .. } => {
+                left.add_table_names(names);
+                right.add_table_names(names);
+            },
+        }
+    }
+}
+
+impl SelectStatement {
+    fn add_table_names(&self, names: &mut BTreeSet<String>) {
+        names.extend(self.from.iter().map(|t| t.name.clone()));
+        names.extend(self.joins.iter().map(|j| j.table.name.clone()));
+        let items = self.items.iter().filter_map(|item| match item {
+            SelectItem::Expr { expr, .. } => Some(expr),
+            _ => None,
+        });
+        let exprs = items.chain(self.joins.iter().map(|j| &j.on)).chain(self.where_clause.iter());
+        for subquery in exprs.flat_map(|e| e.subqueries()) {
+            subquery.add_table_names(names);
+        }
+    }
+}
+
+// Parses the tokens that follow SELECT, or a whole statement starting with WITH
+pub fn parse_query(tokens: &[String]) -> Result<Query, String> {
+    parse_query_tokens(tokenize(&tokens.join(" "))?)
+}
+
+// Parses a query the way Query writes itself out, starting with SELECT or WITH
+pub fn parse_stored_query(text: &str) -> Result<Query, String> {
+    let mut tokens = tokenize(text)?;
+    if matches!(tokens.first(), Some(Token::Ident(word)) if word.eq_ignore_ascii_case("SELECT")) {
+        tokens.remove(0);
+    }
+    parse_query_tokens(tokens)
+}
+
+fn parse_query_tokens(tokens: Vec<Token>) -> Result<Query, String> {
+    let mut parser = Parser::new(tokens);
+    let mut with = vec![];
+    let mut recursive = false;
+    if parser.eat_keyword("WITH") {
+        recursive = parser.eat_keyword("RECURSIVE");
+        loop {
+            with.push(parser.parse_cte()?);
+            if !parser.eat_symbol(",") {
+                break;
+            }
+        }
+        parser.expect_keyword("SELECT")?;
+    }
+    let body = parser.parse_set_expr()?;
+    parser.expect_end()?;
+    Ok(Query { with, recursive, body })
+}
+
+impl Parser {
+    fn parse_cte(&mut self) -> Result<Cte, String> {
+        let name = self.parse_identifier()?;
+        let mut columns = vec![];
+        if self.eat_symbol("(") {
+            loop {
+                columns.push(self.parse_identifier()?);
+                if self.eat_symbol(")") {
+                    break;
+                }
+                self.expect_symbol(",")?;
+            }
+        }
+        self.expect_keyword("AS")?;
+        self.expect_symbol("(")?;
+        self.expect_keyword("SELECT")?;
+        let query = self.parse_set_expr()?;
+        self.expect_symbol(")")?;
+        Ok(Cte { name, columns, query })
+    }
+
+    // Starts after the first SELECT
+    pub fn parse_set_expr(&mut self) -> Result<SetExpr, String> {
+        let mut left = self.parse_intersect()?;
+        loop {
+            let op = if self.eat_keyword("UNION") {
+                SetOperator::Union
+            } else if self.eat_keyword("EXCEPT") {
+                SetOperator::Except
+            } else {
+                return Ok(left);
+            };
+            let all = self.parse_set_quantifier()?;
+            let right = self.parse_intersect()?;
+            left = SetExpr::Operation { op, all, left: Box::new(left), right: Box::new(right) };
+        }
+    }
+
+    fn parse_intersect(&mut self) -> Result<SetExpr, String> {
+        let mut left = SetExpr::Select(Box::new(self.parse_select()?));
+        while self.eat_keyword("INTERSECT") {
+            let all = self.parse_set_quantifier()?;
+            let right = SetExpr::Select(Box::new(self.parse_select()?));
+            left = SetExpr::Operation { op: SetOperator::Intersect, all, left: Box::new(left), right: Box::new(right) };
+        }
+        Ok(left)
+    }
+
+    // [ALL | DISTINCT] SELECT
+    fn parse_set_quantifier(&mut self) -> Result<bool, String> {
+        let all = self.eat_keyword("ALL");
+        if !all {
+            self.eat_keyword("DISTINCT");
+        }
+        self.expect_keyword("SELECT")?;
+        Ok(all)
+    }
+
+    // Stops at the first token that can't continue the statement, so subqueries end at their ')'
+    pub fn parse_select(&mut self) -> Result<SelectStatement, String> {
+        let distinct = self.eat_keyword("DISTINCT");
+        if !distinct {
+            self.eat_keyword("ALL");
+        }
+        let mut items = vec![];
+        loop {
+            items.push(self.parse_select_item()?);
+            if !self.eat_symbol(",") {
+                break;
+            }
+        }
+        let from = if self.eat_keyword("FROM") { Some(self.parse_table_ref()?) } else { None };
+
+        let mut joins = vec![];
+        while from.is_some() {
+            let kind = if self.eat_keyword("JOIN") {
+                JoinKind::Inner
+            } else {
+                let kind = if self.eat_keyword("INNER") {
+                    JoinKind::Inner
+                } else if self.eat_keyword("LEFT") {
+                    JoinKind::Left
+                } else if self.eat_keyword("RIGHT") {
+                    JoinKind::Right
+                } else if self.eat_keyword("FULL") {
+                    JoinKind::Full
+                } else {
+                    break;
+                };
+                if kind != JoinKind::Inner {
+                    self.eat_keyword("OUTER");
+                }
+                self.expect_keyword("JOIN")?;
+                kind
+            };
+            let table = self.parse_table_ref()?;
+            self.expect_keyword("ON")?;
+            let on = self.parse_expr()?;
+            joins.push(JoinClause { kind, table, on });
+        }
+
+        let where_clause = if self.eat_keyword("WHERE") { Some(self.parse_expr()?) } else { None };
+        Ok(SelectStatement { distinct, items, from, joins, where_clause })
+    }
+
+    fn parse_select_item(&mut self) -> Result<SelectItem, String> {
+        if self.eat_symbol("*") {
+            return Ok(SelectItem::All);
+        }
+        let qualified_star = matches!(self.peek_at(1), Some(Token::Symbol(s)) if s == ".")
+            && matches!(self.peek_at(2), Some(Token::Symbol(s)) if s == "*");
+        if qualified_star {
+            let alias = self.parse_identifier()?;
+            self.expect_symbol(".")?;
+            self.expect_symbol("*")?;
+            return Ok(SelectItem::AllOf(alias));
+        }
+        let expr = self.parse_expr()?;
+        let alias = if self.eat_keyword("AS") {
+            Some(self.parse_identifier()?)
+        } else {
+            match self.peek() {
+                // Without FROM, the item can be followed by WHERE or a set operator
+                Some(Token::Ident(word)) if !word.eq_ignore_ascii_case("FROM") && !RESERVED.iter().any(|r| word.eq_ignore_ascii_case(r)) => Some(self.parse_identifier()?),
+                Some(Token::QuotedIdent(_)) => Some(self.parse_identifier()?),
+                _ => None,
+            }
+        };
+        Ok(SelectItem::Expr { expr, alias })
+    }
+
+    fn parse_table_ref(&mut self) -> Result<TableRef, String> {
+        let mut name = self.parse_identifier()?;
+        if self.eat_symbol(".") {
+            name = format!("{}.{}", name, self.parse_identifier()?);
+        }
+        let alias = if self.eat_keyword("AS") {
+            Some(self.parse_identifier()?)
+        } else {
+            match self.peek() {
+                Some(Token::Ident(word)) if !RESERVED.iter().any(|r| word.eq_ignore_ascii_case(r)) => Some(self.parse_identifier()?),
+                Some(Token::QuotedIdent(_)) => Some(self.parse_identifier()?),
+                _ => None,
+            }
+        };
+        Ok(TableRef { name, alias })
+    }
+}
+
+impl fmt::Display for JoinKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            JoinKind::Inner => write!(f, "JOIN"),
+            JoinKind::Left => write!(f, "LEFT JOIN"),
+            JoinKind::Right => write!(f, "RIGHT JOIN"),
+            JoinKind::Full => write!(f, "FULL JOIN"),
+        }
+    }
+}
+
+impl fmt::Display for SetOperator {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SetOperator::Union => write!(f, "UNION"),
+            SetOperator::Intersect => write!(f, "INTERSECT"),
+            SetOperator::Except => write!(f, "EXCEPT"),
+        }
+    }
+}
+
+impl fmt::Display for TableRef {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.alias {
+            Some(alias) => write!(f, "{} {}", self.name, alias),
+            None => write!(f, "{}", self.name),
+        }
+    }
+}
+
+impl fmt::Display for SelectItem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SelectItem::All => write!(f, "*"),
+            SelectItem::AllOf(alias) => write!(f, "{}.*", alias),
+            SelectItem::Expr { expr, alias: Some(alias) } => write!(f, "{} AS {}", expr, alias),
+            SelectItem::Expr { expr, alias: None } => write!(f, "{}", expr),
+        }
+    }
+}
+
+// Writes the statement back out as text that parses to the same tree
+impl fmt::Display for SelectStatement {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "SELECT {}", if self.distinct { "DISTINCT " } else { "" })?;
+        for (i, item) in self.items.iter().enumerate() {
+            if i > 0 {
+                write!(f, ", ")?;
+            }
+            write!(f, "{}", item)?;
+        }
+        if let Some(from) = &self.from {
+            write!(f, " FROM {}", from)?;
+        }
+        for join in &self.joins {
+            write!(f, " {} {} ON {}", join.kind, join.table, join.on)?;
+        }
+        if let Some(expr) = &self.where_clause {
+            write!(f, " WHERE {}", expr)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Cte {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.name)?;
+        if !self.columns.is_empty() {
+            write!(f, " ({})", self.columns.join(", "))?;
+        }
+        write!(f, " AS ({})", self.query)
+    }
+}
+
+impl fmt::Display for SetExpr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SetExpr::Select(statement) => write!(f, "{}", statement),
+            SetExpr::Operation { op, all, left, right } => {
+                write!(f, "{} {}{} {}", left, op, if *all { " ALL" } else { "" }, right)
+            },
+        }
+    }
+}
+
+impl fmt::Display for Query {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if !self.with.is_empty() {
+            write!(f, "WITH {}", if self.recursive { "RECURSIVE " } else { "" })?;
+            for (i, cte) in self.with.iter().enumerate() {
+                if i > 0 {
+                    write!(f, ", ")?;
+                }
+                write!(f, "{}", cte)?;
+            }
+            write!(f, " ")?;
+        }
+        write!(f, "{}", self.body)
+    }
+}
@@ -0,0 +1,203 @@
+use std::cmp::Ordering;
+
+use serde_json::Value;
+
+use crate::{catalog::load_catalog, dql::expr::{compare_values, BinaryOp, Expr, UnaryOp}, models::{ColumnStats, TableStats}, storage::StorageBackend};
+
+// Estimates from what ANALYZE collected. Fractions are of the rows the stats were taken from.
+
+// Fraction of rows a condition is taken to keep when the stats can't say
+pub const DEFAULT_SELECTIVITY: f64 = 1.0 / 3.0;
+
+pub fn table_stats(storage: &mut dyn StorageBackend, name: &str) -> Option<TableStats> {
+    load_catalog(storage).ok()?.stats(name).cloned()
+}
+
+// Fraction of rows where `<column> <op> <value>` holds. None for a value of another type than the
+// column's, or a table that was empty when analyzed.
+pub fn fraction(stats: &ColumnStats, rows: usize, op: BinaryOp, value: &Value) -> Option<f64> {
+    if rows == 0 {
+        return None;
+    }
+    if value.is_null() {
+        return Some(0.0);
+    }
+    let non_null = rows.saturating_sub(stats.nulls) as f64 / rows as f64;
+    let equal = equal_fraction(stats, rows, value)?;
+    let less = || less_fraction(stats, non_null, value);
+    let fraction = match op {
+        BinaryOp::Eq => equal,
+        BinaryOp::NotEq => non_null - equal,
+        BinaryOp::Lt => less()?,
+        BinaryOp::LtEq => less()? + equal,
+        BinaryOp::Gt => non_null - less()? - equal,
+        BinaryOp::GtEq => non_null - less()?,
+        _ => return None,
+    };
+    Some(fraction.clamp(0.0, 1.0))
+}
+
+// Common values are counted; the rest share what's left evenly
+fn equal_fraction(stats: &ColumnStats, rows: usize, value: &Value) -> Option<f64> {
+    if let Some(lowest) = stats.histogram.first() {
+        compare_values(lowest, value)?;
+    }
+    if let Some((_, count)) = stats.most_common.iter().find(|(v, _)| compare_values(v, value) == Some(Ordering::Equal)) {
+        return Some(*count as f64 / rows as f64);
+    }
+    let common: usize = stats.most_common.iter().map(|(_, count)| count).sum();
+    let rest = rows.saturating_sub(stats.nulls + common);
+    let rest_distinct = stats.distinct.saturating_sub(stats.most_common.len());
+    if rest_distinct == 0 {
+        return Some(0.0);
+    }
+    Some(rest as f64 / rest_distinct as f64 / rows as f64)
+}
+
+// Values below `value`, counting whole buckets and the part of its own bucket below it. Only
+// numbers can say where in a bucket they fall; anything else is put halfway.
+fn less_fraction(stats: &ColumnStats, non_null: f64, value: &Value) -> Option<f64> {
+    let bounds = &stats.histogram;
+    if compare_values(value, bounds.first()?)? != Ordering::Greater {
+        return Some(0.0);
+    }
+    if compare_values(value, bounds.last()?)? == Ordering::Greater {
+        return Some(non_null);
+    }
+    let bucket = bounds.windows(2).position(|b| compare_values(value, &b[1]) != Some(Ordering::Greater))?;
+    let within = match (bounds[bucket].as_f64(), bounds[bucket + 1].as_f64(), value.as_f64()) {
+        (Some(low), Some(high), Some(v)) if high > low => (v - low) / (high - low),
+        _ => 0.5,
+    };
+    Some((bucket as f64 + within) / (bounds.len() - 1) as f64 * non_null)
+}
+
+// Distinct values now. A column with few distinct values is taken to keep them as the table
+// grows; one with many, to gain more in step with it.
+pub fn distinct_values(stats: &ColumnStats, analyzed_rows: usize, rows: usize) -> usize {
+    let distinct = if stats.distinct * 10 > analyzed_rows && analyzed_rows > 0 {
+        stats.distinct * rows / analyzed_rows
+    } else {
+        stats.distinct
+    };
+    distinct.clamp(1, rows.max(1))
+}
+
+// Fraction of rows a condition keeps. `column` gives the stats for a column as the condition
+// names it, with the number of rows they were taken from.
+pub fn selectivity<'s>(expr: &Expr, column: &dyn Fn(&str) -> Option<(&'s ColumnStats, usize)>) -> f64 {
+    let estimate = match expr {
+        Expr::Literal(Value::Bool(b)) => Some(if *b { 1.0 } else { 0.0 }),
+        Expr::Literal(Value::Null) => Some(0.0),
+        Expr::Binary { left, op: BinaryOp::And, right } => Some(selectivity(left, column) * selectivity(right, column)),
+        Expr::Binary { left, op: BinaryOp::Or, right } => {
+            let (left, right) = (selectivity(left, column), selectivity(right, column));
+            Some(left + right - left * right)
+        },
+        Expr::Unary { op: UnaryOp::Not, expr } => Some(1.0 - selectivity(expr, column)),
+        Expr::Binary { left, op, right } => match (left.as_ref(), right.as_ref()) {
+            (Expr::Column(name), Expr::Literal(value)) => column(name).and_then(|(stats, rows)| fraction(stats, rows, *op, value)),
+            (Expr::Literal(value), Expr::Column(name)) => column(name).and_then(|(stats, rows)| fraction(stats, rows, flipped(*op), value)),
+            _ => None,
+        },
+        Expr::IsNull { expr, negated } => match expr.as_ref() {
+            Expr::Column(name) => column(name).filter(|(_, rows)| *rows > 0).map(|(stats, rows)| {
+                let nulls = stats.nulls as f64 / rows as f64;
+                if *negated { 1.0 - nulls } else { nulls }
+            }),
+            _ => None,
+        },
+        _ => None,
+    };
+    estimate.unwrap_or(DEFAULT_SELECTIVITY)
+}
+
+// `<value> <op> <column>` read the other way round
+fn flipped(op: BinaryOp) -> BinaryOp {
+    match op {
+        BinaryOp::Lt => BinaryOp::Gt,
+        BinaryOp::Gt => BinaryOp::Lt,
+        BinaryOp::LtEq => BinaryOp::GtEq,
+        BinaryOp::GtEq => BinaryOp::LtEq,
+        other => other,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::dql::expr::parse_expression;
+
+    // 100 rows: 10 NULL, 30 holding 5 and 20 holding 7, and 40 spread over 10 other values
+    fn numbers() -> ColumnStats {
+        ColumnStats {
+            nulls: 10,
+            distinct: 12,
+            most_common: vec![(json!(5), 30), (json!(7), 20)],
+            histogram: vec![json!(0), json!(20), json!(40), json!(60), json!(80), json!(100)],
+        }
+    }
+
+    fn assert_close(actual: Option<f64>, expected: f64) {
+        let actual = actual.expect("expected an estimate");
+        assert!((actual - expected).abs() < 1e-9, "{} is not {}", actual, expected);
+    }
+
+    #[test]
+    fn common_values_use_their_count() {
+        assert_close(fraction(&numbers(), 100, BinaryOp::Eq, &json!(5)), 0.3);
+        assert_close(fraction(&numbers(), 100, BinaryOp::NotEq, &json!(5)), 0.6);
+    }
+
+    #[test]
+    fn other_values_share_the_rest() {
+        assert_close(fraction(&numbers(), 100, BinaryOp::Eq, &json!(50)), 0.04);
+    }
+
+    #[test]
+    fn ranges_interpolate_within_a_bucket() {
+        // Halfway through the third of five buckets, of the 90% that aren't NULL
+        assert_close(fraction(&numbers(), 100, BinaryOp::Lt, &json!(50)), 0.45);
+        assert_close(fraction(&numbers(), 100, BinaryOp::LtEq, &json!(50)), 0.49);
+        assert_close(fraction(&numbers(), 100, BinaryOp::GtEq, &json!(50)), 0.45);
+        assert_close(fraction(&numbers(), 100, BinaryOp::Gt, &json!(50)), 0.41);
+    }
+
+    #[test]
+    fn ranges_past_the_ends_are_clamped() {
+        assert_close(fraction(&numbers(), 100, BinaryOp::Lt, &json!(-5)), 0.0);
+        assert_close(fraction(&numbers(), 100, BinaryOp::Lt, &json!(500)), 0.9);
+        assert_close(fraction(&numbers(), 100, BinaryOp::Gt, &json!(500)), 0.0);
+    }
+
+    #[test]
+    fn text_falls_halfway_through_its_bucket() {
+        let stats = ColumnStats { nulls: 0, distinct: 4, most_common: vec![], histogram: vec![json!("a"), json!("m"), json!("z")] };
+        assert_close(fraction(&stats, 10, BinaryOp::Lt, &json!("c")), 0.25);
+    }
+
+    #[test]
+    fn null_matches_nothing() {
+        assert_close(fraction(&numbers(), 100, BinaryOp::Eq, &Value::Null), 0.0);
+    }
+
+    #[test]
+    fn no_estimate_without_comparable_stats() {
+        assert_eq!(fraction(&numbers(), 100, BinaryOp::Eq, &json!("five")), None);
+        assert_eq!(fraction(&numbers(), 0, BinaryOp::Eq, &json!(5)), None);
+        assert_eq!(fraction(&numbers(), 100, BinaryOp::And, &json!(5)), None);
+    }
+
+    #[test]
+    fn selectivity_combines_conditions() {
+        let stats = numbers();
+        let column = |name: &str| (name == "n").then_some((&stats, 100));
+        let expr = |text: &str| parse_expression(text).unwrap();
+        assert!((selectivity(&expr("n = 5 AND n = 7"), &column) - 0.06).abs() < 1e-9);
+        assert!((selectivity(&expr("n = 5 OR n = 7"), &column) - 0.44).abs() < 1e-9);
+        assert!((selectivity(&expr("n IS NULL"), &column) - 0.1).abs() < 1e-9);
+        assert_eq!(selectivity(&expr("m = 5"), &column), DEFAULT_SELECTIVITY);
+    }
+}
@@ -0,0 +1,454 @@
+use std::{cmp::Ordering, collections::HashMap, fmt};
+
+use serde_json::Value;
+
+use crate::dql::{expr::{compare_values, evaluate_in, float_value, EvalContext, Expr, Parser, Token}, join::normalize_value};
+
+// Syntax after a function call:
+//   OVER ([PARTITION BY <expr>, ...] [ORDER BY <expr> [ASC | DESC], ...] [<frame>])
+// where a frame is
+//   {ROWS | RANGE} {<bound> | BETWEEN <bound> AND <bound>}
+//   <bound> = UNBOUNDED PRECEDING | <n> PRECEDING | CURRENT ROW | <n> FOLLOWING | UNBOUNDED FOLLOWING
+// Window functions are worked out over the rows WHERE kept, before the select list is.
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct WindowSpec {
+    pub partition_by: Vec<Expr>,
+    pub order_by: Vec<OrderBy>,
+    pub frame: Option<Frame>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct OrderBy {
+    pub expr: Expr,
+    pub descending: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FrameUnits {
+    Rows,
+    // Rows that sort the same as the current one are all in or all out
+    Range,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FrameBound {
+    UnboundedPreceding,
+    Preceding(u64),
+    CurrentRow,
+    Following(u64),
+    UnboundedFollowing,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Frame {
+    pub units: FrameUnits,
+    pub start: FrameBound,
+    pub end: FrameBound,
+}
+
+const RANKING: [&str; 3] = ["ROW_NUMBER", "RANK", "DENSE_RANK"];
+const OFFSET: [&str; 2] = ["LAG", "LEAD"];
+const AGGREGATES: [&str; 5] = ["SUM", "AVG", "COUNT", "MIN", "MAX"];
+
+// Columns the select list reads window results from while the query runs
+pub fn window_column(i: usize) -> String {
+    format!("#window{}", i)
+}
+
+pub fn check_window(name: &str, arg_count: usize) -> Result<(), String> {
+    let (min, max) = if RANKING.contains(&name) {
+        (0, 0)
+    } else if OFFSET.contains(&name) {
+        (1, 3)
+    } else if name == "COUNT" {
+        // COUNT(*) is parsed without arguments
+        (0, 1)
+    } else if AGGREGATES.contains(&name) {
+        (1, 1)
+    } else {
+        return Err(format!("{} is not a window function", name));
+    };
+    if arg_count < min || arg_count > max {
+        let expected = if min == max { min.to_string() } else { format!("{} to {}", min, max) };
+        return Err(format!("{} takes {} argument(s), got {}", name, expected, arg_count));
+    }
+    Ok(())
+}
+
+impl Parser {
+    // After OVER
+    pub fn parse_window_spec(&mut self) -> Result<WindowSpec, String> {
+        self.expect_symbol("(")?;
+        let mut partition_by = vec![];
+        if self.eat_keyword("PARTITION") {
+            self.expect_keyword("BY")?;
+            loop {
+                partition_by.push(self.parse_expr()?);
+                if !self.eat_symbol(",") {
+                    break;
+                }
+            }
+        }
+        let mut order_by = vec![];
+        if self.eat_keyword("ORDER") {
+            self.expect_keyword("BY")?;
+            loop {
+                let expr = self.parse_expr()?;
+                let descending = self.eat_keyword("DESC");
+                if !descending {
+                    self.eat_keyword("ASC");
+                }
+                order_by.push(OrderBy { expr, descending });
+                if !self.eat_symbol(",") {
+                    break;
+                }
+            }
+        }
+        let units = if self.eat_keyword("ROWS") {
+            Some(FrameUnits::Rows)
+        } else if self.eat_keyword("RANGE") {
+            Some(FrameUnits::Range)
+        } else {
+            None
+        };
+        let frame = match units {
+            Some(units) => Some(self.parse_frame(units)?),
+            None => None,
+        };
+        self.expect_symbol(")")?;
+        Ok(WindowSpec { partition_by, order_by, frame })
+    }
+
+    fn parse_frame(&mut self, units: FrameUnits) -> Result<Frame, String> {
+        let (start, end) = if self.eat_keyword("BETWEEN") {
+            let start = self.parse_frame_bound()?;
+            self.expect_keyword("AND")?;
+            (start, self.parse_frame_bound()?)
+        } else {
+            (self.parse_frame_bound()?, FrameBound::CurrentRow)
+        };
+        if start == FrameBound::UnboundedFollowing || end == FrameBound::UnboundedPreceding {
+            return Err("A frame can't start at UNBOUNDED FOLLOWING or end at UNBOUNDED PRECEDING".to_string());
+        }
+        if units == FrameUnits::Range && [start, end].iter().any(|b| matches!(b, FrameBound::Preceding(_) | FrameBound::Following(_))) {
+            return Err("RANGE frames only take UNBOUNDED or CURRENT ROW; use ROWS to count rows".to_string());
+        }
+        Ok(Frame { units, start, end })
+    }
+
+    fn parse_frame_bound(&mut self) -> Result<FrameBound, String> {
+        if self.eat_keyword("UNBOUNDED") {
+            if self.eat_keyword("PRECEDING") {
+                return Ok(FrameBound::UnboundedPreceding);
+            }
+            self.expect_keyword("FOLLOWING")?;
+            return Ok(FrameBound::UnboundedFollowing);
+        }
+        if self.eat_keyword("CURRENT") {
+            self.expect_keyword("ROW")?;
+            return Ok(FrameBound::CurrentRow);
+        }
+        let count = match self.next_token() {
+            Some(Token::Number(n)) => n.parse::<u64>().map_err(|_| format!("Expected a whole number of rows, found {}", n))?,
+            _ => return Err("Expected UNBOUNDED, CURRENT ROW or a number of rows".to_string()),
+        };
+        if self.eat_keyword("PRECEDING") {
+            return Ok(FrameBound::Preceding(count));
+        }
+        self.expect_keyword("FOLLOWING")?;
+        Ok(FrameBound::Following(count))
+    }
+}
+
+// The window function's value for each of `rows`, in the same order
+pub fn evaluate_window(context: &mut dyn EvalContext, name: &str, args: &[Expr], spec: &WindowSpec, rows: &[Value]) -> Result<Vec<Value>, String> {
+    // Partitions keep the order their first row came in
+    let mut partitions: Vec<Vec<usize>> = vec![];
+    let mut partition_of: HashMap<Vec<Value>, usize> = HashMap::new();
+    let mut sort_keys = vec![];
+    for (i, row) in rows.iter().enumerate() {
+        let key = spec.partition_by.iter().map(|e| evaluate_in(e, row, context).map(|v| normalize_value(&v))).collect::<Result<Vec<Value>, String>>()?;
+        let partition = *partition_of.entry(key).or_insert_with(|| {
+            partitions.push(vec![]);
+            partitions.len() - 1
+        });
+        partitions[partition].push(i);
+        sort_keys.push(spec.order_by.iter().map(|o| evaluate_in(&o.expr, row, context)).collect::<Result<Vec<Value>, String>>()?);
+    }
+
+    let mut results = vec![Value::Null; rows.len()];
+    for mut partition in partitions {
+        partition.sort_by(|a, b| compare_sort_keys(&sort_keys[*a], &sort_keys[*b], &spec.order_by));
+        // Where each row's peers, the rows sorting the same as it, start and end
+        let mut peers = vec![(0, 0); partition.len()];
+        let mut start = 0;
+        for i in 1..=partition.len() {
+            if i == partition.len() || compare_sort_keys(&sort_keys[partition[start]], &sort_keys[partition[i]], &spec.order_by) != Ordering::Equal {
+                for peer in peers.iter_mut().take(i).skip(start) {
+                    *peer = (start, i);
+                }
+                start = i;
+            }
+        }
+
+        let values = if RANKING.contains(&name) {
+            rank(name, &peers)
+        } else if OFFSET.contains(&name) {
+            shift(context, name, args, rows, &partition)?
+        } else {
+            aggregate(context, name, args, spec, rows, &partition, &peers)?
+        };
+        for (i, value) in partition.into_iter().zip(values) {
+            results[i] = value;
+        }
+    }
+    Ok(results)
+}
+
+// NULLs sort after everything else, so first when descending
+fn compare_sort_keys(a: &[Value], b: &[Value], order_by: &[OrderBy]) -> Ordering {
+    for ((x, y), order) in a.iter().zip(b).zip(order_by) {
+        let ordering = match (x.is_null(), y.is_null()) {
+            (true, true) => Ordering::Equal,
+            (true, false) => Ordering::Greater,
+            (false, true) => Ordering::Less,
+            _ => compare_values(x, y).unwrap_or(Ordering::Equal),
+        };
+        let ordering = if order.descending { ordering.reverse() } else { ordering };
+        if ordering != Ordering::Equal {
+            return ordering;
+        }
+    }
+    Ordering::Equal
+}
+
+fn rank(name: &str, peers: &[(usize, usize)]) -> Vec<Value> {
+    let mut dense = 0;
+    peers.iter().enumerate().map(|(i, (start, _))| {
+        if i == *start {
+            dense += 1;
+        }
+        match name {
+            "ROW_NUMBER" => Value::from(i + 1),
+            "RANK" => Value::from(start + 1),
+            _ => Value::from(dense),
+        }
+    }).collect()
+}
+
+// LAG(<expr> [, <offset> [, <default>]]) reads <expr> from the row <offset> places earlier in the
+// partition, LEAD from the one that many later. Past either end it gives the default, or NULL.
+fn shift(context: &mut dyn EvalContext, name: &str, args: &[Expr], rows: &[Value], partition: &[usize]) -> Result<Vec<Value>, String> {
+    let mut values = vec![];
+    for (position, index) in partition.iter().enumerate() {
+        let row = &rows[*index];
+        let offset = match args.get(1) {
+            Some(offset) => {
+                let value = evaluate_in(offset, row, context)?;
+                value.as_u64().ok_or_else(|| format!("{} offset must be a whole number of rows, not {}", name, value))? as usize
+            },
+            None => 1,
+        };
+        let target = if name == "LAG" { position.checked_sub(offset) } else { position.checked_add(offset) };
+        let value = match target.and_then(|t| partition.get(t)) {
+            Some(other) => evaluate_in(&args[0], &rows[*other], context)?,
+            None => match args.get(2) {
+                Some(default) => evaluate_in(default, row, context)?,
+                None => Value::Null,
+            },
+        };
+        values.push(value);
+    }
+    Ok(values)
+}
+
+// Without a frame, the frame is the whole partition, or with ORDER BY every row up to the
+// current one and its peers
+fn aggregate(context: &mut dyn EvalContext, name: &str, args: &[Expr], spec: &WindowSpec, rows: &[Value], partition: &[usize], peers: &[(usize, usize)]) -> Result<Vec<Value>, String> {
+    let frame = match (&spec.frame, spec.order_by.is_empty()) {
+        (Some(frame), _) => frame.clone(),
+        (None, true) => Frame { units: FrameUnits::Rows, start: FrameBound::UnboundedPreceding, end: FrameBound::UnboundedFollowing },
+        (None, false) => Frame { units: FrameUnits::Range, start: FrameBound::UnboundedPreceding, end: FrameBound::CurrentRow },
+    };
+    // COUNT(*) counts rows, which is the same as counting a value no row lacks
+    let inputs = match args.first() {
+        Some(arg) => partition.iter().map(|i| evaluate_in(arg, &rows[*i], context)).collect::<Result<Vec<Value>, String>>()?,
+        None => vec![Value::Bool(true); partition.len()],
+    };
+
+    let last = partition.len() as i64 - 1;
+    let mut values = vec![];
+    for (position, (peer_start, peer_end)) in peers.iter().enumerate() {
+        let position = position as i64;
+        let bound = |bound: FrameBound, is_start: bool| match bound {
+            FrameBound::UnboundedPreceding => 0,
+            FrameBound::UnboundedFollowing => last,
+            FrameBound::Preceding(n) => position - n.min(i64::MAX as u64) as i64,
+            FrameBound::Following(n) => position.saturating_add(n.min(i64::MAX as u64) as i64),
+            FrameBound::CurrentRow => match (frame.units, is_start) {
+                (FrameUnits::Rows, _) => position,
+                (FrameUnits::Range, true) => *peer_start as i64,
+                (FrameUnits::Range, false) => *peer_end as i64 - 1,
+            },
+        };
+        let start = bound(frame.start, true).max(0);
+        let end = bound(frame.end, false).min(last);
+        let in_frame: Vec<&Value> = if start > end {
+            vec![]
+        } else {
+            inputs[start as usize..=end as usize].iter().filter(|v| !v.is_null()).collect()
+        };
+        values.push(fold(name, &in_frame)?);
+    }
+    Ok(values)
+}
+
+// NULLs are already left out. Only COUNT gives a value for an empty frame.
+fn fold(name: &str, values: &[&Value]) -> Result<Value, String> {
+    if name == "COUNT" {
+        return Ok(Value::from(values.len()));
+    }
+    if values.is_empty() {
+        return Ok(Value::Null);
+    }
+    match name {
+        "MIN" | "MAX" => {
+            let mut best = values[0];
+            for value in &values[1..] {
+                let ordering = compare_values(value, best).ok_or_else(|| format!("{} can't compare {} and {}", name, value, best))?;
+                if (name == "MIN" && ordering == Ordering::Less) || (name == "MAX" && ordering == Ordering::Greater) {
+                    best = value;
+                }
+            }
+            Ok(best.clone())
+        },
+        _ => {
+            let mut numbers = vec![];
+            for value in values {
+                match value {
+                    Value::Number(n) => numbers.push(n),
+                    other => return Err(format!("{} expects numbers, got {}", name, other)),
+                }
+            }
+            let float_sum: f64 = numbers.iter().map(|n| n.as_f64().unwrap_or(0.0)).sum();
+            if name == "AVG" {
+                return float_value(float_sum / numbers.len() as f64);
+            }
+            // Whole numbers add up to a whole number while they fit one
+            let int_sum = numbers.iter().try_fold(0i64, |sum, n| n.as_i64().and_then(|i| sum.checked_add(i)));
+            match int_sum {
+                Some(sum) => Ok(Value::from(sum)),
+                None => float_value(float_sum),
+            }
+        },
+    }
+}
+
+impl fmt::Display for WindowSpec {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut parts = vec![];
+        if !self.partition_by.is_empty() {
+            let exprs: Vec<String> = self.partition_by.iter().map(|e| e.to_string()).collect();
+            parts.push(format!("PARTITION BY {}", exprs.join(", ")));
+        }
+        if !self.order_by.is_empty() {
+            let exprs: Vec<String> = self.order_by.iter()
+                .map(|o| format!("{}{}", o.expr, if o.descending { " DESC" } else { "" }))
+                .collect();
+            parts.push(format!("ORDER BY {}", exprs.join(", ")));
+        }
+        if let Some(frame) = &self.frame {
+            parts.push(frame.to_string());
+        }
+        write!(f, "({})", parts.join(" "))
+    }
+}
+
+impl fmt::Display for Frame {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let units = match self.units {
+            FrameUnits::Rows => "ROWS",
+            FrameUnits::Range => "RANGE",
+        };
+        write!(f, "{} BETWEEN {} AND {}", units, self.start, self.end)
+    }
+}
+
+impl fmt::Display for FrameBound {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FrameBound::UnboundedPreceding => write!(f, "UNBOUNDED PRECEDING"),
+            FrameBound::Preceding(n) => write!(f, "{} PRECEDING", n),
+            FrameBound::CurrentRow => write!(f, "CURRENT ROW"),
+            FrameBound::Following(n) => write!(f, "{} FOLLOWING", n),
+            FrameBound::UnboundedFollowing => write!(f, "UNBOUNDED FOLLOWING"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use crate::{ddl::create::{create, CreateData}, dml::insert::insert, dql::query::select_query, storage::{memory::MemoryBackend, StorageBackend}};
+
+    fn tokens(text: &str) -> Vec<String> {
+        text.split_whitespace().map(String::from).collect()
+    }
+
+    // Rows 2 and 3 are peers, sorting the same on k
+    fn peers() -> MemoryBackend {
+        let mut storage = MemoryBackend::new();
+        create(&mut storage, CreateData::Table { name: "t".to_string(), schema: tokens("id NUMBER KEY k NUMBER v NUMBER") });
+        let table = storage.load_schema("t").unwrap();
+        insert(&mut storage, "main", table, tokens("VALUES (1, 1, 10), (2, 2, 20), (3, 2, 30), (4, 3, 40)")).unwrap();
+        storage
+    }
+
+    // The window's value for rows 1 to 4
+    fn window(storage: &mut MemoryBackend, over: &str) -> Vec<serde_json::Value> {
+        let results = select_query(storage, "main", tokens(&format!("id, SUM(v) OVER ({}) AS w FROM t", over))).unwrap();
+        let mut rows: Vec<(i64, serde_json::Value)> = results.filtered.values().map(|row| (row["id"].as_i64().unwrap(), row["w"].clone())).collect();
+        rows.sort_by_key(|(id, _)| *id);
+        rows.into_iter().map(|(_, w)| w).collect()
+    }
+
+    #[test]
+    fn rows_frames_stop_at_the_current_row() {
+        let mut storage = peers();
+        let sums = window(&mut storage, "ORDER BY k, id ROWS BETWEEN UNBOUNDED PRECEDING AND CURRENT ROW");
+        assert_eq!(sums, vec![json!(10), json!(30), json!(60), json!(100)]);
+    }
+
+    #[test]
+    fn range_frames_take_in_every_peer() {
+        let mut storage = peers();
+        let sums = window(&mut storage, "ORDER BY k RANGE BETWEEN UNBOUNDED PRECEDING AND CURRENT ROW");
+        assert_eq!(sums, vec![json!(10), json!(60), json!(60), json!(100)]);
+        // Which is the frame ORDER BY gives when none is written
+        assert_eq!(window(&mut storage, "ORDER BY k"), sums);
+    }
+
+    #[test]
+    fn range_frames_can_start_at_the_current_peers() {
+        let mut storage = peers();
+        let sums = window(&mut storage, "ORDER BY k RANGE BETWEEN CURRENT ROW AND UNBOUNDED FOLLOWING");
+        assert_eq!(sums, vec![json!(100), json!(90), json!(90), json!(40)]);
+    }
+
+    #[test]
+    fn rows_frames_count_rows_either_side() {
+        let mut storage = peers();
+        let sums = window(&mut storage, "ORDER BY k, id ROWS BETWEEN 1 PRECEDING AND 1 FOLLOWING");
+        assert_eq!(sums, vec![json!(30), json!(60), json!(90), json!(70)]);
+    }
+
+    #[test]
+    fn without_order_by_the_frame_is_the_partition() {
+        let mut storage = peers();
+        assert_eq!(window(&mut storage, ""), vec![json!(100); 4]);
+        let sums = window(&mut storage, "PARTITION BY k");
+        assert_eq!(sums, vec![json!(10), json!(50), json!(50), json!(40)]);
+    }
+}
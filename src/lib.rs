@@ -0,0 +1,81 @@
+use std::rc::Rc;
+
+use serde_json::Value;
+
+use crate::{dql::{functions, query::select_query, select::SelectReturn}, storage::{memory::MemoryBackend, StorageBackend}};
+
+pub mod catalog;
+pub mod cli;
+pub mod ddl;
+pub mod dml;
+pub mod dql;
+pub mod storage;
+pub mod models;
+
+// A database for programs that embed ezpzdb rather than run the command line
+pub struct Database {
+    storage: Box<dyn StorageBackend>,
+    database: String,
+}
+
+impl Database {
+    pub fn new(storage: Box<dyn StorageBackend>) -> Database {
+        Database { storage, database: "main".to_string() }
+    }
+
+    pub fn in_memory() -> Database {
+        Database::new(Box::new(MemoryBackend::new()))
+    }
+
+    // Lets queries call `name` with exactly `arity` arguments. Functions are registered for the
+    // thread, so every database on it can call them.
+    pub fn register_function(&mut self, name: &str, arity: usize, call: impl Fn(&[Value]) -> Result<Value, String> + 'static) -> Result<(), String> {
+        functions::register_function(name, arity, Rc::new(call))
+    }
+
+    // Runs a SELECT, or a query starting with WITH
+    pub fn query(&mut self, sql: &str) -> Result<SelectReturn, String> {
+        let mut tokens: Vec<String> = sql.split_whitespace().map(String::from).collect();
+        if tokens.first().is_some_and(|t| t.eq_ignore_ascii_case("SELECT")) {
+            tokens.remove(0);
+        }
+        select_query(self.storage.as_mut(), &self.database, tokens)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::{ddl::create::{create, CreateData}, dml::insert::insert};
+
+    fn tokens(text: &str) -> Vec<String> {
+        text.split_whitespace().map(String::from).collect()
+    }
+
+    fn normalize_sku(args: &[Value]) -> Result<Value, String> {
+        match &args[0] {
+            Value::String(sku) => Ok(Value::String(sku.replace('-', "").to_uppercase())),
+            Value::Null => Ok(Value::Null),
+            other => Err(format!("expected text, got {}", other)),
+        }
+    }
+
+    #[test]
+    fn queries_call_registered_functions() {
+        let mut storage = MemoryBackend::new();
+        create(&mut storage, CreateData::Table { name: "items".to_string(), schema: tokens("id NUMBER KEY sku TEXT") });
+        let table = storage.load_schema("items").unwrap();
+        insert(&mut storage, "main", table, tokens("VALUES (1, 'ab-12'), (2, 'cd-34')")).unwrap();
+
+        let mut db = Database::new(Box::new(storage));
+        db.register_function("normalize_sku", 1, normalize_sku).unwrap();
+        let results = db.query("SELECT id FROM items WHERE normalize_sku(sku) = 'CD34'").unwrap();
+        assert_eq!(results.filtered.values().map(|row| row["id"].clone()).collect::<Vec<Value>>(), vec![json!(2)]);
+
+        assert_eq!(db.query("SELECT NORMALIZE_SKU('a', 'b')").err(), Some("NORMALIZE_SKU takes 1 argument(s), got 2".to_string()));
+        assert_eq!(db.query("SELECT normalize_sku(1)").err(), Some("NORMALIZE_SKU: expected text, got 1".to_string()));
+        assert!(db.register_function("upper", 1, normalize_sku).is_err());
+    }
+}
@@ -1,12 +1,5 @@
-use cli::ezpzdb_cli;
-pub mod cli;
-pub mod ddl;
-pub mod dml;
-pub mod dql;
-pub mod storage;
-pub mod models;
+use ezpzdb::cli::ezpzdb_cli;
 
 fn main() {
     ezpzdb_cli();
 }
-
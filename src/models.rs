@@ -6,22 +6,114 @@ use serde_json::Value;
 use std::cmp::Ordering;
 
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Table {
     pub name: String,
     pub schema: Vec<FieldDef>,
     pub data: HashMap<Value, Value>,
     pub indexes: HashMap<String, Index>,
+    #[serde(default)]
+    pub constraints: Vec<Constraint>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Catalog {
+    pub databases: BTreeMap<String, DatabaseEntry>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct DatabaseEntry {
+    pub tables: BTreeMap<String, TableEntry>,
+    #[serde(default)]
+    pub sequences: BTreeMap<String, Sequence>,
+    #[serde(default)]
+    pub views: BTreeMap<String, View>,
+}
+
+// A stored query that reads like a table
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct View {
+    // As it reads back from the parsed tree, starting with SELECT or WITH
+    pub query: String,
+    // Materialized views keep their rows in a table of the same name until refreshed
+    pub materialized: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TableEntry {
+    pub indexes: Vec<String>,
+    pub schema_version: u32,
+    // Set by ANALYZE, and dropped when the schema changes
+    #[serde(default)]
+    pub stats: Option<TableStats>,
+    // The table's foreign keys, so tables they point at find them without reading every schema.
+    // None in catalogs saved before they were recorded.
+    #[serde(default)]
+    pub foreign_keys: Option<Vec<Constraint>>,
+}
+
+// What ANALYZE found. Estimates use it as fractions of `rows`, so stats taken before later
+// writes still scale to the table as it is.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct TableStats {
+    pub rows: usize,
+    pub columns: BTreeMap<String, ColumnStats>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ColumnStats {
+    pub nulls: usize,
+    // Distinct values other than NULL
+    pub distinct: usize,
+    // Values held by more than one row with how many hold each, most frequent first
+    pub most_common: Vec<(Value, usize)>,
+    // Bounds of buckets holding about as many of the non-NULL values each, lowest first
+    pub histogram: Vec<Value>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Sequence {
+    pub start: i64,
+    pub increment: i64,
+    pub min: i64,
+    pub max: i64,
+    pub cycle: bool,
+    // The last value handed out, or the next one to hand out while `is_called` is false
+    pub last_value: i64,
+    pub is_called: bool,
+    // Set for sequences created for a SERIAL column, which go when the column does
+    pub owner: Option<SequenceOwner>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct SequenceOwner {
+    pub table: String,
+    pub column: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Constraint {
+    pub name: String,
+    pub kind: ConstraintKind,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum ConstraintKind {
+    Unique { columns: Vec<String> },
+    // Kept as source text and parsed when rows are checked
+    Check { expression: String },
+    // Always points at the referenced table's primary key
+    ForeignKey { column: String, ref_table: String },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Index {
     pub indexed_column: String,
     pub index_type: FieldDataType,
     pub index_data: IndexStore,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum IndexStore {
     Text(BTreeMap<String, Vec<Value>>),
     #[serde(with = "index_number_map")]
@@ -29,13 +121,13 @@ pub enum IndexStore {
     Boolean(BTreeMap<bool, Vec<Value>>),
 }
 
-#[derive(Debug, Serialize, Deserialize, PartialEq)]
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 pub enum IndexNumber {
     Int(i64),
     Float(OrderedFloat),
 }
 
-#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, PartialOrd)]
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
 pub struct OrderedFloat(pub f64);
 
 impl Eq for OrderedFloat {}
@@ -46,6 +138,12 @@ impl Ord for OrderedFloat {
     }
 }
 
+impl PartialOrd for OrderedFloat {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
 impl Eq for IndexNumber {}
 
 impl Ord for IndexNumber {
@@ -81,9 +179,14 @@ pub struct FieldDef {
     pub serial: Option<SerialState>,
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
 pub struct SerialState {
-    pub next_val: u32,
+    // The owned sequence values come from
+    #[serde(default)]
+    pub sequence: Option<String>,
+    // Counter from before sequences existed, carried into a sequence the first time the column is used
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub next_val: Option<i64>,
 }
 
 pub mod index_number_map {
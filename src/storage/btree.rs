@@ -0,0 +1,423 @@
+use std::cmp::Ordering;
+
+use serde_json::Value;
+
+use crate::storage::page::{Node, PageId, NO_PAGE, PAGE_SIZE};
+use crate::storage::pager::Pager;
+
+// B+tree of rows keyed by primary key. Leaves are chained left to right for scans.
+// Deletes don't rebalance, so emptied leaves simply stay in the chain until reused by later inserts.
+
+// Longer values go to a chain of overflow pages, so a leaf always has room for several rows
+const INLINE_VALUE: usize = PAGE_SIZE / 8;
+// Keys are searched where they sit, so they can't overflow and are limited to this many bytes of JSON
+const MAX_KEY: usize = PAGE_SIZE / 8;
+// A leaf value starting with this is the first overflow page of the row. JSON text never does.
+const OVERFLOW_MARK: char = '#';
+
+pub fn get(pager: &mut Pager, key: &Value) -> Result<Option<Value>, String> {
+    let mut current = pager.root();
+    while current != NO_PAGE {
+        match pager.read_node(current)? {
+            Node::Internal { keys, children } => {
+                current = children[child_index(&keys, key)?];
+            },
+            Node::Leaf { keys, values, .. } => {
+                return match search(&keys, key)? {
+                    Ok(pos) => Ok(Some(decode(&read_value(pager, &values[pos])?)?)),
+                    Err(_) => Ok(None),
+                };
+            },
+            _ => return Err(format!("Corrupt tree at page {}", current)),
+        }
+    }
+    Ok(None)
+}
+
+// Fails for the keys upsert refuses, so a batch of rows can be checked before any is written
+pub fn check_key(key: &Value) -> Result<(), String> {
+    key_text(key).map(|_| ())
+}
+
+fn key_text(key: &Value) -> Result<String, String> {
+    let text = encode(key)?;
+    if text.len() > MAX_KEY {
+        return Err(format!("Key is {} bytes, more than the {} a key can take", text.len(), MAX_KEY));
+    }
+    Ok(text)
+}
+
+pub fn upsert(pager: &mut Pager, key: &Value, value: &Value) -> Result<(), String> {
+    let key_text = key_text(key)?;
+    let value_text = encode(value)?;
+
+    if pager.root() == NO_PAGE {
+        let root = pager.allocate()?;
+        pager.write_node(root, &Node::Leaf { keys: vec![], values: vec![], next: NO_PAGE })?;
+        pager.set_root(root);
+    }
+
+    let root = pager.root();
+    if let Some((separator, right)) = insert_into(pager, root, key, key_text, value_text)? {
+        let new_root = pager.allocate()?;
+        pager.write_node(new_root, &Node::Internal { keys: vec![separator], children: vec![root, right] })?;
+        pager.set_root(new_root);
+    }
+    Ok(())
+}
+
+pub fn remove(pager: &mut Pager, key: &Value) -> Result<bool, String> {
+    let mut current = pager.root();
+    while current != NO_PAGE {
+        match pager.read_node(current)? {
+            Node::Internal { keys, children } => {
+                current = children[child_index(&keys, key)?];
+            },
+            Node::Leaf { mut keys, mut values, next } => {
+                return match search(&keys, key)? {
+                    Ok(pos) => {
+                        keys.remove(pos);
+                        free_value(pager, &values.remove(pos))?;
+                        pager.write_node(current, &Node::Leaf { keys, values, next })?;
+                        Ok(true)
+                    },
+                    Err(_) => Ok(false),
+                };
+            },
+            _ => return Err(format!("Corrupt tree at page {}", current)),
+        }
+    }
+    Ok(false)
+}
+
+// Walks the leaf chain, returning every row in key order
+pub fn scan(pager: &mut Pager) -> Result<Vec<(Value, Value)>, String> {
+    let mut rows = vec![];
+    let mut current = first_leaf(pager)?;
+    while current != NO_PAGE {
+        match pager.read_node(current)? {
+            Node::Leaf { keys, values, next } => {
+                for (key, value) in keys.iter().zip(values.iter()) {
+                    rows.push((decode(key)?, decode(&read_value(pager, value)?)?));
+                }
+                current = next;
+            },
+            _ => return Err(format!("Corrupt leaf chain at page {}", current)),
+        }
+    }
+    Ok(rows)
+}
+
+// Every key in order, without reading the rows
+pub fn scan_keys(pager: &mut Pager) -> Result<Vec<Value>, String> {
+    let mut found = vec![];
+    let mut current = first_leaf(pager)?;
+    while current != NO_PAGE {
+        match pager.read_node(current)? {
+            Node::Leaf { keys, next, .. } => {
+                for key in &keys {
+                    found.push(decode(key)?);
+                }
+                current = next;
+            },
+            _ => return Err(format!("Corrupt leaf chain at page {}", current)),
+        }
+    }
+    Ok(found)
+}
+
+fn first_leaf(pager: &mut Pager) -> Result<PageId, String> {
+    let mut current = pager.root();
+    while current != NO_PAGE {
+        match pager.read_node(current)? {
+            Node::Internal { children, .. } => current = children[0],
+            Node::Leaf { .. } => break,
+            _ => return Err(format!("Corrupt tree at page {}", current)),
+        }
+    }
+    Ok(current)
+}
+
+pub fn compare_keys(a: &Value, b: &Value) -> Ordering {
+    match (a, b) {
+        (Value::Number(x), Value::Number(y)) => match (x.as_i64(), y.as_i64()) {
+            (Some(x), Some(y)) => x.cmp(&y),
+            _ => x.as_f64().unwrap_or(0.0).partial_cmp(&y.as_f64().unwrap_or(0.0)).unwrap_or(Ordering::Equal),
+        },
+        (Value::String(x), Value::String(y)) => x.cmp(y),
+        (Value::Bool(x), Value::Bool(y)) => x.cmp(y),
+        _ => type_rank(a).cmp(&type_rank(b)),
+    }
+}
+
+fn type_rank(value: &Value) -> u8 {
+    match value {
+        Value::Null => 0,
+        Value::Bool(_) => 1,
+        Value::Number(_) => 2,
+        Value::String(_) => 3,
+        Value::Array(_) => 4,
+        Value::Object(_) => 5,
+    }
+}
+
+// Returns the separator key and new page when the node had to split
+fn insert_into(pager: &mut Pager, page: PageId, key: &Value, key_text: String, value_text: String) -> Result<Option<(String, PageId)>, String> {
+    match pager.read_node(page)? {
+        Node::Leaf { mut keys, mut values, next } => {
+            match search(&keys, key)? {
+                Ok(pos) => {
+                    if read_value(pager, &values[pos])? == value_text {
+                        return Ok(None);
+                    }
+                    free_value(pager, &values[pos])?;
+                    values[pos] = store_value(pager, value_text)?;
+                },
+                Err(pos) => {
+                    keys.insert(pos, key_text);
+                    values.insert(pos, store_value(pager, value_text)?);
+                },
+            }
+
+            let node = Node::Leaf { keys, values, next };
+            if node.fits() {
+                pager.write_node(page, &node)?;
+                return Ok(None);
+            }
+            let Node::Leaf { mut keys, mut values, next } = node else { unreachable!() };
+
+            let mid = split_point(keys.iter().zip(values.iter()).map(|(k, v)| k.len() + v.len()).collect());
+            if mid == 0 {
+                return Err("Row is too large to fit in a page".to_string());
+            }
+            let right_keys = keys.split_off(mid);
+            let right_values = values.split_off(mid);
+            let separator = right_keys[0].clone();
+            let right_page = pager.allocate()?;
+
+            let right = Node::Leaf { keys: right_keys, values: right_values, next };
+            let left = Node::Leaf { keys, values, next: right_page };
+            if !left.fits() || !right.fits() {
+                return Err("Row is too large to fit in a page".to_string());
+            }
+            pager.write_node(right_page, &right)?;
+            pager.write_node(page, &left)?;
+            Ok(Some((separator, right_page)))
+        },
+        Node::Internal { mut keys, mut children } => {
+            let idx = child_index(&keys, key)?;
+            let split = insert_into(pager, children[idx], key, key_text, value_text)?;
+            let Some((separator, new_child)) = split else { return Ok(None) };
+
+            keys.insert(idx, separator);
+            children.insert(idx + 1, new_child);
+            let node = Node::Internal { keys, children };
+            if node.fits() {
+                pager.write_node(page, &node)?;
+                return Ok(None);
+            }
+            let Node::Internal { mut keys, mut children } = node else { unreachable!() };
+
+            // The middle key moves up to the parent rather than staying in either half
+            let mid = keys.len() / 2;
+            let right_keys = keys.split_off(mid + 1);
+            let promoted = keys.pop().unwrap();
+            let right_children = children.split_off(mid + 1);
+            let right_page = pager.allocate()?;
+
+            pager.write_node(right_page, &Node::Internal { keys: right_keys, children: right_children })?;
+            pager.write_node(page, &Node::Internal { keys, children })?;
+            Ok(Some((promoted, right_page)))
+        },
+        _ => Err(format!("Corrupt tree at page {}", page)),
+    }
+}
+
+// Picks a split so both halves hold roughly the same number of bytes
+fn split_point(sizes: Vec<usize>) -> usize {
+    if sizes.len() < 2 {
+        return 0;
+    }
+    let total: usize = sizes.iter().sum();
+    let mut running = 0;
+    for (i, size) in sizes.iter().enumerate() {
+        running += size;
+        if running * 2 >= total {
+            return (i + 1).clamp(1, sizes.len() - 1);
+        }
+    }
+    sizes.len() / 2
+}
+
+fn search(keys: &[String], key: &Value) -> Result<Result<usize, usize>, String> {
+    let decoded = keys.iter().map(|k| decode(k)).collect::<Result<Vec<Value>, String>>()?;
+    Ok(decoded.binary_search_by(|k| compare_keys(k, key)))
+}
+
+// Number of separator keys less than or equal to the key
+fn child_index(keys: &[String], key: &Value) -> Result<usize, String> {
+    let decoded = keys.iter().map(|k| decode(k)).collect::<Result<Vec<Value>, String>>()?;
+    Ok(decoded.partition_point(|k| compare_keys(k, key) != Ordering::Greater))
+}
+
+// The text kept in the leaf: the value itself, or where its overflow pages start
+fn store_value(pager: &mut Pager, text: String) -> Result<String, String> {
+    if text.len() <= INLINE_VALUE {
+        return Ok(text);
+    }
+    let first = pager.write_overflow(text.as_bytes())?;
+    Ok(format!("{}{}", OVERFLOW_MARK, first))
+}
+
+fn read_value(pager: &mut Pager, stored: &str) -> Result<String, String> {
+    match overflow_page(stored) {
+        Some(first) => String::from_utf8(pager.read_overflow(first)?).map_err(|_| format!("Corrupt overflow chain at page {}", first)),
+        None => Ok(stored.to_string()),
+    }
+}
+
+fn free_value(pager: &mut Pager, stored: &str) -> Result<(), String> {
+    match overflow_page(stored) {
+        Some(first) => pager.free_overflow(first),
+        None => Ok(()),
+    }
+}
+
+fn overflow_page(stored: &str) -> Option<PageId> {
+    stored.strip_prefix(OVERFLOW_MARK)?.parse().ok()
+}
+
+fn encode(value: &Value) -> Result<String, String> {
+    serde_json::to_string(value).map_err(|e| format!("Could not encode value: {}", e))
+}
+
+fn decode(text: &str) -> Result<Value, String> {
+    serde_json::from_str(text).map_err(|e| format!("Could not decode value: {}", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs, path::PathBuf};
+
+    use serde_json::{json, Value};
+
+    use super::*;
+
+    // A fresh page file for each test, removed again when dropped
+    struct TempPager {
+        path: PathBuf,
+        pager: Pager,
+    }
+
+    impl TempPager {
+        fn new(name: &str) -> TempPager {
+            let path = env::temp_dir().join(format!("ezpzdb-btree-{}-{}.pages", std::process::id(), name));
+            let _ = fs::remove_file(&path);
+            let pager = Pager::open(&path, true).unwrap();
+            TempPager { path, pager }
+        }
+
+        fn reopen(&mut self) {
+            self.pager.flush().unwrap();
+            self.pager = Pager::open(&self.path, false).unwrap();
+        }
+    }
+
+    impl Drop for TempPager {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.path);
+        }
+    }
+
+    fn row(i: i64) -> Value {
+        json!({ "id": i, "name": format!("row {:0>80}", i) })
+    }
+
+    fn keys(pager: &mut Pager) -> Vec<i64> {
+        scan_keys(pager).unwrap().iter().map(|k| k.as_i64().unwrap()).collect()
+    }
+
+    #[test]
+    fn inserts_split_into_a_sorted_tree() {
+        let mut file = TempPager::new("split");
+        // Out of order, so splits happen in the middle of leaves as well as at the end
+        for i in (0..400).map(|i| (i * 37) % 400) {
+            upsert(&mut file.pager, &Value::from(i), &row(i)).unwrap();
+        }
+        file.reopen();
+        let pager = &mut file.pager;
+
+        assert!(matches!(pager.read_node(pager.root()).unwrap(), Node::Internal { .. }));
+        assert_eq!(keys(pager), (0..400).collect::<Vec<i64>>());
+        assert_eq!(get(pager, &Value::from(123)).unwrap(), Some(row(123)));
+        assert_eq!(get(pager, &Value::from(400)).unwrap(), None);
+        let rows = scan(pager).unwrap();
+        assert_eq!(rows.len(), 400);
+        assert_eq!(rows[7], (Value::from(7), row(7)));
+    }
+
+    #[test]
+    fn upsert_replaces_the_row_under_a_key() {
+        let mut file = TempPager::new("upsert");
+        let pager = &mut file.pager;
+        upsert(pager, &Value::from(1), &row(1)).unwrap();
+        upsert(pager, &Value::from(1), &json!({ "id": 1, "name": "changed" })).unwrap();
+        assert_eq!(get(pager, &Value::from(1)).unwrap(), Some(json!({ "id": 1, "name": "changed" })));
+        assert_eq!(keys(pager), vec![1]);
+    }
+
+    #[test]
+    fn removed_keys_are_gone_from_gets_and_scans() {
+        let mut file = TempPager::new("remove");
+        for i in 0..300 {
+            upsert(&mut file.pager, &Value::from(i), &row(i)).unwrap();
+        }
+        for i in (0..300).filter(|i| i % 3 != 0) {
+            assert!(remove(&mut file.pager, &Value::from(i)).unwrap());
+        }
+        assert!(!remove(&mut file.pager, &Value::from(1)).unwrap());
+        file.reopen();
+
+        assert_eq!(keys(&mut file.pager), (0..300).filter(|i| i % 3 == 0).collect::<Vec<i64>>());
+        assert_eq!(get(&mut file.pager, &Value::from(2)).unwrap(), None);
+        assert_eq!(get(&mut file.pager, &Value::from(3)).unwrap(), Some(row(3)));
+    }
+
+    #[test]
+    fn text_keys_scan_in_order() {
+        let mut file = TempPager::new("text");
+        for name in ["pear", "apple", "fig", "banana"] {
+            upsert(&mut file.pager, &Value::from(name), &json!({ "name": name })).unwrap();
+        }
+        let names: Vec<Value> = scan_keys(&mut file.pager).unwrap();
+        assert_eq!(names, vec![json!("apple"), json!("banana"), json!("fig"), json!("pear")]);
+    }
+
+    #[test]
+    fn long_values_go_to_overflow_pages() {
+        let mut file = TempPager::new("overflow");
+        let long = json!({ "id": 1, "text": "x".repeat(5000) });
+        upsert(&mut file.pager, &Value::from(1), &long).unwrap();
+        upsert(&mut file.pager, &Value::from(2), &row(2)).unwrap();
+        file.reopen();
+        assert_eq!(get(&mut file.pager, &Value::from(1)).unwrap(), Some(long.clone()));
+        assert_eq!(scan(&mut file.pager).unwrap()[0], (Value::from(1), long.clone()));
+
+        // The pages freed by the short row are reused for the next long one
+        upsert(&mut file.pager, &Value::from(1), &row(1)).unwrap();
+        file.reopen();
+        let size = fs::metadata(&file.path).unwrap().len();
+        upsert(&mut file.pager, &Value::from(3), &long).unwrap();
+        file.reopen();
+        assert_eq!(fs::metadata(&file.path).unwrap().len(), size);
+        assert_eq!(get(&mut file.pager, &Value::from(3)).unwrap(), Some(long));
+    }
+
+    #[test]
+    fn keys_too_long_for_a_page_are_refused() {
+        let mut file = TempPager::new("long-key");
+        let key = Value::from("k".repeat(1000));
+        let error = upsert(&mut file.pager, &key, &json!({})).unwrap_err();
+        assert!(error.contains("more than the 512 a key can take"), "{}", error);
+    }
+}
@@ -0,0 +1,122 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom, Write};
+
+use crate::storage::page::{PageId, PAGE_SIZE};
+
+// Holds recently used pages in memory, and only writes back the ones that changed
+pub struct BufferPool {
+    file: File,
+    frames: HashMap<PageId, Frame>,
+    capacity: usize,
+    clock: u64,
+    page_count: u32,
+}
+
+struct Frame {
+    data: Vec<u8>,
+    dirty: bool,
+    last_used: u64,
+}
+
+impl BufferPool {
+    pub fn new(file: File, capacity: usize) -> Result<BufferPool, String> {
+        let len = file.metadata().map_err(|e| format!("Could not read page file: {}", e))?.len();
+        Ok(BufferPool {
+            file,
+            frames: HashMap::new(),
+            capacity: capacity.max(1),
+            clock: 0,
+            page_count: (len / PAGE_SIZE as u64) as u32,
+        })
+    }
+
+    pub fn page_count(&self) -> u32 {
+        self.page_count
+    }
+
+    pub fn read(&mut self, id: PageId) -> Result<&[u8], String> {
+        self.load(id)?;
+        Ok(&self.frames[&id].data)
+    }
+
+    // Marks the page dirty only when its contents actually change
+    pub fn write(&mut self, id: PageId, bytes: &[u8]) -> Result<(), String> {
+        if bytes.len() > PAGE_SIZE {
+            return Err(format!("Page overflow: {} bytes", bytes.len()));
+        }
+        let mut data = bytes.to_vec();
+        data.resize(PAGE_SIZE, 0);
+
+        self.load(id)?;
+        let frame = self.frames.get_mut(&id).unwrap();
+        if frame.data != data {
+            frame.data = data;
+            frame.dirty = true;
+        }
+        Ok(())
+    }
+
+    // Adds a zeroed page to the end of the file
+    pub fn append(&mut self) -> Result<PageId, String> {
+        let id = self.page_count;
+        self.page_count += 1;
+        self.make_room()?;
+        self.clock += 1;
+        self.frames.insert(id, Frame { data: vec![0; PAGE_SIZE], dirty: true, last_used: self.clock });
+        Ok(id)
+    }
+
+    // Writes every dirty page back to the file, and returns how many were written
+    pub fn flush(&mut self) -> Result<usize, String> {
+        let mut dirty: Vec<PageId> = self.frames.iter().filter(|(_, f)| f.dirty).map(|(id, _)| *id).collect();
+        dirty.sort();
+        for id in &dirty {
+            let data = self.frames[id].data.clone();
+            self.write_to_file(*id, &data)?;
+            self.frames.get_mut(id).unwrap().dirty = false;
+        }
+        self.file.sync_data().map_err(|e| format!("Could not sync page file: {}", e))?;
+        Ok(dirty.len())
+    }
+
+    fn load(&mut self, id: PageId) -> Result<(), String> {
+        self.clock += 1;
+        if let Some(frame) = self.frames.get_mut(&id) {
+            frame.last_used = self.clock;
+            return Ok(());
+        }
+        if id >= self.page_count {
+            return Err(format!("Page {} is out of range", id));
+        }
+
+        self.make_room()?;
+        let mut data = vec![0; PAGE_SIZE];
+        self.file.seek(SeekFrom::Start(id as u64 * PAGE_SIZE as u64))
+            .and_then(|_| self.file.read_exact(&mut data))
+            .map_err(|e| format!("Could not read page {}: {}", id, e))?;
+        self.frames.insert(id, Frame { data, dirty: false, last_used: self.clock });
+        Ok(())
+    }
+
+    // Evicts the least recently used page once the pool is full
+    fn make_room(&mut self) -> Result<(), String> {
+        if self.frames.len() < self.capacity {
+            return Ok(());
+        }
+        let victim = self.frames.iter().min_by_key(|(_, f)| f.last_used).map(|(id, _)| *id);
+        if let Some(id) = victim {
+            let frame = self.frames.remove(&id).unwrap();
+            if frame.dirty {
+                self.write_to_file(id, &frame.data)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn write_to_file(&mut self, id: PageId, data: &[u8]) -> Result<(), String> {
+        self.file.seek(SeekFrom::Start(id as u64 * PAGE_SIZE as u64))
+            .and_then(|_| self.file.write_all(data))
+            .map_err(|e| format!("Could not write page {}: {}", id, e))
+    }
+}
@@ -0,0 +1,105 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+use serde_json::Value;
+
+use crate::models::{Catalog, Table};
+use crate::storage::{json::JsonBackend, paged::PagedBackend, StorageBackend, StorageKind};
+
+// Tables stored as files in one directory. Existing tables keep whichever format they
+// were created with, and new tables use the default format.
+pub struct FileBackend {
+    json: JsonBackend,
+    paged: PagedBackend,
+    default_kind: StorageKind,
+}
+
+impl FileBackend {
+    pub fn new(dir: PathBuf, default_kind: StorageKind) -> FileBackend {
+        FileBackend {
+            json: JsonBackend::new(dir.clone()),
+            paged: PagedBackend::new(dir),
+            default_kind,
+        }
+    }
+
+    fn backend_for(&mut self, name: &str) -> &mut dyn StorageBackend {
+        if self.paged.path(name).exists() {
+            &mut self.paged
+        } else if self.json.path(name).exists() {
+            &mut self.json
+        } else {
+            match self.default_kind {
+                StorageKind::Json => &mut self.json,
+                StorageKind::Paged => &mut self.paged,
+            }
+        }
+    }
+}
+
+impl StorageBackend for FileBackend {
+    fn load_table(&mut self, name: &str) -> Result<Table, String> {
+        self.backend_for(name).load_table(name)
+    }
+
+    fn save_table(&mut self, table: &Table) -> Result<(), String> {
+        self.backend_for(&table.name).save_table(table)
+    }
+
+    fn list_tables(&mut self) -> Result<Vec<String>, String> {
+        let mut names = self.json.list_tables()?;
+        names.extend(self.paged.list_tables()?);
+        names.sort();
+        names.dedup();
+        Ok(names)
+    }
+
+    fn drop_table(&mut self, name: &str) -> Result<(), String> {
+        self.backend_for(name).drop_table(name)
+    }
+
+    fn rename_table(&mut self, from: &str, to: &str) -> Result<(), String> {
+        self.backend_for(from).rename_table(from, to)
+    }
+
+    // Both formats share one catalog file in the directory
+    fn load_catalog(&mut self) -> Result<Option<Catalog>, String> {
+        self.json.load_catalog()
+    }
+
+    fn save_catalog(&mut self, catalog: &Catalog) -> Result<(), String> {
+        self.json.save_catalog(catalog)
+    }
+
+    fn load_schema(&mut self, name: &str) -> Result<Table, String> {
+        self.backend_for(name).load_schema(name)
+    }
+
+    fn get_row(&mut self, name: &str, key: &Value) -> Result<Option<Value>, String> {
+        self.backend_for(name).get_row(name, key)
+    }
+
+    fn get_rows(&mut self, name: &str, keys: &[Value]) -> Result<HashMap<Value, Value>, String> {
+        self.backend_for(name).get_rows(name, keys)
+    }
+
+    fn put_row(&mut self, name: &str, key: &Value, row: &Value) -> Result<(), String> {
+        self.backend_for(name).put_row(name, key, row)
+    }
+
+    fn delete_row(&mut self, name: &str, key: &Value) -> Result<(), String> {
+        self.backend_for(name).delete_row(name, key)
+    }
+
+    fn save_schema(&mut self, table: &Table) -> Result<(), String> {
+        self.backend_for(&table.name).save_schema(table)
+    }
+
+    fn save_rows(&mut self, table: &Table, keys: &[Value]) -> Result<(), String> {
+        self.backend_for(&table.name).save_rows(table, keys)
+    }
+
+    fn truncate_table(&mut self, table: &Table) -> Result<(), String> {
+        self.backend_for(&table.name).truncate_table(table)
+    }
+}
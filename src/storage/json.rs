@@ -0,0 +1,91 @@
+use std::fs;
+use std::path::PathBuf;
+
+use serde_json::Value;
+
+use crate::models::{Catalog, Table};
+use crate::storage::{move_table_file, read_catalog_file, remove_table_file, table_path, tables_with_extension, with_rows, write_catalog_file, write_table_file, StorageBackend};
+
+// The original storage format: one pretty-printed JSON document per table
+pub struct JsonBackend {
+    dir: PathBuf,
+}
+
+impl JsonBackend {
+    pub fn new(dir: PathBuf) -> JsonBackend {
+        JsonBackend { dir }
+    }
+
+    pub fn path(&self, name: &str) -> PathBuf {
+        table_path(&self.dir, name, "db")
+    }
+}
+
+impl StorageBackend for JsonBackend {
+    fn load_table(&mut self, name: &str) -> Result<Table, String> {
+        let file = fs::read_to_string(self.path(name)).map_err(|_| "Could not read file".to_string())?;
+        let mut table = serde_json::from_str::<Table>(&file).map_err(|_| "Could not build table from file".to_string())?;
+        restore_keys(&mut table);
+        Ok(table)
+    }
+
+    fn save_table(&mut self, table: &Table) -> Result<(), String> {
+        let store_json = serde_json::to_string_pretty(table).map_err(|_| "Table could not convert to JSON".to_string())?;
+        write_table_file(&self.path(&table.name), store_json.as_bytes())
+    }
+
+    // The whole file is rewritten whatever changed, so it is read and written once for every row
+    fn save_rows(&mut self, table: &Table, keys: &[Value]) -> Result<(), String> {
+        let mut data = self.load_table(&table.name)?.data;
+        for key in keys {
+            match table.data.get(key) {
+                Some(row) => { data.insert(key.clone(), row.clone()); },
+                None => { data.remove(key); },
+            }
+        }
+        self.save_table(&with_rows(table, data))
+    }
+
+    fn list_tables(&mut self) -> Result<Vec<String>, String> {
+        tables_with_extension(&self.dir, "db")
+    }
+
+    fn drop_table(&mut self, name: &str) -> Result<(), String> {
+        remove_table_file(&self.dir, &self.path(name))
+    }
+
+    // The file moves as-is, then only the name stored inside it gets rewritten
+    fn rename_table(&mut self, from: &str, to: &str) -> Result<(), String> {
+        move_table_file(&self.path(from), &self.path(to))?;
+        let mut table = self.load_table(to)?;
+        table.name = to.to_string();
+        self.save_table(&table)
+    }
+
+    fn load_catalog(&mut self) -> Result<Option<Catalog>, String> {
+        read_catalog_file(&self.dir)
+    }
+
+    fn save_catalog(&mut self, catalog: &Catalog) -> Result<(), String> {
+        write_catalog_file(&self.dir, catalog)
+    }
+}
+
+// JSON object keys are always strings, so numeric and boolean keys get rebuilt from the primary key column.
+// Tables without one are keyed by row number.
+fn restore_keys(table: &mut Table) {
+    if let Some(key) = table.schema.iter().find(|f| f.primary_key) {
+        table.data = std::mem::take(&mut table.data)
+            .into_values()
+            .map(|row| (row[&key.name].clone(), row))
+            .collect();
+    } else {
+        table.data = std::mem::take(&mut table.data)
+            .into_iter()
+            .map(|(key, row)| match key.as_str().and_then(|k| k.parse::<u64>().ok()) {
+                Some(n) => (Value::from(n), row),
+                None => (key, row),
+            })
+            .collect();
+    }
+}
@@ -0,0 +1,79 @@
+use std::collections::HashMap;
+
+use serde_json::Value;
+
+use crate::models::{Catalog, Table};
+use crate::storage::StorageBackend;
+
+// Keeps tables in memory only, for tests and throwaway sessions
+#[derive(Default)]
+pub struct MemoryBackend {
+    tables: HashMap<String, Table>,
+    catalog: Option<Catalog>,
+}
+
+impl MemoryBackend {
+    pub fn new() -> MemoryBackend {
+        MemoryBackend::default()
+    }
+}
+
+impl StorageBackend for MemoryBackend {
+    fn load_table(&mut self, name: &str) -> Result<Table, String> {
+        self.tables.get(name).cloned().ok_or_else(|| "Could not read file".to_string())
+    }
+
+    fn save_table(&mut self, table: &Table) -> Result<(), String> {
+        self.tables.insert(table.name.clone(), table.clone());
+        Ok(())
+    }
+
+    fn list_tables(&mut self) -> Result<Vec<String>, String> {
+        let mut names: Vec<String> = self.tables.keys().cloned().collect();
+        names.sort();
+        Ok(names)
+    }
+
+    fn drop_table(&mut self, name: &str) -> Result<(), String> {
+        self.tables.remove(name).map(|_| ()).ok_or_else(|| format!("Table {} not found", name))
+    }
+
+    fn load_catalog(&mut self) -> Result<Option<Catalog>, String> {
+        Ok(self.catalog.clone())
+    }
+
+    fn save_catalog(&mut self, catalog: &Catalog) -> Result<(), String> {
+        self.catalog = Some(catalog.clone());
+        Ok(())
+    }
+
+    fn get_row(&mut self, name: &str, key: &Value) -> Result<Option<Value>, String> {
+        let table = self.tables.get(name).ok_or_else(|| "Could not read file".to_string())?;
+        Ok(table.data.get(key).cloned())
+    }
+
+    fn get_rows(&mut self, name: &str, keys: &[Value]) -> Result<HashMap<Value, Value>, String> {
+        let table = self.tables.get(name).ok_or_else(|| "Could not read file".to_string())?;
+        Ok(keys.iter().filter_map(|key| table.data.get(key).map(|row| (key.clone(), row.clone()))).collect())
+    }
+
+    fn put_row(&mut self, name: &str, key: &Value, row: &Value) -> Result<(), String> {
+        let table = self.tables.get_mut(name).ok_or_else(|| "Could not read file".to_string())?;
+        table.data.insert(key.clone(), row.clone());
+        Ok(())
+    }
+
+    fn delete_row(&mut self, name: &str, key: &Value) -> Result<(), String> {
+        let table = self.tables.get_mut(name).ok_or_else(|| "Could not read file".to_string())?;
+        table.data.remove(key);
+        Ok(())
+    }
+
+    fn save_schema(&mut self, table: &Table) -> Result<(), String> {
+        let stored = self.tables.get_mut(&table.name).ok_or_else(|| "Could not read file".to_string())?;
+        stored.schema = table.schema.clone();
+        stored.indexes = table.indexes.clone();
+        stored.constraints = table.constraints.clone();
+        Ok(())
+    }
+}
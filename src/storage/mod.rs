@@ -1,59 +1,184 @@
-use std::{collections::HashMap, fs};
+use std::{collections::HashMap, fs, path::{Path, PathBuf}};
+use clap::ValueEnum;
 use directories::UserDirs;
+use serde_json::Value;
 
-use crate::models::Table;
-
-pub fn save_to_disk(table: &String, store: &Table) {
-    if let Some(dirs) = UserDirs::new() {
-        let store_bin = serde_json::to_string_pretty(&store).unwrap();
-
-        let mut file_name = String::from(table);
-        file_name.push_str(".db");
-        let save_path = dirs.home_dir().join("Documents/ezpzdb/").join(file_name);
-        fs::write(save_path, store_bin).unwrap();
-    } else {
-        println!("No home directory found");
-    }
-}
-
-pub fn load_from_disk(table: &String) -> Table {
-    if let Some(dirs) = UserDirs::new() {
-        let mut file_name = String::from(table);
-        file_name.push_str(".db");
-        let load_path = dirs.home_dir().join("Documents/ezpzdb/").join(file_name);
-        let store = fs::read_to_string(load_path);
-        match store {
-            Ok(file) => {
-                let table = serde_json::from_str::<Table>(&file);
-                if table.is_ok() {
-                    table.unwrap()
-                } else {
-                    eprintln!("Error: Could not build table from file");
-                    Table {
-                        name: "".to_string(),
-                        schema: vec![],
-                        data: HashMap::new(),
-                        indexes: HashMap::new(),
-                    }
-                }
-            },
-            Err(_) => {
-                eprintln!("Error: Could not read file");
-                Table {
-                    name: "".to_string(),
-                    schema: vec![],
-                    data: HashMap::new(),
-                    indexes: HashMap::new(),
+use crate::models::{Catalog, Table};
+
+pub mod btree;
+pub mod buffer_pool;
+pub mod file;
+pub mod json;
+pub mod memory;
+pub mod page;
+pub mod paged;
+pub mod pager;
+
+pub trait StorageBackend {
+    fn load_table(&mut self, name: &str) -> Result<Table, String>;
+
+    fn save_table(&mut self, table: &Table) -> Result<(), String>;
+
+    fn list_tables(&mut self) -> Result<Vec<String>, String>;
+
+    fn drop_table(&mut self, name: &str) -> Result<(), String>;
+
+    // Moves a table to a new name, keeping its rows and storage format
+    fn rename_table(&mut self, from: &str, to: &str) -> Result<(), String> {
+        let mut table = self.load_table(from)?;
+        table.name = to.to_string();
+        self.save_table(&table)?;
+        self.drop_table(from)
+    }
+
+    // None until a catalog has been saved for the first time
+    fn load_catalog(&mut self) -> Result<Option<Catalog>, String>;
+
+    fn save_catalog(&mut self, catalog: &Catalog) -> Result<(), String>;
+
+    // Table with its schema and indexes, but no rows
+    fn load_schema(&mut self, name: &str) -> Result<Table, String> {
+        let mut table = self.load_table(name)?;
+        table.data.clear();
+        Ok(table)
+    }
+
+    fn get_row(&mut self, name: &str, key: &Value) -> Result<Option<Value>, String> {
+        let table = self.load_table(name)?;
+        Ok(table.data.get(key).cloned())
+    }
+
+    // The rows stored under any of `keys`, read together
+    fn get_rows(&mut self, name: &str, keys: &[Value]) -> Result<HashMap<Value, Value>, String> {
+        let mut table = self.load_table(name)?;
+        Ok(keys.iter().filter_map(|key| table.data.remove_entry(key)).collect())
+    }
+
+    // Writes one row, replacing any stored under the same key. Indexes are written with the schema.
+    fn put_row(&mut self, name: &str, key: &Value, row: &Value) -> Result<(), String> {
+        let mut table = self.load_table(name)?;
+        table.data.insert(key.clone(), row.clone());
+        self.save_table(&table)
+    }
+
+    fn delete_row(&mut self, name: &str, key: &Value) -> Result<(), String> {
+        let mut table = self.load_table(name)?;
+        table.data.remove(key);
+        self.save_table(&table)
+    }
+
+    // Writes the schema, indexes and constraints, leaving the stored rows as they are
+    fn save_schema(&mut self, table: &Table) -> Result<(), String> {
+        let stored = self.load_table(&table.name)?;
+        self.save_table(&with_rows(table, stored.data))
+    }
+
+    // Writes the rows under `keys` as `table` has them, removing the ones it no longer has, then
+    // the schema. Other stored rows are left alone, so `table` only needs the rows that changed.
+    fn save_rows(&mut self, table: &Table, keys: &[Value]) -> Result<(), String> {
+        for key in keys {
+            match table.data.get(key) {
+                Some(row) => self.put_row(&table.name, key, row)?,
+                None => self.delete_row(&table.name, key)?,
+            }
+        }
+        self.save_schema(table)
+    }
+
+    // Removes every stored row. The table passed in has already had its rows cleared.
+    fn truncate_table(&mut self, table: &Table) -> Result<(), String> {
+        self.save_table(table)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+pub enum StorageKind {
+    Json,
+    Paged,
+}
+
+// The schema, indexes and constraints of `table` over the given rows
+fn with_rows(table: &Table, data: HashMap<Value, Value>) -> Table {
+    Table { name: table.name.clone(), schema: table.schema.clone(), data, indexes: table.indexes.clone(), constraints: table.constraints.clone() }
+}
+
+pub fn data_dir() -> Option<PathBuf> {
+    UserDirs::new().map(|dirs| dirs.home_dir().join("Documents/ezpzdb/"))
+}
+
+// Tables in the default database sit in the root directory, and every other database gets a sub-directory
+fn table_path(dir: &Path, name: &str, extension: &str) -> PathBuf {
+    match name.split_once('.') {
+        Some((database, table)) => dir.join(database).join(format!("{}.{}", table, extension)),
+        None => dir.join(format!("{}.{}", name, extension)),
+    }
+}
+
+// Table names for every file with the given extension, qualified by database for sub-directories
+fn tables_with_extension(dir: &Path, extension: &str) -> Result<Vec<String>, String> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return Ok(vec![]),
+    };
+    let mut names = vec![];
+    for entry in entries.flatten() {
+        let path = entry.path();
+        if path.is_dir() {
+            let database = entry.file_name().to_string_lossy().to_string();
+            for table in tables_with_extension(&path, extension)? {
+                if !table.contains('.') {
+                    names.push(format!("{}.{}", database, table));
                 }
             }
+        } else if path.extension().is_some_and(|e| e == extension) {
+            if let Some(stem) = path.file_stem() {
+                names.push(stem.to_string_lossy().to_string());
+            }
         }
-    } else {
-        println!("Error: No home directory found");
-        Table {
-            name: "".to_string(),
-            schema: vec![],
-            data: HashMap::new(),
-            indexes: HashMap::new(),
+    }
+    names.sort();
+    Ok(names)
+}
+
+fn write_table_file(path: &Path, contents: &[u8]) -> Result<(), String> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent).map_err(|_| format!("Could not create directory {}", parent.display()))?;
+    }
+    fs::write(path, contents).map_err(|_| "Could not write file".to_string())
+}
+
+// Removes the table file, along with its database directory once that is empty
+fn remove_table_file(dir: &Path, path: &Path) -> Result<(), String> {
+    fs::remove_file(path).map_err(|_| "Could not remove table".to_string())?;
+    if let Some(parent) = path.parent() {
+        if parent != dir {
+            let _ = fs::remove_dir(parent);
         }
     }
+    Ok(())
+}
+
+fn move_table_file(from: &Path, to: &Path) -> Result<(), String> {
+    if to.exists() {
+        return Err(format!("{} already exists", to.display()));
+    }
+    if let Some(parent) = to.parent() {
+        fs::create_dir_all(parent).map_err(|_| format!("Could not create directory {}", parent.display()))?;
+    }
+    fs::rename(from, to).map_err(|_| "Could not rename table".to_string())
+}
+
+fn read_catalog_file(dir: &Path) -> Result<Option<Catalog>, String> {
+    let path = dir.join("catalog.json");
+    if !path.exists() {
+        return Ok(None);
+    }
+    let file = fs::read_to_string(path).map_err(|_| "Could not read catalog".to_string())?;
+    serde_json::from_str(&file).map(Some).map_err(|_| "Could not build catalog from file".to_string())
+}
+
+fn write_catalog_file(dir: &Path, catalog: &Catalog) -> Result<(), String> {
+    let catalog_json = serde_json::to_string_pretty(catalog).map_err(|_| "Catalog could not convert to JSON".to_string())?;
+    fs::create_dir_all(dir).map_err(|_| format!("Could not create directory {}", dir.display()))?;
+    fs::write(dir.join("catalog.json"), catalog_json).map_err(|_| "Could not write catalog".to_string())
 }
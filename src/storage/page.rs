@@ -0,0 +1,79 @@
+use serde::{Deserialize, Serialize};
+
+pub const PAGE_SIZE: usize = 4096;
+
+pub type PageId = u32;
+
+// Page 0 always holds the file header, so it doubles as the "no page" marker
+pub const NO_PAGE: PageId = 0;
+
+const MAGIC: [u8; 8] = *b"EZPZPAGE";
+
+// Bytes of a meta or overflow page left over for data once the node framing is accounted for
+pub const META_CHUNK: usize = PAGE_SIZE - 32;
+
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+pub struct FileHeader {
+    magic: [u8; 8],
+    pub page_count: u32,
+    pub root: PageId,
+    pub meta_head: PageId,
+    pub free_head: PageId,
+}
+
+impl Default for FileHeader {
+    fn default() -> FileHeader {
+        FileHeader {
+            magic: MAGIC,
+            page_count: 1,
+            root: NO_PAGE,
+            meta_head: NO_PAGE,
+            free_head: NO_PAGE,
+        }
+    }
+}
+
+impl FileHeader {
+    pub fn encode(&self) -> Result<Vec<u8>, String> {
+        bincode::serialize(self).map_err(|e| format!("Could not encode file header: {}", e))
+    }
+
+    pub fn decode(page: &[u8]) -> Result<FileHeader, String> {
+        let header: FileHeader = bincode::deserialize(page)
+            .map_err(|e| format!("Could not decode file header: {}", e))?;
+        if header.magic != MAGIC {
+            return Err("File is not an ezpzdb page file".to_string());
+        }
+        Ok(header)
+    }
+}
+
+// Everything stored in a page after the header.
+// Keys and values are kept as JSON text, as bincode can't round-trip a serde_json::Value.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub enum Node {
+    Leaf { keys: Vec<String>, values: Vec<String>, next: PageId },
+    Internal { keys: Vec<String>, children: Vec<PageId> },
+    Meta { bytes: Vec<u8>, next: PageId },
+    Free { next: PageId },
+    // Part of a row value too long to keep in its leaf
+    Overflow { bytes: Vec<u8>, next: PageId },
+}
+
+impl Node {
+    pub fn encode(&self) -> Result<Vec<u8>, String> {
+        let bytes = bincode::serialize(self).map_err(|e| format!("Could not encode page: {}", e))?;
+        if bytes.len() > PAGE_SIZE {
+            return Err(format!("Page overflow: {} bytes", bytes.len()));
+        }
+        Ok(bytes)
+    }
+
+    pub fn decode(page: &[u8]) -> Result<Node, String> {
+        bincode::deserialize(page).map_err(|e| format!("Could not decode page: {}", e))
+    }
+
+    pub fn fits(&self) -> bool {
+        bincode::serialized_size(self).is_ok_and(|size| size as usize <= PAGE_SIZE)
+    }
+}
@@ -0,0 +1,193 @@
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::models::{Catalog, Constraint, FieldDef, Index, Table};
+use crate::storage::btree;
+use crate::storage::pager::Pager;
+use crate::storage::{move_table_file, read_catalog_file, remove_table_file, table_path, tables_with_extension, write_catalog_file, StorageBackend};
+
+// Page-oriented storage: rows live in a B+tree keyed by primary key, and the schema and
+// indexes live in a chain of metadata pages. Only pages that change are written back.
+pub struct PagedBackend {
+    dir: PathBuf,
+}
+
+// Serialises to the same shape as a Table, without copying the table to drop its rows
+#[derive(Serialize)]
+struct TableMeta<'a> {
+    name: &'a String,
+    schema: &'a Vec<FieldDef>,
+    data: HashMap<Value, Value>,
+    indexes: &'a HashMap<String, Index>,
+    constraints: &'a Vec<Constraint>,
+}
+
+impl PagedBackend {
+    pub fn new(dir: PathBuf) -> PagedBackend {
+        PagedBackend { dir }
+    }
+
+    pub fn path(&self, name: &str) -> PathBuf {
+        table_path(&self.dir, name, "pages")
+    }
+
+    fn open(&self, name: &str) -> Result<Pager, String> {
+        let path = self.path(name);
+        if !path.exists() {
+            return Err("Could not read file".to_string());
+        }
+        Pager::open(&path, false)
+    }
+}
+
+impl StorageBackend for PagedBackend {
+    fn load_table(&mut self, name: &str) -> Result<Table, String> {
+        let mut pager = self.open(name)?;
+        let mut table = read_schema(&mut pager)?;
+        table.data = btree::scan(&mut pager)?.into_iter().collect();
+        Ok(table)
+    }
+
+    fn load_schema(&mut self, name: &str) -> Result<Table, String> {
+        let mut pager = self.open(name)?;
+        read_schema(&mut pager)
+    }
+
+    fn get_row(&mut self, name: &str, key: &Value) -> Result<Option<Value>, String> {
+        let mut pager = self.open(name)?;
+        btree::get(&mut pager, key)
+    }
+
+    fn save_table(&mut self, table: &Table) -> Result<(), String> {
+        let path = self.path(&table.name);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).map_err(|_| format!("Could not create directory {}", parent.display()))?;
+        }
+        table.data.keys().try_for_each(btree::check_key)?;
+        let mut pager = Pager::open(&path, true)?;
+        write_schema(&mut pager, table)?;
+
+        // Rows that haven't changed leave their leaf untouched, so it never gets flushed
+        for (key, row) in &table.data {
+            btree::upsert(&mut pager, key, row)?;
+        }
+        let stored = btree::scan_keys(&mut pager)?;
+        let current: HashSet<&Value> = table.data.keys().collect();
+        for key in stored.iter().filter(|k| !current.contains(k)) {
+            btree::remove(&mut pager, key)?;
+        }
+
+        pager.flush()?;
+        Ok(())
+    }
+
+    fn get_rows(&mut self, name: &str, keys: &[Value]) -> Result<HashMap<Value, Value>, String> {
+        let mut pager = self.open(name)?;
+        let mut rows = HashMap::new();
+        for key in keys {
+            if let Some(row) = btree::get(&mut pager, key)? {
+                rows.insert(key.clone(), row);
+            }
+        }
+        Ok(rows)
+    }
+
+    fn put_row(&mut self, name: &str, key: &Value, row: &Value) -> Result<(), String> {
+        let mut pager = self.open(name)?;
+        btree::upsert(&mut pager, key, row)?;
+        pager.flush()?;
+        Ok(())
+    }
+
+    fn delete_row(&mut self, name: &str, key: &Value) -> Result<(), String> {
+        let mut pager = self.open(name)?;
+        btree::remove(&mut pager, key)?;
+        pager.flush()?;
+        Ok(())
+    }
+
+    fn save_schema(&mut self, table: &Table) -> Result<(), String> {
+        let mut pager = self.open(&table.name)?;
+        write_schema(&mut pager, table)?;
+        pager.flush()?;
+        Ok(())
+    }
+
+    // One pass over the file for every row, flushing once at the end
+    fn save_rows(&mut self, table: &Table, keys: &[Value]) -> Result<(), String> {
+        keys.iter().filter(|key| table.data.contains_key(*key)).try_for_each(btree::check_key)?;
+        let mut pager = self.open(&table.name)?;
+        write_schema(&mut pager, table)?;
+        for key in keys {
+            match table.data.get(key) {
+                Some(row) => btree::upsert(&mut pager, key, row)?,
+                None => { btree::remove(&mut pager, key)?; },
+            }
+        }
+        pager.flush()?;
+        Ok(())
+    }
+
+    fn list_tables(&mut self) -> Result<Vec<String>, String> {
+        tables_with_extension(&self.dir, "pages")
+    }
+
+    fn drop_table(&mut self, name: &str) -> Result<(), String> {
+        remove_table_file(&self.dir, &self.path(name))
+    }
+
+    // The file moves as-is, then only the name stored inside it gets rewritten
+    fn rename_table(&mut self, from: &str, to: &str) -> Result<(), String> {
+        move_table_file(&self.path(from), &self.path(to))?;
+        let mut table = self.load_table(to)?;
+        table.name = to.to_string();
+        self.save_table(&table)
+    }
+
+    // Starting a new file is cheaper than removing every key from the tree. It replaces the old
+    // one only once it is complete.
+    fn truncate_table(&mut self, table: &Table) -> Result<(), String> {
+        let path = self.path(&table.name);
+        let temp = path.with_extension("pages.tmp");
+        let _ = fs::remove_file(&temp);
+        if let Err(e) = write_new_file(&temp, table) {
+            let _ = fs::remove_file(&temp);
+            return Err(e);
+        }
+        fs::rename(&temp, &path).map_err(|_| "Could not replace table file".to_string())
+    }
+
+    fn load_catalog(&mut self) -> Result<Option<Catalog>, String> {
+        read_catalog_file(&self.dir)
+    }
+
+    fn save_catalog(&mut self, catalog: &Catalog) -> Result<(), String> {
+        write_catalog_file(&self.dir, catalog)
+    }
+}
+
+fn write_new_file(path: &Path, table: &Table) -> Result<(), String> {
+    table.data.keys().try_for_each(btree::check_key)?;
+    let mut pager = Pager::open(path, true)?;
+    write_schema(&mut pager, table)?;
+    for (key, row) in &table.data {
+        btree::upsert(&mut pager, key, row)?;
+    }
+    pager.flush()?;
+    Ok(())
+}
+
+fn read_schema(pager: &mut Pager) -> Result<Table, String> {
+    let bytes = pager.read_meta()?;
+    serde_json::from_slice::<Table>(&bytes).map_err(|_| "Could not build table from file".to_string())
+}
+
+fn write_schema(pager: &mut Pager, table: &Table) -> Result<(), String> {
+    let meta = TableMeta { name: &table.name, schema: &table.schema, data: HashMap::new(), indexes: &table.indexes, constraints: &table.constraints };
+    let meta_bytes = serde_json::to_vec(&meta).map_err(|_| "Schema could not convert to JSON".to_string())?;
+    pager.write_meta(&meta_bytes)
+}
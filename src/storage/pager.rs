@@ -0,0 +1,170 @@
+use std::fs::OpenOptions;
+use std::path::Path;
+
+use crate::storage::buffer_pool::BufferPool;
+use crate::storage::page::{FileHeader, Node, PageId, META_CHUNK, NO_PAGE};
+
+const POOL_CAPACITY: usize = 64;
+
+// Owns a page file: the header on page 0, page allocation, and the table metadata chain
+pub struct Pager {
+    pool: BufferPool,
+    header: FileHeader,
+}
+
+impl Pager {
+    pub fn open(path: &Path, create: bool) -> Result<Pager, String> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(create)
+            .truncate(false)
+            .open(path)
+            .map_err(|e| format!("Could not open {}: {}", path.display(), e))?;
+        let mut pool = BufferPool::new(file, POOL_CAPACITY)?;
+
+        let header = if pool.page_count() == 0 {
+            let header = FileHeader::default();
+            let id = pool.append()?;
+            pool.write(id, &header.encode()?)?;
+            header
+        } else {
+            FileHeader::decode(pool.read(0)?)?
+        };
+        Ok(Pager { pool, header })
+    }
+
+    pub fn root(&self) -> PageId {
+        self.header.root
+    }
+
+    pub fn set_root(&mut self, root: PageId) {
+        self.header.root = root;
+    }
+
+    pub fn read_node(&mut self, id: PageId) -> Result<Node, String> {
+        Node::decode(self.pool.read(id)?)
+    }
+
+    pub fn write_node(&mut self, id: PageId, node: &Node) -> Result<(), String> {
+        let bytes = node.encode()?;
+        self.pool.write(id, &bytes)
+    }
+
+    // Reuses a page from the free list when there is one
+    pub fn allocate(&mut self) -> Result<PageId, String> {
+        if self.header.free_head != NO_PAGE {
+            let id = self.header.free_head;
+            match self.read_node(id)? {
+                Node::Free { next } => self.header.free_head = next,
+                _ => return Err(format!("Corrupt free list at page {}", id)),
+            }
+            return Ok(id);
+        }
+        let id = self.pool.append()?;
+        self.header.page_count = self.pool.page_count();
+        Ok(id)
+    }
+
+    pub fn free(&mut self, id: PageId) -> Result<(), String> {
+        self.write_node(id, &Node::Free { next: self.header.free_head })?;
+        self.header.free_head = id;
+        Ok(())
+    }
+
+    pub fn read_meta(&mut self) -> Result<Vec<u8>, String> {
+        let mut bytes = vec![];
+        let mut current = self.header.meta_head;
+        while current != NO_PAGE {
+            match self.read_node(current)? {
+                Node::Meta { bytes: chunk, next } => {
+                    bytes.extend(chunk);
+                    current = next;
+                },
+                _ => return Err(format!("Corrupt metadata at page {}", current)),
+            }
+        }
+        Ok(bytes)
+    }
+
+    // Rewrites the metadata chain in place, so unchanged chunks leave their pages clean
+    pub fn write_meta(&mut self, bytes: &[u8]) -> Result<(), String> {
+        let mut existing = vec![];
+        let mut current = self.header.meta_head;
+        while current != NO_PAGE {
+            existing.push(current);
+            current = match self.read_node(current)? {
+                Node::Meta { next, .. } => next,
+                _ => return Err(format!("Corrupt metadata at page {}", current)),
+            };
+        }
+
+        let chunks: Vec<&[u8]> = bytes.chunks(META_CHUNK).collect();
+        let mut pages = vec![];
+        for i in 0..chunks.len() {
+            match existing.get(i) {
+                Some(id) => pages.push(*id),
+                None => pages.push(self.allocate()?),
+            }
+        }
+        for id in existing.iter().skip(chunks.len()) {
+            self.free(*id)?;
+        }
+
+        for (i, chunk) in chunks.iter().enumerate() {
+            let next = pages.get(i + 1).copied().unwrap_or(NO_PAGE);
+            self.write_node(pages[i], &Node::Meta { bytes: chunk.to_vec(), next })?;
+        }
+        self.header.meta_head = pages.first().copied().unwrap_or(NO_PAGE);
+        Ok(())
+    }
+
+    // Spreads the bytes over a new chain of overflow pages, returning the first
+    pub fn write_overflow(&mut self, bytes: &[u8]) -> Result<PageId, String> {
+        let chunks: Vec<&[u8]> = bytes.chunks(META_CHUNK).collect();
+        let mut pages = vec![];
+        for _ in 0..chunks.len() {
+            pages.push(self.allocate()?);
+        }
+        for (i, chunk) in chunks.iter().enumerate() {
+            let next = pages.get(i + 1).copied().unwrap_or(NO_PAGE);
+            self.write_node(pages[i], &Node::Overflow { bytes: chunk.to_vec(), next })?;
+        }
+        Ok(pages.first().copied().unwrap_or(NO_PAGE))
+    }
+
+    pub fn read_overflow(&mut self, first: PageId) -> Result<Vec<u8>, String> {
+        let mut bytes = vec![];
+        let mut current = first;
+        while current != NO_PAGE {
+            match self.read_node(current)? {
+                Node::Overflow { bytes: chunk, next } => {
+                    bytes.extend(chunk);
+                    current = next;
+                },
+                _ => return Err(format!("Corrupt overflow chain at page {}", current)),
+            }
+        }
+        Ok(bytes)
+    }
+
+    pub fn free_overflow(&mut self, first: PageId) -> Result<(), String> {
+        let mut current = first;
+        while current != NO_PAGE {
+            let next = match self.read_node(current)? {
+                Node::Overflow { next, .. } => next,
+                _ => return Err(format!("Corrupt overflow chain at page {}", current)),
+            };
+            self.free(current)?;
+            current = next;
+        }
+        Ok(())
+    }
+
+    // Writes the header and all dirty pages, and returns how many pages hit the disk
+    pub fn flush(&mut self) -> Result<usize, String> {
+        let header = self.header.encode()?;
+        self.pool.write(0, &header)?;
+        self.pool.flush()
+    }
+}